@@ -0,0 +1,609 @@
+// Text assembler, the inverse of the disassembler. One instruction per line:
+//
+//   loop:                   ; labels end with a colon
+//       LOAD_VAR "n"        ; names are quoted strings
+//       PUSH -1
+//       ADD
+//       JUMP loop           ; addresses are labels or plain numbers
+//       MAKE_CLOSURE adder "n"  ; a function address followed by the locals it captures
+//       TABLE_SWITCH 1 other one two    ; first case value, default, a target per case value
+//       LOOKUP_SWITCH other -1:neg 100:big  ; default, then key:target cases in any order
+//
+// Directives start with a dot:
+//
+//   .export square 1        ; export the function at label `square`, taking 1 argument
+//   .module lib             ; name the module, used by the linker for errors and private globals
+//   .import helper          ; `CALL helper` is left for the linker to resolve
+//   .private count          ; the global "count" is not shared with other linked modules
+//   .record Point x y       ; a record type for NEW_RECORD "Point", with fields x and y in order
+//   .enum Option None Some:1    ; an enum for NEW_VARIANT "Option.Some", variants name:arity
+//   .byte 0xff 0 12         ; raw bytes, for data or anything that isn't an instruction
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::instruction::{Instruction, Operand};
+use crate::module::{Import, Module};
+use crate::opcode::{OpCode, OperandKind};
+use crate::record::RecordType;
+use crate::variant::{EnumType, VariantType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+// an address operand before labels are resolved
+enum Target {
+    Resolved(usize),
+    Label(String),
+}
+
+struct PendingInstruction {
+    line: usize,
+    offset: usize,
+    opcode: OpCode,
+    operand: Operand,
+    // one per entry of the operand's targets_mut(), in the same order
+    targets: Vec<Target>,
+}
+
+// something that takes up space in the bytecode
+enum Pending {
+    Instruction(PendingInstruction),
+    Data(Vec<u8>),
+}
+
+enum Directive {
+    Export(String, usize),
+    Module(String),
+    Import(String),
+    Private(String),
+    Record(RecordType),
+    Enum(EnumType),
+    Bytes(Vec<u8>),
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblyError> {
+    assemble_module(source).map(|module| module.code)
+}
+
+pub fn assemble_module(source: &str) -> Result<Module, AssemblyError> {
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut exports: Vec<(usize, String, usize)> = Vec::new();
+    let mut module = Module::default();
+    let mut offset = 0;
+
+    // first pass: parse everything and work out where each label lands
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut tokens = tokenize(text).map_err(|message| AssemblyError { line, message })?;
+
+        while let Some(Token::Word(word)) = tokens.first() {
+            let Some(label) = word.strip_suffix(':') else { break };
+            if labels.insert(label.to_string(), offset).is_some() {
+                return Err(AssemblyError { line, message: format!("duplicate label '{}'", label) });
+            }
+            tokens.remove(0);
+        }
+
+        if tokens.is_empty() {
+            continue;
+        }
+
+        if let Token::Word(word) = &tokens[0] {
+            if word.starts_with('.') {
+                match parse_directive(&tokens).map_err(|message| AssemblyError { line, message })? {
+                    Directive::Export(name, arity) => {
+                        if exports.iter().any(|(_, exported, _)| *exported == name) {
+                            let message = format!("duplicate export '{}'", name);
+                            return Err(AssemblyError { line, message });
+                        }
+                        exports.push((line, name, arity));
+                    }
+                    Directive::Module(name) => module.name = name,
+                    Directive::Import(name) => module.imports.push(Import { name, sites: vec![] }),
+                    Directive::Private(name) => module.private_globals.push(name),
+                    Directive::Record(record) => {
+                        if module.record(&record.name).is_some() {
+                            let message = format!("duplicate record '{}'", record.name);
+                            return Err(AssemblyError { line, message });
+                        }
+                        module.records.push(record);
+                    }
+                    Directive::Enum(declared) => {
+                        if module.enum_type(&declared.name).is_some() {
+                            let message = format!("duplicate enum '{}'", declared.name);
+                            return Err(AssemblyError { line, message });
+                        }
+                        module.enums.push(declared);
+                    }
+                    Directive::Bytes(bytes) => {
+                        offset += bytes.len();
+                        pending.push(Pending::Data(bytes));
+                    }
+                }
+                continue;
+            }
+        }
+
+        let instruction = parse_instruction(&tokens).map_err(|message| AssemblyError { line, message })?;
+        let len = Instruction::new(instruction.opcode, instruction.operand.clone()).encoded_len();
+        pending.push(Pending::Instruction(PendingInstruction { line, offset, ..instruction }));
+        offset += len;
+    }
+
+    // second pass: patch in label addresses and encode
+    let mut bytecode = Vec::with_capacity(offset);
+    for instruction in pending {
+        let instruction = match instruction {
+            Pending::Instruction(instruction) => instruction,
+            Pending::Data(bytes) => {
+                bytecode.extend_from_slice(&bytes);
+                continue;
+            }
+        };
+        let single = instruction.targets.len() == 1;
+        let mut encoded = Instruction::new(instruction.opcode, instruction.operand);
+        for (slot, target) in encoded.targets_mut().into_iter().zip(instruction.targets) {
+            match target {
+                Target::Resolved(addr) => *slot = addr,
+                Target::Label(label) => match labels.get(&label) {
+                    Some(addr) => *slot = *addr,
+                    // the linker patches the placeholder once it knows where the import lives.
+                    // Only calls and closures can refer to another module, not switch cases
+                    None if single && module.imports.iter().any(|import| import.name == label) => {
+                        module.add_import_site(&label, instruction.offset);
+                    }
+                    None => {
+                        return Err(AssemblyError {
+                            line: instruction.line,
+                            message: format!("undefined label '{}'", label),
+                        })
+                    }
+                },
+            }
+        }
+        encoded.encode(&mut bytecode);
+    }
+
+    module.code = bytecode;
+    for (line, name, arity) in exports {
+        let address = labels.get(&name).ok_or_else(|| AssemblyError {
+            line,
+            message: format!("exported label '{}' is not defined", name),
+        })?;
+        module.add_export(&name, *address, arity);
+    }
+
+    Ok(module)
+}
+
+// .export <label> <arity>, .module <name>, .import <name>, .private <name>,
+// .record <name> <field>..., .enum <name> <variant[:arity]>... or .byte <n>...
+fn parse_directive(tokens: &[Token]) -> Result<Directive, String> {
+    let Some(Token::Word(directive)) = tokens.first() else {
+        return Err("expected a directive".to_string());
+    };
+    match (directive.as_str(), &tokens[1..]) {
+        (".export", [Token::Word(name), Token::Word(arity)]) => {
+            let arity = arity.parse::<usize>().map_err(|_| format!("invalid arity '{}'", arity))?;
+            Ok(Directive::Export(name.clone(), arity))
+        }
+        (".export", _) => Err("expected .export <label> <arity>".to_string()),
+        (".module", [Token::Word(name)]) => Ok(Directive::Module(name.clone())),
+        (".import", [Token::Word(name)]) => Ok(Directive::Import(name.clone())),
+        (".private", [Token::Word(name)] | [Token::Str(name)]) => Ok(Directive::Private(name.clone())),
+        (".module" | ".import" | ".private", _) => Err(format!("expected {} <name>", directive)),
+        (".record", [Token::Word(name), fields @ ..]) => {
+            let mut record = RecordType { name: name.clone(), fields: Vec::new() };
+            for field in fields {
+                let Token::Word(field) = field else {
+                    return Err("expected a field name for .record".to_string());
+                };
+                if record.field_index(field).is_some() {
+                    return Err(format!("duplicate field '{}'", field));
+                }
+                record.fields.push(field.clone());
+            }
+            Ok(Directive::Record(record))
+        }
+        (".record", _) => Err("expected .record <name> <field>...".to_string()),
+        (".enum", [Token::Word(name), variants @ ..]) => {
+            let mut declared = EnumType { name: name.clone(), variants: Vec::new() };
+            for variant in variants {
+                let Token::Word(variant) = variant else {
+                    return Err("expected a variant for .enum".to_string());
+                };
+                let (variant, arity) = match variant.split_once(':') {
+                    Some((variant, arity)) => {
+                        (variant, arity.parse::<usize>().map_err(|_| format!("invalid arity '{}'", arity))?)
+                    }
+                    None => (variant.as_str(), 0),
+                };
+                if declared.tag(variant).is_some() {
+                    return Err(format!("duplicate variant '{}'", variant));
+                }
+                declared.variants.push(VariantType { name: variant.to_string(), arity });
+            }
+            Ok(Directive::Enum(declared))
+        }
+        (".enum", _) => Err("expected .enum <name> <variant[:arity]>...".to_string()),
+        (".byte", [_, ..]) => {
+            let mut bytes = Vec::new();
+            for token in &tokens[1..] {
+                let Token::Word(word) = token else {
+                    return Err("expected a number for .byte".to_string());
+                };
+                let n = parse_int(word)?;
+                bytes.push(u8::try_from(n).map_err(|_| format!("{} does not fit in a byte", n))?);
+            }
+            Ok(Directive::Bytes(bytes))
+        }
+        (".byte", _) => Err("expected .byte <n>...".to_string()),
+        _ => Err(format!("unknown directive '{}'", directive)),
+    }
+}
+
+fn parse_instruction(tokens: &[Token]) -> Result<PendingInstruction, String> {
+    let mnemonic = match &tokens[0] {
+        Token::Word(word) => word,
+        Token::Str(_) => return Err("expected an instruction, found a string".to_string()),
+    };
+    let opcode = OpCode::convert_from_name(mnemonic)
+        .ok_or_else(|| format!("unknown instruction '{}'", mnemonic))?;
+
+    let operands = &tokens[1..];
+    let expected = match opcode.operand_kind() {
+        OperandKind::None => 0,
+        // any number of captured names may follow the address
+        OperandKind::Closure => operands.len().max(1),
+        // the cases are optional, there is always a default
+        OperandKind::Table => operands.len().max(2),
+        OperandKind::Lookup => operands.len().max(1),
+        _ => 1,
+    };
+    if operands.len() != expected {
+        return Err(format!("{} takes {} operand(s), found {}", opcode.name(), expected, operands.len()));
+    }
+
+    let mut operand = Operand::None;
+    let mut targets = Vec::new();
+    match (opcode.operand_kind(), operands.first()) {
+        (OperandKind::None, _) => {}
+        (OperandKind::Int, Some(Token::Word(word))) => {
+            operand = Operand::Int(parse_int(word)?);
+        }
+        (OperandKind::Byte, Some(Token::Word(word))) => {
+            let n = parse_int(word)?;
+            let byte = u8::try_from(n).map_err(|_| format!("{} does not fit in a byte", n))?;
+            operand = Operand::Byte(byte);
+        }
+        (OperandKind::Name, Some(Token::Str(name))) => {
+            if name.len() > u8::MAX as usize {
+                return Err(format!("string is longer than {} bytes", u8::MAX));
+            }
+            operand = Operand::Name(name.clone());
+        }
+        (OperandKind::Address, Some(Token::Word(word))) => {
+            // placeholder until the second pass fills in the real address
+            operand = Operand::Address(0);
+            targets.push(parse_target(word));
+        }
+        (OperandKind::Closure, Some(Token::Word(word))) => {
+            let mut names = Vec::new();
+            for token in &operands[1..] {
+                match token {
+                    Token::Str(name) if name.len() <= u8::MAX as usize => names.push(name.clone()),
+                    Token::Str(_) => return Err(format!("string is longer than {} bytes", u8::MAX)),
+                    Token::Word(word) => return Err(format!("expected a quoted local name, found '{}'", word)),
+                }
+            }
+            if names.len() > u8::MAX as usize {
+                return Err(format!("a closure can capture at most {} locals", u8::MAX));
+            }
+            operand = Operand::Closure(0, names);
+            targets.push(parse_target(word));
+        }
+        (OperandKind::Table, Some(Token::Word(low))) => {
+            let low = parse_int(low)?;
+            let words = words(&operands[1..])?;
+            let cases = words.len() - 1;
+            if cases > u32::MAX as usize || (cases > 1 && low.checked_add(cases as i64 - 1).is_none()) {
+                return Err("too many cases for TABLE_SWITCH".to_string());
+            }
+            targets = words.iter().map(|word| parse_target(word)).collect();
+            operand = Operand::Table(low, 0, vec![0; cases]);
+        }
+        (OperandKind::Lookup, Some(_)) => {
+            let words = words(operands)?;
+            let mut cases = Vec::new();
+            for word in &words[1..] {
+                let (key, target) = word
+                    .split_once(':')
+                    .ok_or_else(|| format!("expected a key:target case, found '{}'", word))?;
+                cases.push((parse_int(key)?, parse_target(target)));
+            }
+            // the encoding needs the keys in order, the source doesn't
+            cases.sort_by_key(|(key, _)| *key);
+            if let Some(pair) = cases.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+                return Err(format!("duplicate case {}", pair[0].0));
+            }
+            if cases.len() > u32::MAX as usize {
+                return Err("too many cases for LOOKUP_SWITCH".to_string());
+            }
+            operand = Operand::Lookup(0, cases.iter().map(|(key, _)| (*key, 0)).collect());
+            targets.push(parse_target(words[0]));
+            targets.extend(cases.into_iter().map(|(_, target)| target));
+        }
+        (kind, _) => {
+            return Err(format!("bad operand for {}, expected {:?}", opcode.name(), kind));
+        }
+    }
+
+    Ok(PendingInstruction { line: 0, offset: 0, opcode, operand, targets })
+}
+
+// every token as a bare word, for operands that are all addresses or numbers
+fn words(tokens: &[Token]) -> Result<Vec<&str>, String> {
+    tokens
+        .iter()
+        .map(|token| match token {
+            Token::Word(word) => Ok(word.as_str()),
+            Token::Str(text) => Err(format!("expected a label or address, found \"{}\"", text)),
+        })
+        .collect()
+}
+
+fn parse_target(word: &str) -> Target {
+    match parse_address(word) {
+        Some(addr) => Target::Resolved(addr),
+        None => Target::Label(word.to_string()),
+    }
+}
+
+fn parse_int(word: &str) -> Result<i64, String> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).map(|n| n as i64)
+    } else if negative {
+        // parsed unsigned so i64::MIN, whose magnitude doesn't fit in an i64, still works
+        match digits.parse::<u64>() {
+            Ok(n) if n <= i64::MIN.unsigned_abs() => Ok(n as i64),
+            _ => return Err(format!("invalid integer '{}'", word)),
+        }
+    } else {
+        digits.parse::<i64>()
+    };
+    let n = parsed.map_err(|_| format!("invalid integer '{}'", word))?;
+    Ok(if negative { n.wrapping_neg() } else { n })
+}
+
+// a numeric address, None when the word has to be a label
+fn parse_address(word: &str) -> Option<usize> {
+    match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => word.parse::<usize>().ok(),
+    }
+}
+
+// quotes a string operand's contents so tokenize() reads back exactly the same text
+pub fn escape_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// split a line into words and quoted strings, dropping any ; comment
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated string".to_string()),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some('\\') => text.push('\\'),
+                        Some('"') => text.push('"'),
+                        Some('r') => text.push('\r'),
+                        Some('0') => text.push('\0'),
+                        other => return Err(format!("invalid escape '\\{}'", other.unwrap_or(' '))),
+                    },
+                    Some(other) => text.push(other),
+                }
+            }
+            tokens.push(Token::Str(text));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == ';' || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::decode_all;
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_assemble_and_run() {
+        let source = r#"
+            PUSH 0
+            STORE_VAR "i"
+        loop:
+            LOAD_VAR "i"
+            PUSH 10
+            LT
+            JUMP_IF_FALSE done   ; leave once i reaches 10
+            LOAD_VAR "i"
+            PUSH 1
+            ADD
+            STORE_VAR "i"
+            JUMP loop
+        done:
+            LOAD_VAR "i"
+            PUSH 0x3
+            MOD
+            HALT
+        "#;
+
+        let bytecode = assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(bytecode);
+        vm.run_solution().unwrap();
+
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(1)));
+    }
+
+    #[test]
+    fn test_assemble_new_arithmetic_opcodes() {
+        let bytecode = assemble("PUSH -7\nNEG\nPUSH 2\nSHL\nPUSH 1\nUSHR\nBIT_NOT\nHALT").unwrap();
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(bytecode);
+        vm.run_solution().unwrap();
+
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(!14)));
+    }
+
+    #[test]
+    fn test_assembly_errors() {
+        let err = assemble("PUSH 1\nFROB").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("FROB"));
+
+        let err = assemble("JUMP nowhere").unwrap_err();
+        assert!(err.message.contains("nowhere"));
+
+        let err = assemble("PUSH").unwrap_err();
+        assert!(err.message.contains("operand"));
+
+        let err = assemble("a:\na:\nHALT").unwrap_err();
+        assert_eq!(err.line, 2);
+
+        assert!(assemble("PRINT \"open").is_err());
+    }
+
+    #[test]
+    fn test_switch_operands() {
+        let bytecode = assemble("a:\nTABLE_SWITCH -1 a b a\nb:\nLOOKUP_SWITCH b 9:a -3:b 0x10:a").unwrap();
+        let decoded = decode_all(&bytecode).unwrap();
+        assert_eq!(decoded[0].1.operand, Operand::Table(-1, 0, vec![37, 0]));
+        // cases come out sorted by key
+        assert_eq!(decoded[1].1.operand, Operand::Lookup(37, vec![(-3, 37), (9, 0), (16, 0)]));
+
+        let error = |source: &str| assemble(source).unwrap_err().message;
+        assert_eq!(error("LOOKUP_SWITCH 0 1:0 1:0"), "duplicate case 1");
+        assert_eq!(error("LOOKUP_SWITCH 0 1"), "expected a key:target case, found '1'");
+        assert_eq!(error("TABLE_SWITCH 0"), "TABLE_SWITCH takes 2 operand(s), found 1");
+        assert_eq!(error("TABLE_SWITCH 9223372036854775807 0 0 0"), "too many cases for TABLE_SWITCH");
+        assert_eq!(error("LOOKUP_SWITCH 0 1:nowhere"), "undefined label 'nowhere'");
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let bytecode = assemble(r#"PRINT "say \"hi\"\n""#).unwrap();
+        assert_eq!(bytecode[1] as usize, "say \"hi\"\n".len());
+    }
+
+    #[test]
+    fn test_export_directive() {
+        let module = assemble_module("HALT\n.export double 1\ndouble:\nDUP\nADD\nRETURN").unwrap();
+        assert_eq!(module.export("double").map(|e| (e.address, e.arity)), Some((1, 1)));
+
+        let err = assemble_module(".export missing 0\nHALT").unwrap_err();
+        assert!(err.message.contains("missing"));
+        assert!(assemble_module(".export double\ndouble:\nRETURN").is_err());
+        assert!(assemble_module(".frobnicate").is_err());
+
+        let err = assemble_module(".export f 0\n.export f 1\nf:\nRETURN").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (2, "duplicate export 'f'"));
+    }
+
+    #[test]
+    fn test_record_directive() {
+        let module = assemble_module(".record Point x y\n.record Unit\nHALT").unwrap();
+        assert_eq!(module.record("Point"), Some(&RecordType::new("Point", &["x", "y"])));
+        assert_eq!(module.record("Unit").map(|record| record.fields.len()), Some(0));
+
+        let error = |source: &str| assemble_module(source).unwrap_err().message;
+        assert_eq!(error(".record Point x x"), "duplicate field 'x'");
+        assert_eq!(error(".record P\n.record P a"), "duplicate record 'P'");
+        assert_eq!(error(".record"), "expected .record <name> <field>...");
+    }
+
+    #[test]
+    fn test_enum_directive() {
+        let module = assemble_module(".enum Shape Empty Circle:1 Rect:2\nHALT").unwrap();
+        let shape = EnumType::new("Shape", &[("Empty", 0), ("Circle", 1), ("Rect", 2)]);
+        assert_eq!(module.enum_type("Shape"), Some(&shape));
+
+        let error = |source: &str| assemble_module(source).unwrap_err().message;
+        assert_eq!(error(".enum E A A:1"), "duplicate variant 'A'");
+        assert_eq!(error(".enum E A:x"), "invalid arity 'x'");
+        assert_eq!(error(".enum E\n.enum E"), "duplicate enum 'E'");
+    }
+
+    #[test]
+    fn test_link_directives() {
+        let module = assemble_module(
+            ".module main\n.import helper\n.private \"n\"\nPUSH 1\nCALL helper\nCALL helper\nHALT",
+        )
+        .unwrap();
+
+        assert_eq!(module.name, "main");
+        assert_eq!(module.private_globals, vec!["n".to_string()]);
+        assert_eq!(module.imports, vec![Import { name: "helper".to_string(), sites: vec![9, 18] }]);
+        assert!(assemble_module(".import").is_err());
+    }
+}
@@ -1,13 +1,19 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use crate::value::Value;
+use std::rc::Rc;
+use crate::value::{Closure, Upvalue, Value};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CallFrame {
     // return address: where to jump back to after function call
     return_address: usize,
 
-    // local variable for this function which is seperate from global variables
-    locals: HashMap<String, Value>,
+    // local variable for this function which is seperate from global variables. Each one is a
+    // shared cell so closures made in this frame can capture it
+    locals: HashMap<String, Upvalue>,
+
+    // frames this one stands in for, replaced one after another by tail calls
+    elided: usize,
 }
 
 impl CallFrame {
@@ -15,18 +21,66 @@ impl CallFrame {
         CallFrame {
             return_address,
             locals: HashMap::new(),
+            elided: 0,
         }
     }
 
+    // the frame a TAIL_CALL replaces this one with: fresh locals, but it returns to where this
+    // frame would have
+    pub fn tail_call(&self) -> Self {
+        CallFrame::new_solution(self.return_address).with_elided(self.elided + 1)
+    }
+
+    pub fn with_elided(mut self, elided: usize) -> Self {
+        self.elided = elided;
+        self
+    }
+
+    // the frame a closure runs in, starting out with its captured locals
+    pub fn for_closure(return_address: usize, closure: &Closure) -> Self {
+        let mut frame = CallFrame::new_solution(return_address);
+        for (name, cell) in &closure.upvalues {
+            frame.bind_local(name.clone(), cell.clone());
+        }
+        frame
+    }
+
+    // assigns through an existing cell, so closures that captured it see the new value
     pub fn store_local_solution(&mut self, name: String, value: Value) {
-        self.locals.insert(name, value);
+        match self.locals.get(&name) {
+            Some(cell) => *cell.borrow_mut() = value,
+            None => {
+                self.locals.insert(name, Rc::new(RefCell::new(value)));
+            }
+        }
     }
 
     pub fn load_local_solution(&self, name: &str) -> Option<Value> {
-        self.locals.get(name).copied()
+        self.locals.get(name).map(|cell| cell.borrow().clone())
+    }
+
+    // the cell behind a local, for a closure to share
+    pub fn capture(&self, name: &str) -> Option<Upvalue> {
+        self.locals.get(name).cloned()
+    }
+
+    // makes `name` refer to an existing cell, used for a closure's upvalues when it is called
+    pub fn bind_local(&mut self, name: String, cell: Upvalue) {
+        self.locals.insert(name, cell);
     }
 
     pub fn return_address(&self) -> usize {
         self.return_address
     }
-}
\ No newline at end of file
+
+    pub fn elided(&self) -> usize {
+        self.elided
+    }
+
+    // locals sorted by name, so the order is stable across runs
+    pub fn locals(&self) -> Vec<(&str, &Upvalue)> {
+        let mut locals: Vec<_> = self.locals.iter().map(|(name, cell)| (name.as_str(), cell)).collect();
+        locals.sort_by(|a, b| a.0.cmp(b.0));
+        locals
+    }
+}
@@ -0,0 +1,466 @@
+// Control flow graph over bytecode. Blocks start at offset 0, at every jump or call target and
+// after every instruction that transfers control (jumps, switches, CALL, TAIL_CALL, RETURN,
+// HALT); edges record how control gets from one block to the next. A switch has one taken edge
+// per distinct target, its default included.
+//
+// Dominators and loops are worked out per function: CALL edges lead into the callee but control
+// comes back through the fallthrough edge, so they are left out of both. A TAIL_CALL has a call
+// edge and no fallthrough, control leaves the function for good. The main program and every
+// call target are each the root of their own dominator tree.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::error::VMError;
+use crate::instruction::{decode_all, Instruction};
+use crate::opcode::OpCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    // the jump target of a jump, conditional jump or switch
+    Taken,
+    // on to the next instruction
+    Fallthrough,
+    // from a CALL into the function it calls
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    // offset of the first instruction
+    pub start: usize,
+    // offset just past the last instruction
+    pub end: usize,
+    pub instructions: Vec<(usize, Instruction)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    // the block every iteration goes through, the target of the back edges
+    pub header: usize,
+    pub blocks: BTreeSet<usize>,
+    // index of the innermost loop this one sits in
+    pub parent: Option<usize>,
+    // 1 for an outermost loop
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+    // block 0 and the entry block of every called function
+    pub roots: Vec<usize>,
+}
+
+impl Cfg {
+    pub fn build(bytecode: &[u8]) -> Result<Cfg, VMError> {
+        let instructions = decode_all(bytecode)?;
+        let starts: BTreeSet<usize> = instructions.iter().map(|(offset, _)| *offset).collect();
+
+        let mut leaders = BTreeSet::new();
+        if !instructions.is_empty() {
+            leaders.insert(0);
+        }
+        for (offset, instruction) in &instructions {
+            for target in instruction.targets() {
+                if !starts.contains(&target) {
+                    return Err(VMError::InvalidOperand);
+                }
+                leaders.insert(target);
+            }
+            if ends_block(instruction.opcode) {
+                let next = offset + instruction.encoded_len();
+                if next < bytecode.len() {
+                    leaders.insert(next);
+                }
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for (offset, instruction) in instructions {
+            let end = offset + instruction.encoded_len();
+            match blocks.last_mut() {
+                Some(block) if !leaders.contains(&offset) => {
+                    block.end = end;
+                    block.instructions.push((offset, instruction));
+                }
+                _ => blocks.push(BasicBlock { start: offset, end, instructions: vec![(offset, instruction)] }),
+            }
+        }
+
+        let block_of: HashMap<usize, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| (block.start, index))
+            .collect();
+
+        let mut edges = Vec::new();
+        let mut roots = if blocks.is_empty() { vec![] } else { vec![0] };
+        for (index, block) in blocks.iter().enumerate() {
+            let (_, last) = block.instructions.last().expect("blocks are never empty");
+
+            // closures are functions of their own, entered whenever the value is called
+            for (_, instruction) in &block.instructions {
+                if instruction.opcode == OpCode::MakeClosure {
+                    roots.extend(instruction.targets().iter().map(|target| block_of[target]));
+                }
+            }
+
+            for target in last.targets() {
+                let kind = match last.opcode {
+                    OpCode::Call | OpCode::TailCall => EdgeKind::Call,
+                    OpCode::MakeClosure => continue,
+                    _ => EdgeKind::Taken,
+                };
+                let to = block_of[&target];
+                let edge = Edge { from: index, to, kind };
+                // switch cases often share a target
+                if edges.contains(&edge) {
+                    continue;
+                }
+                edges.push(edge);
+                if kind == EdgeKind::Call && !roots.contains(&to) {
+                    roots.push(to);
+                }
+            }
+            let falls_through = !matches!(
+                last.opcode,
+                OpCode::Jump | OpCode::TableSwitch | OpCode::LookupSwitch
+                    | OpCode::TailCall | OpCode::Return | OpCode::Halt
+            );
+            if falls_through {
+                if let Some(&to) = block_of.get(&block.end) {
+                    edges.push(Edge { from: index, to, kind: EdgeKind::Fallthrough });
+                }
+            }
+        }
+
+        let mut seen = BTreeSet::new();
+        roots.retain(|root| seen.insert(*root));
+
+        Ok(Cfg { blocks, edges, roots })
+    }
+
+    // the block containing the instruction at `offset`
+    pub fn block_at(&self, offset: usize) -> Option<usize> {
+        self.blocks.iter().position(|block| block.start <= offset && offset < block.end)
+    }
+
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.to == block)
+    }
+
+    // iterative data flow: a block's dominators are itself plus whatever dominates all of its
+    // predecessors. Blocks no root reaches are left with an empty set
+    pub fn dominators(&self) -> Dominators {
+        let all: BTreeSet<usize> = (0..self.blocks.len()).collect();
+        let reachable = self.reachable();
+        let mut sets: Vec<BTreeSet<usize>> = (0..self.blocks.len())
+            .map(|block| {
+                if self.roots.contains(&block) {
+                    BTreeSet::from([block])
+                } else if reachable[block] {
+                    all.clone()
+                } else {
+                    BTreeSet::new()
+                }
+            })
+            .collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in 0..self.blocks.len() {
+                if self.roots.contains(&block) || !reachable[block] {
+                    continue;
+                }
+                let mut set: Option<BTreeSet<usize>> = None;
+                for edge in self.predecessors(block).filter(|edge| edge.kind != EdgeKind::Call) {
+                    if !reachable[edge.from] {
+                        continue;
+                    }
+                    set = Some(match set {
+                        None => sets[edge.from].clone(),
+                        Some(set) => set.intersection(&sets[edge.from]).copied().collect(),
+                    });
+                }
+                let mut set = set.unwrap_or_default();
+                set.insert(block);
+                if set != sets[block] {
+                    sets[block] = set;
+                    changed = true;
+                }
+            }
+        }
+
+        Dominators { sets }
+    }
+
+    // natural loops, one per header, with nesting worked out from which loops contain which
+    pub fn loops(&self) -> Vec<Loop> {
+        let dominators = self.dominators();
+        let mut bodies: Vec<(usize, BTreeSet<usize>)> = Vec::new();
+
+        for edge in &self.edges {
+            // a back edge goes to a block that dominates where it comes from
+            if edge.kind == EdgeKind::Call || !dominators.dominates(edge.to, edge.from) {
+                continue;
+            }
+            let header = edge.to;
+            let mut body = BTreeSet::from([header]);
+            let mut worklist = vec![edge.from];
+            while let Some(block) = worklist.pop() {
+                if body.insert(block) {
+                    worklist.extend(
+                        self.predecessors(block)
+                            .filter(|edge| edge.kind != EdgeKind::Call)
+                            .map(|edge| edge.from),
+                    );
+                }
+            }
+            match bodies.iter_mut().find(|(existing, _)| *existing == header) {
+                Some((_, existing)) => existing.extend(body),
+                None => bodies.push((header, body)),
+            }
+        }
+
+        // outer loops first, so a parent always comes before its children
+        bodies.sort_by_key(|(header, body)| (std::cmp::Reverse(body.len()), *header));
+
+        let mut loops: Vec<Loop> = Vec::new();
+        for (header, blocks) in bodies {
+            let parent = loops
+                .iter()
+                .enumerate()
+                .filter(|(_, outer)| outer.blocks.is_superset(&blocks))
+                .max_by_key(|(_, outer)| outer.depth)
+                .map(|(index, _)| index);
+            let depth = parent.map_or(1, |parent| loops[parent].depth + 1);
+            loops.push(Loop { header, blocks, parent, depth });
+        }
+        loops
+    }
+
+    // how many loops the block is inside, 0 when it isn't in any
+    pub fn loop_depth(&self, block: usize) -> usize {
+        self.loops()
+            .iter()
+            .filter(|l| l.blocks.contains(&block))
+            .count()
+    }
+
+    // Graphviz source, one box per block listing its instructions
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph cfg {\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for (offset, instruction) in &block.instructions {
+                let _ = write!(label, "{:04} {}\\l", offset, escape_dot(&instruction.to_string()));
+            }
+            let _ = writeln!(out, "    b{} [label=\"{}\"];", index, label);
+        }
+
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Taken => "label=\"taken\"",
+                EdgeKind::Fallthrough => "label=\"fallthrough\", style=dashed",
+                EdgeKind::Call => "label=\"call\", style=dotted",
+            };
+            let _ = writeln!(out, "    b{} -> b{} [{}];", edge.from, edge.to, style);
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut worklist = self.roots.clone();
+        while let Some(block) = worklist.pop() {
+            if !reachable[block] {
+                reachable[block] = true;
+                worklist.extend(self.successors(block).map(|edge| edge.to));
+            }
+        }
+        reachable
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dominators {
+    // every block that dominates the block at that index, itself included
+    sets: Vec<BTreeSet<usize>>,
+}
+
+impl Dominators {
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        self.sets.get(b).is_some_and(|set| set.contains(&a))
+    }
+
+    // the closest strict dominator, None for roots and unreachable blocks
+    pub fn immediate(&self, block: usize) -> Option<usize> {
+        let set = self.sets.get(block)?;
+        // of the strict dominators, the one dominated by all the others has the biggest set
+        set.iter()
+            .filter(|&&other| other != block)
+            .max_by_key(|&&other| self.sets[other].len())
+            .copied()
+    }
+}
+
+fn ends_block(opcode: OpCode) -> bool {
+    matches!(
+        opcode,
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue
+            | OpCode::JumpIfFalseKeep | OpCode::JumpIfTrueKeep
+            | OpCode::TableSwitch | OpCode::LookupSwitch
+            | OpCode::Call | OpCode::TailCall | OpCode::Return | OpCode::Halt
+    )
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // two nested counting loops followed by a call
+    const NESTED: &str = r#"
+            PUSH 0
+            STORE_VAR "i"
+        outer:                      ; block 1
+            LOAD_VAR "i"
+            PUSH 3
+            LT
+            JUMP_IF_FALSE done
+            PUSH 0                  ; block 2
+            STORE_VAR "j"
+        inner:                      ; block 3
+            LOAD_VAR "j"
+            PUSH 3
+            LT
+            JUMP_IF_FALSE next
+            LOAD_VAR "j"            ; block 4
+            PUSH 1
+            ADD
+            STORE_VAR "j"
+            JUMP inner
+        next:                       ; block 5
+            LOAD_VAR "i"
+            PUSH 1
+            ADD
+            STORE_VAR "i"
+            JUMP outer
+        done:                       ; block 6
+            CALL twice
+            HALT                    ; block 7
+        twice:                      ; block 8
+            DUP
+            ADD
+            RETURN
+    "#;
+
+    fn build(source: &str) -> Cfg {
+        Cfg::build(&assemble(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_blocks_and_edges() {
+        let cfg = build(NESTED);
+
+        assert_eq!(cfg.blocks.len(), 9);
+        assert_eq!(cfg.roots, vec![0, 8]);
+        assert_eq!(cfg.block_at(cfg.blocks[3].start + 1), Some(3));
+
+        let edges_from = |block| cfg.successors(block).map(|e| (e.to, e.kind)).collect::<Vec<_>>();
+        assert_eq!(edges_from(1), vec![(6, EdgeKind::Taken), (2, EdgeKind::Fallthrough)]);
+        assert_eq!(edges_from(4), vec![(3, EdgeKind::Taken)]);
+        assert_eq!(edges_from(6), vec![(8, EdgeKind::Call), (7, EdgeKind::Fallthrough)]);
+        assert_eq!(edges_from(8), vec![]);
+    }
+
+    #[test]
+    fn test_dominators() {
+        let cfg = build(NESTED);
+        let dominators = cfg.dominators();
+
+        assert!(dominators.dominates(1, 5));
+        assert!(dominators.dominates(3, 4));
+        assert!(!dominators.dominates(4, 5));
+        assert_eq!(dominators.immediate(0), None);
+        assert_eq!(dominators.immediate(5), Some(3));
+        assert_eq!(dominators.immediate(6), Some(1));
+        // the callee is its own root, not dominated by the caller
+        assert_eq!(dominators.immediate(8), None);
+    }
+
+    #[test]
+    fn test_loop_nesting() {
+        let cfg = build(NESTED);
+        let loops = cfg.loops();
+
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].header, 1);
+        assert_eq!(loops[0].blocks, BTreeSet::from([1, 2, 3, 4, 5]));
+        assert_eq!((loops[0].parent, loops[0].depth), (None, 1));
+        assert_eq!(loops[1].header, 3);
+        assert_eq!(loops[1].blocks, BTreeSet::from([3, 4]));
+        assert_eq!((loops[1].parent, loops[1].depth), (Some(0), 2));
+
+        assert_eq!(cfg.loop_depth(0), 0);
+        assert_eq!(cfg.loop_depth(5), 1);
+        assert_eq!(cfg.loop_depth(4), 2);
+    }
+
+    #[test]
+    fn test_dot_output() {
+        let dot = build("PUSH 1\nJUMP_IF_TRUE end\nPRINT \"a\\\"b\"\nend:\nHALT").to_dot();
+
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("b0 [label=\"0000 PUSH 1\\l0009 JUMP_IF_TRUE 23\\l\"];"));
+        assert!(dot.contains("PRINT \\\"a\\\"b\\\""));
+        assert!(dot.contains("b0 -> b2 [label=\"taken\"];"));
+        assert!(dot.contains("b0 -> b1 [label=\"fallthrough\", style=dashed];"));
+    }
+
+    #[test]
+    fn test_closure_bodies_are_roots() {
+        let cfg = build("MAKE_CLOSURE f\nHALT\nf:\nRETURN");
+        assert_eq!(cfg.roots, vec![0, 1]);
+        assert!(cfg.edges.is_empty());
+    }
+
+    #[test]
+    fn test_switch_edges() {
+        let cfg = build("PUSH 2\nTABLE_SWITCH 1 other one two one\none:\nHALT\ntwo:\nHALT\nother:\nHALT");
+
+        assert_eq!(cfg.blocks.len(), 4);
+        let edges: Vec<_> = cfg.successors(0).map(|e| (e.to, e.kind)).collect();
+        // no fallthrough, and the repeated case is a single edge
+        assert_eq!(edges, vec![(3, EdgeKind::Taken), (1, EdgeKind::Taken), (2, EdgeKind::Taken)]);
+        assert_eq!(cfg.dominators().immediate(2), Some(0));
+    }
+
+    #[test]
+    fn test_bad_target() {
+        assert_eq!(Cfg::build(&assemble("JUMP 3\nHALT").unwrap()), Err(VMError::InvalidOperand));
+    }
+}
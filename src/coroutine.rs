@@ -0,0 +1,121 @@
+// A coroutine is a function that can suspend itself with YIELD and be picked up again later with
+// RESUME. It runs on its own value stack and call stack, which the VM swaps in while it runs and
+// keeps in the coroutine's heap object while it's suspended. Values travel both ways: RESUME
+// hands one to the coroutine (the function's argument on the first resume, the result of its
+// YIELD after that) and YIELD or the final RETURN hands one back to whoever resumed it.
+
+use std::fmt;
+
+use crate::callframe::CallFrame;
+use crate::heap::{trace_value, Handle};
+use crate::value::{Closure, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    // created but not started yet, or stopped at a YIELD
+    Suspended,
+    // currently executing, or waiting on a coroutine it resumed
+    Running,
+    // its function returned or failed, it can't be resumed again
+    Dead,
+}
+
+impl CoroutineStatus {
+    // how COROUTINE_STATUS and snapshots represent the status
+    pub fn code(&self) -> u8 {
+        match self {
+            CoroutineStatus::Suspended => 0,
+            CoroutineStatus::Running => 1,
+            CoroutineStatus::Dead => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<CoroutineStatus> {
+        match code {
+            0 => Some(CoroutineStatus::Suspended),
+            1 => Some(CoroutineStatus::Running),
+            2 => Some(CoroutineStatus::Dead),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+impl fmt::Display for CoroutineStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// the state a thread of execution needs to be continued later
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExecutionContext {
+    pub stack: Vec<Value>,
+    pub call_stack: Vec<CallFrame>,
+    pub ip: usize,
+}
+
+impl ExecutionContext {
+    // handles reachable from the values and locals in this context
+    pub fn trace(&self, out: &mut Vec<Handle>) {
+        for value in &self.stack {
+            trace_value(value, out);
+        }
+        for frame in &self.call_stack {
+            for (_, cell) in frame.locals() {
+                trace_value(&cell.borrow(), out);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coroutine {
+    pub status: CoroutineStatus,
+    // where it continues from, empty while it's running (the VM holds it then) and once dead
+    pub context: ExecutionContext,
+}
+
+impl Coroutine {
+    // a suspended coroutine that starts at the top of `closure` on its first resume
+    pub fn new(closure: &Closure) -> Self {
+        Coroutine {
+            status: CoroutineStatus::Suspended,
+            context: ExecutionContext {
+                stack: Vec::new(),
+                call_stack: vec![CallFrame::for_closure(0, closure)],
+                ip: closure.address,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_new_coroutine_starts_at_the_function() {
+        let cell = Rc::new(RefCell::new(Value::Function(Handle(3, 0))));
+        let closure = Closure { address: 12, upvalues: vec![("f".to_string(), cell)] };
+        let coroutine = Coroutine::new(&closure);
+
+        assert_eq!(coroutine.status, CoroutineStatus::Suspended);
+        assert_eq!(coroutine.context.ip, 12);
+        assert_eq!(coroutine.context.call_stack.len(), 1);
+
+        let mut handles = Vec::new();
+        coroutine.context.trace(&mut handles);
+        assert_eq!(handles, vec![Handle(3, 0)]);
+        assert_eq!(CoroutineStatus::Dead.to_string(), "dead");
+    }
+}
@@ -1,30 +1,40 @@
-use crate::{opcode::OpCode};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::assembler::escape_string;
+use crate::instruction::{Instruction, Operand};
+use crate::opcode::{OpCode, OperandKind};
+use crate::module::Module;
 
 // a disassembler converts bytecode into readable instruction   
 pub struct Disassembler {
-    bytecode: Vec<u8>,
+    // exported functions get their name printed above their first instruction
+    module: Module,
     offset: usize,
 }
 
 impl Disassembler {
     pub fn new(bytecode: Vec<u8>) -> Self {
-        Disassembler { bytecode, offset: 0 }
+        Disassembler { module: Module::new(bytecode), offset: 0 }
+    }
+
+    pub fn for_module(module: &Module) -> Self {
+        Disassembler { module: module.clone(), offset: 0 }
     }
 
     fn read_byte_solution(&mut self) -> Option<u8> {
-        if self.offset >= self.bytecode.len() {
+        if self.offset >= self.module.code.len() {
             return None;
         }
 
-        let byte = self.bytecode[self.offset];
+        let byte = self.module.code[self.offset];
         self.offset += 1;
         Some(byte)
     }
 
     fn read_i64_solution(&mut self) -> Option<i64> {
         let mut bytes = [0u8; 8];
-        for i in 0..8 {
-            bytes[i] = self.read_byte_solution()?;
+        for byte in bytes.iter_mut() {
+            *byte = self.read_byte_solution()?;
 
         }
 
@@ -33,8 +43,8 @@ impl Disassembler {
 
     fn read_usize_solution(&mut self) -> Option<usize> {
         let mut bytes = [0u8; 8];
-        for i in 0..8 {
-            bytes[i] = self.read_byte_solution()?;
+        for byte in bytes.iter_mut() {
+            *byte = self.read_byte_solution()?;
         }
         Some(usize::from_le_bytes(bytes))
     }
@@ -55,24 +65,44 @@ impl Disassembler {
         let byte = self.read_byte_solution()?;
         let opcode = OpCode::convert_from_u8(byte)?;
         
-        let instruction = match opcode {
-            OpCode::Push => {
+        let instruction = match opcode.operand_kind() {
+            OperandKind::Int => {
                 let value = self.read_i64_solution()?;
-                format!("{:04} PUSH {}", start_offset, value)
+                format!("{:04} {} {}", start_offset, opcode.name(), value)
             }
             
-            OpCode::StoreVar | OpCode::LoadVar | 
-            OpCode::StoreLocal | OpCode::LoadLocal | OpCode::Print => {
+            OperandKind::Byte => {
+                let value = self.read_byte_solution()?;
+                format!("{:04} {} {}", start_offset, opcode.name(), value)
+            }
+            
+            OperandKind::Name => {
                 let name = self.read_string_solution()?;
                 format!("{:04} {} \"{}\"", start_offset, opcode.name(), name)
             }
             
-            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Call => {
+            OperandKind::Address => {
                 let addr = self.read_usize_solution()?;
                 format!("{:04} {} {}", start_offset, opcode.name(), addr)
             }
             
-            _ => {
+            OperandKind::Closure => {
+                let addr = self.read_usize_solution()?;
+                let mut text = format!("{:04} {} {}", start_offset, opcode.name(), addr);
+                for _ in 0..self.read_byte_solution()? {
+                    text.push_str(&format!(" \"{}\"", self.read_string_solution()?));
+                }
+                text
+            }
+
+            // switch tables are decoded whole rather than read field by field
+            OperandKind::Table | OperandKind::Lookup => {
+                let instruction = Instruction::decode(&self.module.code, start_offset).ok()?;
+                self.offset = start_offset + instruction.encoded_len();
+                format!("{:04} {}", start_offset, instruction)
+            }
+
+            OperandKind::None => {
                 format!("{:04} {}", start_offset, opcode.name())
             }
         };
@@ -87,12 +117,16 @@ impl Disassembler {
         output.push_str("ADDR INSTRUCTION\n");
         output.push_str("---- -----------\n");
         
-        while self.offset < self.bytecode.len() {
+        while self.offset < self.module.code.len() {
+            for export in self.module.exports.iter().filter(|export| export.address == self.offset) {
+                output.push_str(&format!("     {}: ; arity {}\n", export.name, export.arity));
+            }
+            let start = self.offset;
             if let Some(instruction) = self.disassemble_instruction() {
                 output.push_str(&instruction);
                 output.push('\n');
             } else {
-                output.push_str(&format!("{:04} <invalid>\n", self.offset));
+                output.push_str(&format!("{:04} <invalid>\n", start));
                 break;
             }
         }
@@ -101,9 +135,462 @@ impl Disassembler {
     }
 }
 
+// one entry of a structured listing, for tools that want data instead of text
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedInstruction {
+    pub offset: usize,
+    pub length: usize,
+    // None for a byte that doesn't decode, which is listed on its own like .byte data
+    pub instruction: Option<Instruction>,
+    pub bytes: Vec<u8>,
+}
+
+impl Disassembler {
+    // every instruction in order, carrying on past bytes that don't decode
+    pub fn decoded(&self) -> Vec<DecodedInstruction> {
+        let code = &self.module.code;
+        let mut listing = Vec::new();
+        let mut offset = 0;
+
+        while offset < code.len() {
+            let instruction = Instruction::decode(code, offset).ok();
+            let length = instruction.as_ref().map_or(1, |instruction| instruction.encoded_len());
+            listing.push(DecodedInstruction {
+                offset,
+                length,
+                instruction,
+                bytes: code[offset..offset + length].to_vec(),
+            });
+            offset += length;
+        }
+
+        listing
+    }
+
+    // the decoded listing and export table as JSON:
+    //
+    //   {"instructions": [{"offset": 0, "length": 9, "opcode": "PUSH",
+    //                      "operand": {"kind": "int", "value": 3}, "bytes": [4, 3, ...]}, ...],
+    //    "exports": [{"name": "main", "address": 0, "arity": 0}]}
+    //
+    // undecodable bytes have a null opcode and operand
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"instructions\":[");
+        for (index, decoded) in self.decoded().iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            out.push_str(&format!("{{\"offset\":{},\"length\":{},", decoded.offset, decoded.length));
+            match &decoded.instruction {
+                Some(instruction) => {
+                    out.push_str(&format!("\"opcode\":{},\"operand\":", json_string(instruction.opcode.name())));
+                    out.push_str(&operand_json(&instruction.operand));
+                }
+                None => out.push_str("\"opcode\":null,\"operand\":null"),
+            }
+            let bytes: Vec<String> = decoded.bytes.iter().map(|byte| byte.to_string()).collect();
+            out.push_str(&format!(",\"bytes\":[{}]}}", bytes.join(",")));
+        }
+
+        out.push_str("],\"exports\":[");
+        for (index, export) in self.module.exports.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            out.push_str(&format!(
+                "{{\"name\":{},\"address\":{},\"arity\":{}}}",
+                json_string(&export.name),
+                export.address,
+                export.arity
+            ));
+        }
+        out.push_str("]}");
+        out
+    }
+
+    // assembler source rather than a table: every jump and call target gets a label (L0012),
+    // function entries are marked, string operands are annotated with their length and bytes
+    // that don't decode are kept as .byte data, so assembling the text gives back the same bytes
+    pub fn disassemble_source(&self) -> String {
+        let module = &self.module;
+        let code = &module.code;
+
+        let items: Vec<(usize, Option<Instruction>)> = self
+            .decoded()
+            .into_iter()
+            .map(|decoded| (decoded.offset, decoded.instruction))
+            .collect();
+        let starts: BTreeSet<usize> = items.iter().map(|(offset, _)| *offset).collect();
+
+        let import_sites: HashMap<usize, &str> = module.imports
+            .iter()
+            .flat_map(|import| import.sites.iter().map(move |site| (*site, import.name.as_str())))
+            .collect();
+
+        // a target in the middle of an instruction can't have a label, it stays a number
+        let mut labels = BTreeSet::new();
+        let mut functions = BTreeSet::new();
+        for (offset, instruction) in &items {
+            let Some(instruction) = instruction else { continue };
+            if import_sites.contains_key(offset) {
+                continue;
+            }
+            for target in instruction.targets().into_iter().filter(|target| starts.contains(target)) {
+                labels.insert(target);
+                if matches!(instruction.opcode, OpCode::Call | OpCode::TailCall | OpCode::MakeClosure) {
+                    functions.insert(target);
+                }
+            }
+        }
+        let exports: Vec<_> = module.exports
+            .iter()
+            .filter(|export| starts.contains(&export.address))
+            .collect();
+        functions.extend(exports.iter().map(|export| export.address));
+
+        let mut output = String::new();
+        if !module.name.is_empty() {
+            output.push_str(&format!(".module {}\n", module.name));
+        }
+        for import in &module.imports {
+            output.push_str(&format!(".import {}\n", import.name));
+        }
+        for global in &module.private_globals {
+            output.push_str(&format!(".private \"{}\"\n", escape_string(global)));
+        }
+        for record in &module.records {
+            output.push_str(&format!(".record {}", record.name));
+            for field in &record.fields {
+                output.push_str(&format!(" {}", field));
+            }
+            output.push('\n');
+        }
+        for declared in &module.enums {
+            output.push_str(&format!(".enum {}", declared.name));
+            for variant in &declared.variants {
+                match variant.arity {
+                    0 => output.push_str(&format!(" {}", variant.name)),
+                    arity => output.push_str(&format!(" {}:{}", variant.name, arity)),
+                }
+            }
+            output.push('\n');
+        }
+        for export in &exports {
+            output.push_str(&format!(".export {} {}\n", export.name, export.arity));
+        }
+        if !output.is_empty() {
+            output.push('\n');
+        }
+
+        let mut index = 0;
+        while index < items.len() {
+            let offset = items[index].0;
+            if functions.contains(&offset) {
+                output.push_str("; function\n");
+            }
+            for export in exports.iter().filter(|export| export.address == offset) {
+                output.push_str(&format!("{}:\n", export.name));
+            }
+            if labels.contains(&offset) {
+                output.push_str(&format!("L{:04}:\n", offset));
+            }
+
+            let Some(instruction) = &items[index].1 else {
+                // a run of data bytes, broken up wherever something needs a label
+                let mut bytes = vec![format!("0x{:02x}", code[offset])];
+                index += 1;
+                while let Some((next, None)) = items.get(index) {
+                    if functions.contains(next) || labels.contains(next) {
+                        break;
+                    }
+                    bytes.push(format!("0x{:02x}", code[*next]));
+                    index += 1;
+                }
+                output.push_str(&format!("    .byte {}\n", bytes.join(" ")));
+                continue;
+            };
+
+            let label = |addr: &usize| {
+                if labels.contains(addr) { format!("L{:04}", addr) } else { addr.to_string() }
+            };
+            let line = match &instruction.operand {
+                Operand::None => instruction.opcode.name().to_string(),
+                Operand::Int(n) => format!("{} {}", instruction.opcode.name(), n),
+                Operand::Byte(b) => format!("{} {}", instruction.opcode.name(), b),
+                Operand::Name(name) => format!(
+                    "{} \"{}\"    ; {} bytes",
+                    instruction.opcode.name(),
+                    escape_string(name),
+                    name.len()
+                ),
+                Operand::Address(addr) => {
+                    let target = match import_sites.get(&offset) {
+                        Some(import) => import.to_string(),
+                        None if labels.contains(addr) => format!("L{:04}", addr),
+                        None => addr.to_string(),
+                    };
+                    format!("{} {}", instruction.opcode.name(), target)
+                }
+                Operand::Closure(addr, names) => {
+                    let target = match import_sites.get(&offset) {
+                        Some(import) => import.to_string(),
+                        None if labels.contains(addr) => format!("L{:04}", addr),
+                        None => addr.to_string(),
+                    };
+                    let mut line = format!("{} {}", instruction.opcode.name(), target);
+                    for name in names {
+                        line.push_str(&format!(" \"{}\"", escape_string(name)));
+                    }
+                    line
+                }
+                Operand::Table(low, default, targets) => {
+                    let mut line = format!("{} {} {}", instruction.opcode.name(), low, label(default));
+                    for target in targets {
+                        line.push_str(&format!(" {}", label(target)));
+                    }
+                    line
+                }
+                Operand::Lookup(default, cases) => {
+                    let mut line = format!("{} {}", instruction.opcode.name(), label(default));
+                    for (key, target) in cases {
+                        line.push_str(&format!(" {}:{}", key, label(target)));
+                    }
+                    line
+                }
+            };
+            output.push_str(&format!("    {}\n", line));
+            index += 1;
+        }
+
+        output
+    }
+}
+
 pub fn disassemble(bytecode: Vec<u8>) -> String {
     let mut disas= Disassembler::new(bytecode);
     disas.disassemble()
 }
 
+pub fn disassemble_module(module: &Module) -> String {
+    let mut disas = Disassembler::for_module(module);
+    disas.disassemble()
+}
+
+fn operand_json(operand: &Operand) -> String {
+    match operand {
+        Operand::None => "null".to_string(),
+        Operand::Int(n) => format!("{{\"kind\":\"int\",\"value\":{}}}", n),
+        Operand::Byte(b) => format!("{{\"kind\":\"byte\",\"value\":{}}}", b),
+        Operand::Name(name) => format!("{{\"kind\":\"name\",\"value\":{}}}", json_string(name)),
+        Operand::Address(addr) => format!("{{\"kind\":\"address\",\"value\":{}}}", addr),
+        Operand::Closure(addr, names) => {
+            let names: Vec<String> = names.iter().map(|name| json_string(name)).collect();
+            format!("{{\"kind\":\"closure\",\"address\":{},\"captures\":[{}]}}", addr, names.join(","))
+        }
+        Operand::Table(low, default, targets) => {
+            let targets: Vec<String> = targets.iter().map(|target| target.to_string()).collect();
+            format!(
+                "{{\"kind\":\"table\",\"low\":{},\"default\":{},\"targets\":[{}]}}",
+                low,
+                default,
+                targets.join(",")
+            )
+        }
+        Operand::Lookup(default, cases) => {
+            let cases: Vec<String> = cases
+                .iter()
+                .map(|(key, target)| format!("{{\"key\":{},\"target\":{}}}", key, target))
+                .collect();
+            format!("{{\"kind\":\"lookup\",\"default\":{},\"cases\":[{}]}}", default, cases.join(","))
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn disassemble_json(bytecode: Vec<u8>) -> String {
+    Disassembler::new(bytecode).to_json()
+}
+
+pub fn disassemble_module_json(module: &Module) -> String {
+    Disassembler::for_module(module).to_json()
+}
+
+// labelled assembler source for the bytecode, see Disassembler::disassemble_source
+pub fn disassemble_source(bytecode: Vec<u8>) -> String {
+    Disassembler::new(bytecode).disassemble_source()
+}
+
+pub fn disassemble_module_source(module: &Module) -> String {
+    Disassembler::for_module(module).disassemble_source()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, assemble_module};
+
+    #[test]
+    fn test_disassemble_operands() {
+        let bytecode = assemble("PUSH -3\nPICK 1\nSTORE_VAR \"x\"\nJUMP 0\nHALT").unwrap();
+        let text = disassemble(bytecode);
+
+        assert!(text.contains("0000 PUSH -3\n"));
+        assert!(text.contains("0009 PICK 1\n"));
+        assert!(text.contains("0011 STORE_VAR \"x\"\n"));
+        assert!(text.contains("0014 JUMP 0\n"));
+        assert!(text.contains("0023 HALT\n"));
+    }
 
+    #[test]
+    fn test_export_labels() {
+        let module = assemble_module(".export inc 1\nHALT\ninc:\nPUSH 1\nADD\nRETURN").unwrap();
+        let text = disassemble_module(&module);
+
+        assert!(text.contains("0000 HALT\n     inc: ; arity 1\n0001 PUSH 1\n"));
+    }
+
+    #[test]
+    fn test_source_round_trip() {
+        let source = r#"
+            PUSH 3
+        loop:
+            DUP
+            CALL show
+            PUSH -1
+            ADD
+            DUP
+            JUMP_IF_TRUE loop
+            PUSH -9223372036854775808
+            PRINT "tab\tquote\" done\n"
+            HALT
+        show:
+            PRINT_VAL
+            RETURN
+        "#;
+        let bytecode = assemble(source).unwrap();
+        let text = disassemble_source(bytecode.clone());
+
+        assert!(text.contains("L0009:\n    DUP\n    CALL L0067\n"));
+        assert!(text.contains("; function\nL0067:\n    PRINT_VAL\n"));
+        assert!(text.contains("    JUMP_IF_TRUE L0009\n"));
+        assert!(text.contains("PRINT \"tab\\tquote\\\" done\\n\"    ; 16 bytes\n"));
+        assert_eq!(assemble(&text).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn test_switches() {
+        let source = "PUSH 1\nTABLE_SWITCH 0 other a b\na:\nHALT\nb:\nHALT\nother:\nLOOKUP_SWITCH a -2:b\n";
+        let bytecode = assemble(source).unwrap();
+
+        let table = disassemble(bytecode.clone());
+        assert!(table.contains("0009 TABLE_SWITCH 0 48 46 47\n0046 HALT\n"));
+        assert!(table.contains("0048 LOOKUP_SWITCH 46 -2:47\n"));
+
+        let text = disassemble_source(bytecode.clone());
+        assert!(text.contains("    TABLE_SWITCH 0 L0048 L0046 L0047\n"));
+        assert!(text.contains("    LOOKUP_SWITCH L0046 -2:L0047\n"));
+        assert_eq!(assemble(&text).unwrap(), bytecode);
+
+        let json = disassemble_json(bytecode);
+        assert!(json.contains("\"operand\":{\"kind\":\"table\",\"low\":0,\"default\":48,\"targets\":[46,47]}"));
+        assert!(json.contains("\"operand\":{\"kind\":\"lookup\",\"default\":46,\"cases\":[{\"key\":-2,\"target\":47}]}"));
+    }
+
+    #[test]
+    fn test_module_source_round_trip() {
+        let module = assemble_module(
+            ".module main\n.import helper\n.private \"n\"\n.record Point x y\n.enum Option None Some:1\n.export twice 1\nCALL helper\nHALT\ntwice:\nDUP\nADD\nRETURN",
+        )
+        .unwrap();
+        let text = disassemble_module_source(&module);
+        assert!(text.contains(".record Point x y\n.enum Option None Some:1\n"));
+
+        assert!(text.contains("    CALL helper\n"));
+        assert!(text.contains("; function\ntwice:\n    DUP\n"));
+        assert_eq!(assemble_module(&text).unwrap(), module);
+    }
+
+    #[test]
+    fn test_invalid_bytes_become_data() {
+        let mut bytecode = assemble("JUMP 12\nHALT").unwrap();
+        bytecode.extend_from_slice(&[250, 251, 5, 4, 255]);
+        let text = disassemble_source(bytecode.clone());
+
+        assert!(text.contains("    HALT\n    .byte 0xfa 0xfb\nL0012:\n    HALT\n    .byte 0x04 0xff\n"));
+        assert_eq!(assemble(&text).unwrap(), bytecode);
+        // the table format still gives up at the first bad byte
+        assert!(disassemble(bytecode).contains("0010 <invalid>\n"));
+    }
+
+    #[test]
+    fn test_arbitrary_bytes_round_trip() {
+        // whatever the bytes are, the source assembles back to them
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..50 {
+            let bytecode: Vec<u8> = (0..200)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    // mostly valid opcodes so real instructions show up
+                    if seed.is_multiple_of(4) { (seed >> 8) as u8 } else { (seed >> 8) as u8 % 74 }
+                })
+                .collect();
+            let text = disassemble_source(bytecode.clone());
+            assert_eq!(assemble(&text).unwrap(), bytecode, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_decoded_listing() {
+        let mut bytecode = assemble("PUSH 2\nSTORE_VAR \"x\"\nJUMP 0").unwrap();
+        bytecode.push(250);
+        let listing = Disassembler::new(bytecode).decoded();
+
+        assert_eq!(listing.len(), 4);
+        assert_eq!((listing[1].offset, listing[1].length), (9, 3));
+        assert_eq!(listing[1].bytes, vec![6, 1, b'x']);
+        assert_eq!(
+            listing[2].instruction,
+            Some(Instruction::new(OpCode::Jump, Operand::Address(0)))
+        );
+        assert_eq!((listing[3].offset, listing[3].instruction.clone()), (21, None));
+    }
+
+    #[test]
+    fn test_json_output() {
+        let mut module = assemble_module(".export f 0\nf:\nPICK 1\nPRINT \"a\\\"\\n\"\nRETURN").unwrap();
+        module.code.push(255);
+        let json = disassemble_module_json(&module);
+
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"instructions":["#,
+                r#"{"offset":0,"length":2,"opcode":"PICK","operand":{"kind":"byte","value":1},"bytes":[43,1]},"#,
+                r#"{"offset":2,"length":5,"opcode":"PRINT","operand":{"kind":"name","value":"a\"\n"},"bytes":[20,3,97,34,10]},"#,
+                r#"{"offset":7,"length":1,"opcode":"RETURN","operand":null,"bytes":[17]},"#,
+                r#"{"offset":8,"length":1,"opcode":null,"operand":null,"bytes":[255]}],"#,
+                r#""exports":[{"name":"f","address":0,"arity":0}]}"#,
+            )
+        );
+        assert!(disassemble_json(vec![]).starts_with("{\"instructions\":[]"));
+    }
+}
@@ -1,5 +1,7 @@
 use std::fmt;
 
+use crate::coroutine::CoroutineStatus;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum VMError {
     StackUnderflow,
@@ -10,6 +12,48 @@ pub enum VMError {
     UndefinedVariable(String),
     InvalidString,
     InfiniteLoopDetected,
+    IntegerOverflow,
+    // the host's output sink failed, holds the io error message
+    Io(String),
+    // a read opcode found no more input
+    EndOfInput,
+    // READ_INT found something that isn't an integer, holds the offending text
+    InvalidInput(String),
+    // CALL_NATIVE named a function the host never registered
+    UndefinedNative(String),
+    // an error raised by a host function, holds its message
+    Native(String),
+    // a function run through VM::call_function hit HALT instead of returning
+    HaltedInCall,
+    // snapshot bytes that don't decode, holds what was wrong
+    InvalidSnapshot(String),
+    // the snapshot was taken against different bytecode than the VM has loaded
+    SnapshotMismatch,
+    // the loaded module has no export with this name
+    UndefinedExport(String),
+    // a function was called with the wrong number of arguments
+    ArityMismatch { name: String, expected: usize, found: usize },
+    // a function value whose heap object was already collected, the host forgot to pin it
+    DanglingHandle(u32),
+    // RESUME on a coroutine that is already running or has finished
+    CannotResume(CoroutineStatus),
+    // YIELD while no coroutine is running
+    YieldOutsideCoroutine,
+    // NEW_RECORD named a record type the loaded module doesn't declare
+    UndefinedRecord(String),
+    // a field access on a value that isn't a record, holds the value's type name
+    NotARecord(&'static str),
+    // a field name or position the record's type doesn't have
+    NoSuchField { record: String, field: String },
+    // NEW_VARIANT named an "Enum.Variant" the loaded module doesn't declare
+    UndefinedVariant(String),
+    // a variant opcode on a value that isn't an enum variant, holds the value's type name
+    NotAVariant(&'static str),
+    // PAYLOAD_AT past the end of the variant's payload
+    NoSuchPayload { variant: String, index: usize },
+    // FORMAT with a format string that doesn't parse or an argument its spec can't show,
+    // holds what was wrong
+    InvalidFormat(String),
 }
 
 impl fmt::Display for VMError {
@@ -40,10 +84,79 @@ impl fmt::Display for VMError {
             VMError::InfiniteLoopDetected => {
                 write!(f, "Infinite loop detected")
             }
+            VMError::IntegerOverflow => {
+                write!(f, "Integer overflow in arithmetic operation")
+            }
+            VMError::Io(message) => {
+                write!(f, "I/O error: {}", message)
+            }
+            VMError::EndOfInput => {
+                write!(f, "Unexpected end of input")
+            }
+            VMError::InvalidInput(text) => {
+                write!(f, "Invalid input: expected an integer, found '{}'", text)
+            }
+            VMError::UndefinedNative(name) => {
+                write!(f, "Undefined native function: {}", name)
+            }
+            VMError::Native(message) => {
+                write!(f, "Native function error: {}", message)
+            }
+            VMError::HaltedInCall => {
+                write!(f, "Program halted before the called function returned")
+            }
+            VMError::InvalidSnapshot(message) => {
+                write!(f, "Invalid snapshot: {}", message)
+            }
+            VMError::SnapshotMismatch => {
+                write!(f, "Snapshot was taken against different bytecode")
+            }
+            VMError::UndefinedExport(name) => {
+                write!(f, "Undefined export: {}", name)
+            }
+            VMError::ArityMismatch { name, expected, found } => {
+                write!(f, "{} expects {} argument(s), got {}", name, expected, found)
+            }
+            VMError::DanglingHandle(handle) => {
+                write!(f, "Use of collected heap object #{}", handle)
+            }
+            VMError::CannotResume(status) => {
+                write!(f, "Cannot resume a {} coroutine", status)
+            }
+            VMError::YieldOutsideCoroutine => {
+                write!(f, "Yield outside of a coroutine")
+            }
+            VMError::UndefinedRecord(name) => {
+                write!(f, "Undefined record type: {}", name)
+            }
+            VMError::NotARecord(type_name) => {
+                write!(f, "Field access on a {}, expected a record", type_name)
+            }
+            VMError::NoSuchField { record, field } => {
+                write!(f, "Record {} has no field {}", record, field)
+            }
+            VMError::UndefinedVariant(name) => {
+                write!(f, "Undefined enum variant: {}", name)
+            }
+            VMError::NotAVariant(type_name) => {
+                write!(f, "Variant operation on a {}, expected an enum variant", type_name)
+            }
+            VMError::NoSuchPayload { variant, index } => {
+                write!(f, "Variant {} has no payload value {}", variant, index)
+            }
+            VMError::InvalidFormat(message) => {
+                write!(f, "Invalid format: {}", message)
+            }
         }
     }
 }
 
+impl From<std::io::Error> for VMError {
+    fn from(err: std::io::Error) -> Self {
+        VMError::Io(err.to_string())
+    }
+}
+
 // This allows VMError to be used with the ? operator and Result types
 impl std::error::Error for VMError {}
 
@@ -68,7 +68,6 @@ pub fn example_if_else() {
     bytecode.push(OpCode::Gte.convert_to_u8());
     
     // JUMP_IF_FALSE to else block
-    let condition_end = bytecode.len();
     bytecode.push(OpCode::JumpIfFalse.convert_to_u8());
     
     // Reserve space for jump address 
@@ -76,7 +75,6 @@ pub fn example_if_else() {
     bytecode.extend(encode_usize(0)); // Placeholder
     
     // Then block: can_vote = 1
-    let then_start = bytecode.len();
     bytecode.push(OpCode::Push.convert_to_u8());
     bytecode.extend(encode_i64(1));
     bytecode.push(OpCode::StoreVar.convert_to_u8());
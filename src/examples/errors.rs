@@ -1,6 +1,6 @@
 use crate::vm::VM;
 use crate::opcode::OpCode;
-use super::utils::{encode_i64, encode_string};
+use super::utils::encode_i64;
 
 pub fn example_errors() {
     println!("Example 3: Error Handling");
@@ -20,12 +20,13 @@ pub fn example_errors() {
     
     println!("  3b. Division by Zero:");
     let mut vm = VM::new();
-    let bytecode = vec![
-        OpCode::Push.convert_to_u8(), 10,
-        OpCode::Push.convert_to_u8(), 0,
-        OpCode::Div.convert_to_u8(),
-        OpCode::Halt.convert_to_u8(),
-    ];
+    let mut bytecode = vec![];
+    bytecode.push(OpCode::Push.convert_to_u8());
+    bytecode.extend(encode_i64(10));
+    bytecode.push(OpCode::Push.convert_to_u8());
+    bytecode.extend(encode_i64(0));
+    bytecode.push(OpCode::Div.convert_to_u8());
+    bytecode.push(OpCode::Halt.convert_to_u8());
     vm.load_bytecode_solution(bytecode);
     
     match vm.run_solution() {
@@ -0,0 +1,312 @@
+// Format strings for FORMAT and PRINT_FORMAT. Text is copied as is, `{{` and `}}` are literal
+// braces and every `{}` or `{:spec}` takes the next argument, so the argument count is known from
+// the string alone. A spec is a subset of rust's:
+//
+//   [[fill]align][#][0][width][.precision][type]
+//
+//   align       `<` left, `>` right, `^` centered; numbers default to right, anything else to left
+//   #           prefix hex, binary and octal with 0x, 0b or 0o
+//   0           pad integers with zeroes after the sign and prefix, instead of with the fill
+//   width       the minimum length. Width and precision are at most MAX_WIDTH
+//   precision   the minimum number of digits of an integer, the maximum length of anything else
+//   type        `x` / `X` hex, `b` binary or `o` octal, integers only. Negative integers are
+//               shown as their two's complement, like rust does
+
+use crate::value::Value;
+
+// the largest width or precision a spec may give
+pub const MAX_WIDTH: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Radix {
+    Decimal,
+    LowerHex,
+    UpperHex,
+    Binary,
+    Octal,
+}
+
+impl Radix {
+    fn name(self) -> &'static str {
+        match self {
+            Radix::Decimal => "decimal",
+            Radix::LowerHex | Radix::UpperHex => "hex",
+            Radix::Binary => "binary",
+            Radix::Octal => "octal",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    pub fill: char,
+    pub align: Option<Align>,
+    pub alternate: bool,
+    pub zero: bool,
+    pub width: usize,
+    pub precision: Option<usize>,
+    pub radix: Radix,
+}
+
+impl Default for Spec {
+    fn default() -> Self {
+        Spec {
+            fill: ' ',
+            align: None,
+            alternate: false,
+            zero: false,
+            width: 0,
+            precision: None,
+            radix: Radix::Decimal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Text(String),
+    Argument(Spec),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatString {
+    pub pieces: Vec<Piece>,
+}
+
+impl FormatString {
+    pub fn parse(format: &str) -> Result<FormatString, String> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => return Err("unmatched '}'".to_string()),
+                '{' => {
+                    let mut inside = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inside.push(c),
+                            None => return Err("unclosed '{'".to_string()),
+                        }
+                    }
+                    let spec = match inside.strip_prefix(':') {
+                        Some(spec) => parse_spec(spec)?,
+                        None if inside.is_empty() => Spec::default(),
+                        None => return Err(format!("expected '}}' or ':' in '{{{}}}'", inside)),
+                    };
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Argument(spec));
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(FormatString { pieces })
+    }
+
+    // how many values the string takes from the stack
+    pub fn arguments(&self) -> usize {
+        self.pieces.iter().filter(|piece| matches!(piece, Piece::Argument(_))).count()
+    }
+
+    // `display` renders values that aren't integers, so the VM can show functions and records
+    pub fn render(&self, args: &[Value], display: impl Fn(&Value) -> String) -> Result<String, String> {
+        let mut args = args.iter();
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Argument(spec) => {
+                    let value = args.next().ok_or("not enough arguments")?;
+                    out.push_str(&spec.apply(value, &display)?);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn parse_spec(spec: &str) -> Result<Spec, String> {
+    let chars: Vec<char> = spec.chars().collect();
+    let align = |c: &char| match c {
+        '<' => Some(Align::Left),
+        '>' => Some(Align::Right),
+        '^' => Some(Align::Center),
+        _ => None,
+    };
+
+    let mut result = Spec::default();
+    let mut i = 0;
+    if let Some(a) = chars.get(1).and_then(align) {
+        result.fill = chars[0];
+        result.align = Some(a);
+        i = 2;
+    } else if let Some(a) = chars.first().and_then(align) {
+        result.align = Some(a);
+        i = 1;
+    }
+    if chars.get(i) == Some(&'#') {
+        result.alternate = true;
+        i += 1;
+    }
+    if chars.get(i) == Some(&'0') {
+        result.zero = true;
+        i += 1;
+    }
+    let (width, next) = digits(&chars, i)?;
+    result.width = width.unwrap_or(0);
+    i = next;
+    if chars.get(i) == Some(&'.') {
+        let (precision, next) = digits(&chars, i + 1)?;
+        result.precision = Some(precision.ok_or_else(|| format!("missing precision in ':{}'", spec))?);
+        i = next;
+    }
+    result.radix = match chars.get(i) {
+        Some('x') => Radix::LowerHex,
+        Some('X') => Radix::UpperHex,
+        Some('b') => Radix::Binary,
+        Some('o') => Radix::Octal,
+        _ => Radix::Decimal,
+    };
+    if result.radix != Radix::Decimal {
+        i += 1;
+    }
+    if i != chars.len() {
+        return Err(format!("invalid format spec ':{}'", spec));
+    }
+    Ok(result)
+}
+
+// the number starting at `start`, if there is one, and where it ends
+fn digits(chars: &[char], start: usize) -> Result<(Option<usize>, usize), String> {
+    let end = start + chars[start.min(chars.len())..].iter().take_while(|c| c.is_ascii_digit()).count();
+    if end == start {
+        return Ok((None, start));
+    }
+    let text: String = chars[start..end].iter().collect();
+    match text.parse() {
+        Ok(n) if n <= MAX_WIDTH => Ok((Some(n), end)),
+        _ => Err(format!("{} is larger than {}", text, MAX_WIDTH)),
+    }
+}
+
+impl Spec {
+    pub fn apply(&self, value: &Value, display: impl Fn(&Value) -> String) -> Result<String, String> {
+        let (sign, prefix, mut body) = match (value, self.radix) {
+            (Value::Integer(n), Radix::Decimal) => {
+                (if *n < 0 { "-" } else { "" }, "", n.unsigned_abs().to_string())
+            }
+            (Value::Integer(n), Radix::LowerHex) => ("", "0x", format!("{:x}", n)),
+            (Value::Integer(n), Radix::UpperHex) => ("", "0x", format!("{:X}", n)),
+            (Value::Integer(n), Radix::Binary) => ("", "0b", format!("{:b}", n)),
+            (Value::Integer(n), Radix::Octal) => ("", "0o", format!("{:o}", n)),
+            (other, Radix::Decimal) => ("", "", display(other)),
+            (other, radix) => {
+                return Err(format!("{} formatting needs an int, found {}", radix.name(), other.type_name()))
+            }
+        };
+        let integer = matches!(value, Value::Integer(_));
+        let prefix = if self.alternate { prefix } else { "" };
+
+        match self.precision {
+            Some(digits) if integer && body.len() < digits => body.insert_str(0, &"0".repeat(digits - body.len())),
+            Some(length) if !integer => body = body.chars().take(length).collect(),
+            _ => {}
+        }
+
+        let len = sign.len() + prefix.len() + body.chars().count();
+        let padding = self.width.saturating_sub(len);
+        if self.zero && integer {
+            return Ok(format!("{}{}{}{}", sign, prefix, "0".repeat(padding), body));
+        }
+
+        let text = format!("{}{}{}", sign, prefix, body);
+        let fill = |n: usize| self.fill.to_string().repeat(n);
+        let align = self.align.unwrap_or(if integer { Align::Right } else { Align::Left });
+        Ok(match align {
+            Align::Left => text + &fill(padding),
+            Align::Right => fill(padding) + &text,
+            Align::Center => fill(padding / 2) + &text + &fill(padding - padding / 2),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(format: &str, args: &[Value]) -> Result<String, String> {
+        FormatString::parse(format)?.render(args, |value| value.to_string())
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let args = [Value::Integer(5), Value::Integer(120)];
+        assert_eq!(format("{}! = {}", &args), Ok("5! = 120".to_string()));
+        assert_eq!(format("{{{}}}", &args[..1]), Ok("{5}".to_string()));
+        assert_eq!(FormatString::parse("a {} b {:>4} {{}}").unwrap().arguments(), 2);
+        assert_eq!(format("{} {}", &args[..1]), Err("not enough arguments".to_string()));
+    }
+
+    #[test]
+    fn test_padding_and_alignment() {
+        let n = [Value::Integer(-42)];
+        let s = [Value::str_solution("abc")];
+        assert_eq!(format("[{:6}]", &n), Ok("[   -42]".to_string()));
+        assert_eq!(format("[{:<6}]", &n), Ok("[-42   ]".to_string()));
+        assert_eq!(format("[{:06}]", &n), Ok("[-00042]".to_string()));
+        assert_eq!(format("[{:.4}]", &n), Ok("[-0042]".to_string()));
+        assert_eq!(format("[{:6}]", &s), Ok("[abc   ]".to_string()));
+        assert_eq!(format("[{:*^7}]", &s), Ok("[**abc**]".to_string()));
+        assert_eq!(format("[{:>5.2}]", &s), Ok("[   ab]".to_string()));
+    }
+
+    #[test]
+    fn test_radixes() {
+        let n = Value::Integer(255);
+        let args = [n.clone(), n.clone(), n, Value::Integer(5), Value::Integer(8)];
+        assert_eq!(format("{:x} {:X} {:#x} {:b} {:o}", &args), Ok("ff FF 0xff 101 10".to_string()));
+        assert_eq!(format("{:#010b}", &[Value::Integer(5)]), Ok("0b00000101".to_string()));
+        assert_eq!(format("{:x}", &[Value::Integer(-1)]), Ok("ffffffffffffffff".to_string()));
+        assert_eq!(
+            format("{:x}", &[Value::bool_solution(true)]),
+            Err("hex formatting needs an int, found bool".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bad_format_strings() {
+        assert_eq!(FormatString::parse("{"), Err("unclosed '{'".to_string()));
+        assert_eq!(FormatString::parse("}"), Err("unmatched '}'".to_string()));
+        assert_eq!(FormatString::parse("{0}"), Err("expected '}' or ':' in '{0}'".to_string()));
+        assert_eq!(FormatString::parse("{:y}"), Err("invalid format spec ':y'".to_string()));
+        assert_eq!(FormatString::parse("{:.}"), Err("missing precision in ':.'".to_string()));
+        assert_eq!(
+            FormatString::parse("{:18446744073709551615}"),
+            Err("18446744073709551615 is larger than 65535".to_string())
+        );
+        assert_eq!(FormatString::parse("{:.65536}"), Err("65536 is larger than 65535".to_string()));
+        assert_eq!(format("{:65535}", &[Value::Integer(1)]).map(|s| s.len()), Ok(65535));
+    }
+}
@@ -0,0 +1,312 @@
+// Garbage collected storage for values that can refer to other values. A Value holds a Handle
+// (an index into the heap) instead of owning the object, so objects can form cycles, like a
+// closure that captures the local it is stored in, and still be freed once nothing reaches them.
+// Freed slots are reused, but a slot's generation goes up each time its object is freed, so a
+// handle kept past that finds nothing instead of whatever took the slot over.
+//
+// Collection is mark and sweep. The heap doesn't know where the roots are, the VM gathers them
+// (value stack, every frame's locals, globals) and passes them to collect(); objects the host
+// holds on to have to be pinned so a collection doesn't free them from under it.
+
+use std::collections::HashMap;
+
+use crate::coroutine::Coroutine;
+use crate::record::Record;
+use crate::value::{Closure, Value};
+
+// slot index, and the slot's generation when the handle was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Handle(pub u32, pub u32);
+
+impl Handle {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn generation(self) -> u32 {
+        self.1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Slot {
+    pub generation: u32,
+    pub object: Option<HeapObject>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapObject {
+    Closure(Closure),
+    Coroutine(Coroutine),
+    Record(Record),
+}
+
+impl HeapObject {
+    // handles directly reachable from this object
+    pub fn trace(&self, out: &mut Vec<Handle>) {
+        match self {
+            HeapObject::Closure(closure) => {
+                for (_, cell) in &closure.upvalues {
+                    trace_value(&cell.borrow(), out);
+                }
+            }
+            HeapObject::Coroutine(coroutine) => coroutine.context.trace(out),
+            HeapObject::Record(record) => record.trace(out),
+        }
+    }
+}
+
+// arrays and variants aren't on the heap themselves, but what they hold is reachable
+pub fn trace_value(value: &Value, out: &mut Vec<Handle>) {
+    let items = match value {
+        Value::Array(items) => &items[..],
+        Value::Variant(variant) => &variant.payload[..],
+        _ => {
+            out.extend(value.handle());
+            return;
+        }
+    };
+    for item in items {
+        trace_value(item, out);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    // live objects allowed before the first collection
+    pub initial_threshold: usize,
+    // after a collection the next one happens once the heap is this many times the live size
+    pub growth_factor: usize,
+    // collect on every allocation, to shake out missing roots in tests
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig { initial_threshold: 1024, growth_factor: 2, stress: false }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GcStats {
+    pub collections: usize,
+    pub allocated: usize,
+    pub freed: usize,
+    pub live: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Heap {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    // handles the host holds, with how many times each was pinned
+    pinned: HashMap<Handle, usize>,
+    config: GcConfig,
+    stats: GcStats,
+    next_collection: usize,
+}
+
+impl Heap {
+    pub fn new(config: GcConfig) -> Self {
+        Heap { config, next_collection: config.initial_threshold, ..Heap::default() }
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_collection = config.initial_threshold.max(self.stats.live * config.growth_factor);
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    // whether the next allocation should collect first
+    pub fn should_collect(&self) -> bool {
+        self.config.stress || self.stats.live >= self.next_collection
+    }
+
+    pub fn allocate(&mut self, object: HeapObject) -> Handle {
+        self.stats.allocated += 1;
+        self.stats.live += 1;
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.object = Some(object);
+                Handle(index, slot.generation)
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, object: Some(object) });
+                Handle(self.slots.len() as u32 - 1, 0)
+            }
+        }
+    }
+
+    // None for a handle whose object has been freed, even if its slot has been reused since
+    pub fn get(&self, handle: Handle) -> Option<&HeapObject> {
+        let slot = self.slots.get(handle.index())?;
+        if slot.generation != handle.generation() {
+            return None;
+        }
+        slot.object.as_ref()
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut HeapObject> {
+        let slot = self.slots.get_mut(handle.index())?;
+        if slot.generation != handle.generation() {
+            return None;
+        }
+        slot.object.as_mut()
+    }
+
+    pub fn pin(&mut self, handle: Handle) {
+        *self.pinned.entry(handle).or_insert(0) += 1;
+    }
+
+    pub fn unpin(&mut self, handle: Handle) {
+        if let Some(count) = self.pinned.get_mut(&handle) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(&handle);
+            }
+        }
+    }
+
+    // marks everything reachable from `roots` and the pinned handles, frees the rest and
+    // returns how many objects were freed
+    pub fn collect(&mut self, roots: Vec<Handle>) -> usize {
+        let mut marked = vec![false; self.slots.len()];
+        let mut worklist = roots;
+        worklist.extend(self.pinned.keys());
+
+        while let Some(handle) = worklist.pop() {
+            let index = handle.index();
+            // a stale pin doesn't keep whatever reused its slot alive
+            if index >= marked.len() || marked[index] || self.slots[index].generation != handle.generation() {
+                continue;
+            }
+            marked[index] = true;
+            if let Some(object) = &self.slots[index].object {
+                object.trace(&mut worklist);
+            }
+        }
+
+        let mut freed = 0;
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.object.is_some() && !marked[index] {
+                slot.object = None;
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index as u32);
+                freed += 1;
+            }
+        }
+
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        self.stats.live -= freed;
+        self.next_collection = self.config.initial_threshold.max(self.stats.live * self.config.growth_factor);
+        freed
+    }
+
+    // every slot, empty ones included so handles keep their meaning, for snapshots
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
+
+    // replaces the contents with slots taken from a snapshot, config and pins stay. The host still
+    // holds the values it pinned, a pin whose handle doesn't match the restored slot does nothing
+    pub fn restore(&mut self, slots: Vec<Slot>) {
+        self.free = slots
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, slot)| slot.object.is_none())
+            .map(|(index, _)| index as u32)
+            .collect();
+        self.stats.live = slots.iter().filter(|slot| slot.object.is_some()).count();
+        self.slots = slots;
+        self.next_collection = self.config.initial_threshold.max(self.stats.live * self.config.growth_factor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn closure(captures: Vec<Value>) -> HeapObject {
+        HeapObject::Closure(Closure {
+            address: 0,
+            upvalues: captures
+                .into_iter()
+                .enumerate()
+                .map(|(n, value)| (format!("v{}", n), Rc::new(RefCell::new(value))))
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn test_collects_unreachable_objects() {
+        let mut heap = Heap::new(GcConfig::default());
+        let leaf = heap.allocate(closure(vec![]));
+        let parent = heap.allocate(closure(vec![Value::Function(leaf)]));
+        let garbage = heap.allocate(closure(vec![]));
+
+        assert_eq!(heap.collect(vec![parent]), 1);
+        assert!(heap.get(leaf).is_some());
+        assert!(heap.get(garbage).is_none());
+        assert_eq!(heap.stats(), GcStats { collections: 1, allocated: 3, freed: 1, live: 2 });
+
+        // the freed slot is reused, under a new generation the old handle doesn't match
+        let reused = heap.allocate(closure(vec![]));
+        assert_eq!(reused, Handle(garbage.0, garbage.1 + 1));
+        assert!(heap.get(garbage).is_none());
+        assert!(heap.get(reused).is_some());
+    }
+
+    #[test]
+    fn test_cycles_are_freed() {
+        let mut heap = Heap::new(GcConfig::default());
+        let a = heap.allocate(closure(vec![Value::Integer(0)]));
+        let b = heap.allocate(closure(vec![Value::Function(a)]));
+        if let Some(HeapObject::Closure(closure)) = heap.get(a) {
+            *closure.upvalues[0].1.borrow_mut() = Value::Function(b);
+        }
+
+        assert_eq!(heap.collect(vec![a]), 0);
+        assert_eq!(heap.collect(vec![]), 2);
+    }
+
+    #[test]
+    fn test_pinned_handles_survive() {
+        let mut heap = Heap::new(GcConfig::default());
+        let handle = heap.allocate(closure(vec![]));
+        heap.pin(handle);
+        heap.pin(handle);
+
+        heap.unpin(handle);
+        assert_eq!(heap.collect(vec![]), 0);
+        heap.unpin(handle);
+        assert_eq!(heap.collect(vec![]), 1);
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let mut heap = Heap::new(GcConfig { initial_threshold: 2, growth_factor: 3, stress: false });
+        heap.allocate(closure(vec![]));
+        assert!(!heap.should_collect());
+        let kept = heap.allocate(closure(vec![]));
+        assert!(heap.should_collect());
+
+        heap.collect(vec![kept]);
+        // one live object, but never below the initial threshold
+        assert!(!heap.should_collect());
+
+        heap.set_config(GcConfig { stress: true, ..heap.config() });
+        assert!(heap.should_collect());
+    }
+}
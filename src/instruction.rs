@@ -0,0 +1,329 @@
+// Decoded form of the bytecode. The VM reads raw bytes directly, but the assembler and the tools
+// that rewrite or inspect programs are much easier to write against whole instructions
+
+use std::fmt;
+
+use crate::opcode::{OpCode, OperandKind};
+use crate::error::VMError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    None,
+    Int(i64),
+    Byte(u8),
+    Name(String),
+    Address(usize),
+    // function address and the names of the locals it captures
+    Closure(usize, Vec<String>),
+    // first case value, default target and the target for each case value from the first up
+    Table(i64, usize, Vec<usize>),
+    // default target and (key, target) cases sorted by key
+    Lookup(usize, Vec<(i64, usize)>),
+}
+
+impl Operand {
+    pub fn kind(&self) -> OperandKind {
+        match self {
+            Operand::None => OperandKind::None,
+            Operand::Int(_) => OperandKind::Int,
+            Operand::Byte(_) => OperandKind::Byte,
+            Operand::Name(_) => OperandKind::Name,
+            Operand::Address(_) => OperandKind::Address,
+            Operand::Closure(..) => OperandKind::Closure,
+            Operand::Table(..) => OperandKind::Table,
+            Operand::Lookup(..) => OperandKind::Lookup,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub opcode: OpCode,
+    pub operand: Operand,
+}
+
+impl Instruction {
+    pub fn new(opcode: OpCode, operand: Operand) -> Self {
+        Instruction { opcode, operand }
+    }
+
+    // an instruction without operands
+    pub fn simple(opcode: OpCode) -> Self {
+        Instruction { opcode, operand: Operand::None }
+    }
+
+    // bytecode offsets this instruction refers to: where it can transfer control to, or the
+    // function a MAKE_CLOSURE builds a value for. A switch lists its default first, then its cases
+    pub fn targets(&self) -> Vec<usize> {
+        match &self.operand {
+            Operand::Address(addr) | Operand::Closure(addr, _) => vec![*addr],
+            Operand::Table(_, default, targets) => {
+                std::iter::once(*default).chain(targets.iter().copied()).collect()
+            }
+            Operand::Lookup(default, cases) => {
+                std::iter::once(*default).chain(cases.iter().map(|(_, target)| *target)).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    // same as targets(), for rewriting them in place
+    pub fn targets_mut(&mut self) -> Vec<&mut usize> {
+        match &mut self.operand {
+            Operand::Address(addr) | Operand::Closure(addr, _) => vec![addr],
+            Operand::Table(_, default, targets) => std::iter::once(default).chain(targets.iter_mut()).collect(),
+            Operand::Lookup(default, cases) => {
+                std::iter::once(default).chain(cases.iter_mut().map(|(_, target)| target)).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    // number of bytes this instruction takes up in the bytecode
+    pub fn encoded_len(&self) -> usize {
+        1 + match &self.operand {
+            Operand::None => 0,
+            Operand::Byte(_) => 1,
+            Operand::Int(_) | Operand::Address(_) => 8,
+            Operand::Name(name) => 1 + name.len(),
+            Operand::Closure(_, names) => 9 + names.iter().map(|name| 1 + name.len()).sum::<usize>(),
+            Operand::Table(_, _, targets) => 20 + 8 * targets.len(),
+            Operand::Lookup(_, cases) => 12 + 16 * cases.len(),
+        }
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.opcode.convert_to_u8());
+        match &self.operand {
+            Operand::None => {}
+            Operand::Int(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Operand::Byte(b) => out.push(*b),
+            Operand::Address(addr) => out.extend_from_slice(&addr.to_le_bytes()),
+            Operand::Name(name) => encode_name(name, out),
+            Operand::Closure(addr, names) => {
+                out.extend_from_slice(&addr.to_le_bytes());
+                out.push(names.len() as u8);
+                for name in names {
+                    encode_name(name, out);
+                }
+            }
+            Operand::Table(low, default, targets) => {
+                out.extend_from_slice(&low.to_le_bytes());
+                out.extend_from_slice(&default.to_le_bytes());
+                out.extend_from_slice(&(targets.len() as u32).to_le_bytes());
+                for target in targets {
+                    out.extend_from_slice(&target.to_le_bytes());
+                }
+            }
+            Operand::Lookup(default, cases) => {
+                out.extend_from_slice(&default.to_le_bytes());
+                out.extend_from_slice(&(cases.len() as u32).to_le_bytes());
+                for (key, target) in cases {
+                    out.extend_from_slice(&key.to_le_bytes());
+                    out.extend_from_slice(&target.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    // decode the instruction starting at `offset`
+    pub fn decode(bytecode: &[u8], offset: usize) -> Result<Instruction, VMError> {
+        let mut reader = Reader { bytecode, offset };
+        let byte = reader.read_byte()?;
+        let opcode = OpCode::convert_from_u8(byte).ok_or(VMError::InvalidOpCode(byte))?;
+
+        let operand = match opcode.operand_kind() {
+            OperandKind::None => Operand::None,
+            OperandKind::Int => Operand::Int(i64::from_le_bytes(reader.read_array()?)),
+            OperandKind::Byte => Operand::Byte(reader.read_byte()?),
+            OperandKind::Address => Operand::Address(usize::from_le_bytes(reader.read_array()?)),
+            OperandKind::Name => Operand::Name(reader.read_name()?),
+            OperandKind::Closure => {
+                let addr = usize::from_le_bytes(reader.read_array()?);
+                let count = reader.read_byte()?;
+                let names = (0..count).map(|_| reader.read_name()).collect::<Result<_, _>>()?;
+                Operand::Closure(addr, names)
+            }
+            OperandKind::Table => {
+                let low = i64::from_le_bytes(reader.read_array()?);
+                let default = usize::from_le_bytes(reader.read_array()?);
+                // no capacity up front, a bogus count runs out of bytes before it runs out of memory
+                let mut targets = Vec::new();
+                for _ in 0..reader.read_u32()? {
+                    targets.push(usize::from_le_bytes(reader.read_array()?));
+                }
+                // the last case value has to be an i64 too
+                if targets.len() > 1 && low.checked_add(targets.len() as i64 - 1).is_none() {
+                    return Err(VMError::InvalidOperand);
+                }
+                Operand::Table(low, default, targets)
+            }
+            OperandKind::Lookup => {
+                let default = usize::from_le_bytes(reader.read_array()?);
+                let mut cases: Vec<(i64, usize)> = Vec::new();
+                for _ in 0..reader.read_u32()? {
+                    let key = i64::from_le_bytes(reader.read_array()?);
+                    let target = usize::from_le_bytes(reader.read_array()?);
+                    // the VM binary searches the keys, so they must be strictly increasing
+                    if cases.last().is_some_and(|(last, _)| *last >= key) {
+                        return Err(VMError::InvalidOperand);
+                    }
+                    cases.push((key, target));
+                }
+                Operand::Lookup(default, cases)
+            }
+        };
+
+        Ok(Instruction { opcode, operand })
+    }
+}
+
+// mnemonic and operand the way the disassembler prints them, without the offset
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.operand {
+            Operand::None => write!(f, "{}", self.opcode.name()),
+            Operand::Int(n) => write!(f, "{} {}", self.opcode.name(), n),
+            Operand::Byte(b) => write!(f, "{} {}", self.opcode.name(), b),
+            Operand::Name(name) => write!(f, "{} \"{}\"", self.opcode.name(), name),
+            Operand::Address(addr) => write!(f, "{} {}", self.opcode.name(), addr),
+            Operand::Closure(addr, names) => {
+                write!(f, "{} {}", self.opcode.name(), addr)?;
+                for name in names {
+                    write!(f, " \"{}\"", name)?;
+                }
+                Ok(())
+            }
+            Operand::Table(low, default, targets) => {
+                write!(f, "{} {} {}", self.opcode.name(), low, default)?;
+                for target in targets {
+                    write!(f, " {}", target)?;
+                }
+                Ok(())
+            }
+            Operand::Lookup(default, cases) => {
+                write!(f, "{} {}", self.opcode.name(), default)?;
+                for (key, target) in cases {
+                    write!(f, " {}:{}", key, target)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// decode a whole program into (offset, instruction) pairs, failing on the first bad instruction
+pub fn decode_all(bytecode: &[u8]) -> Result<Vec<(usize, Instruction)>, VMError> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytecode.len() {
+        let instruction = Instruction::decode(bytecode, offset)?;
+        let len = instruction.encoded_len();
+        instructions.push((offset, instruction));
+        offset += len;
+    }
+
+    Ok(instructions)
+}
+
+pub fn encode_all(instructions: &[Instruction]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for instruction in instructions {
+        instruction.encode(&mut out);
+    }
+    out
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    out.push(name.len() as u8);
+    out.extend_from_slice(name.as_bytes());
+}
+
+struct Reader<'a> {
+    bytecode: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_byte(&mut self) -> Result<u8, VMError> {
+        let byte = *self.bytecode.get(self.offset).ok_or(VMError::OutOfBounds)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], VMError> {
+        let bytes = self.bytecode
+            .get(self.offset..self.offset + len)
+            .ok_or(VMError::OutOfBounds)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn read_name(&mut self) -> Result<String, VMError> {
+        let len = self.read_byte()? as usize;
+        let bytes = self.read_slice(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| VMError::InvalidString)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, VMError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.read_slice(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_array(&mut self) -> Result<[u8; 8], VMError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.read_slice(8)?);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let program = vec![
+            Instruction::new(OpCode::Push, Operand::Int(-42)),
+            Instruction::new(OpCode::StoreVar, Operand::Name("x".to_string())),
+            Instruction::new(OpCode::Jump, Operand::Address(0)),
+            Instruction::simple(OpCode::Ushr),
+            Instruction::new(OpCode::Pick, Operand::Byte(2)),
+            Instruction::new(OpCode::MakeClosure, Operand::Closure(3, vec!["a".to_string(), "bc".to_string()])),
+            Instruction::new(OpCode::TableSwitch, Operand::Table(-1, 0, vec![9, 12, 9])),
+            Instruction::new(OpCode::LookupSwitch, Operand::Lookup(12, vec![(-5, 0), (100, 9)])),
+            Instruction::simple(OpCode::Halt),
+        ];
+
+        let bytecode = encode_all(&program);
+        let decoded = decode_all(&bytecode).unwrap();
+
+        assert_eq!(decoded.len(), program.len());
+        assert_eq!(decoded[1].0, 9);
+        assert_eq!(decoded[2].0, 12);
+        for ((_, got), expected) in decoded.iter().zip(&program) {
+            assert_eq!(got, expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Instruction::decode(&[255], 0), Err(VMError::InvalidOpCode(255)));
+        // PUSH with only half an operand
+        assert_eq!(Instruction::decode(&[4, 1, 2, 3], 0), Err(VMError::OutOfBounds));
+
+        // a table claiming more targets than there are bytes
+        let mut table = vec![OpCode::TableSwitch.convert_to_u8()];
+        table.extend_from_slice(&[0; 16]);
+        table.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Instruction::decode(&table, 0), Err(VMError::OutOfBounds));
+
+        // lookup keys out of order
+        let unsorted = Instruction::new(OpCode::LookupSwitch, Operand::Lookup(0, vec![(2, 0), (1, 0)]));
+        let mut bytecode = Vec::new();
+        unsorted.encode(&mut bytecode);
+        assert_eq!(Instruction::decode(&bytecode, 0), Err(VMError::InvalidOperand));
+    }
+}
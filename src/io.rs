@@ -0,0 +1,97 @@
+// Host side I/O plumbing. The VM writes program output to any `Write` and reads program input
+// from any `BufRead`, stdout/stdin unless the host swaps them out. These buffers make it easy to
+// feed a program and capture what it printed
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::rc::Rc;
+
+// in-memory output sink. Clones share the same buffer, so keep one and hand the other to the VM
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        OutputBuffer::default()
+    }
+
+    // everything written so far, lossily decoded as utf-8
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.data.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.data.borrow_mut().clear();
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// in-memory input source, reads through the given bytes once
+#[derive(Debug, Clone, Default)]
+pub struct InputBuffer {
+    data: Cursor<Vec<u8>>,
+}
+
+impl InputBuffer {
+    pub fn new(data: impl Into<Vec<u8>>) -> Self {
+        InputBuffer { data: Cursor::new(data.into()) }
+    }
+}
+
+impl Read for InputBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl BufRead for InputBuffer {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.data.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.data.consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_buffer_is_shared_between_clones() {
+        let buffer = OutputBuffer::new();
+        let mut writer = buffer.clone();
+
+        write!(writer, "hello {}", 42).unwrap();
+        assert_eq!(buffer.contents(), "hello 42");
+
+        buffer.clear();
+        assert_eq!(writer.contents(), "");
+    }
+
+    #[test]
+    fn test_input_buffer_reads_lines() {
+        let mut input = InputBuffer::new("first\nsecond");
+        let mut line = String::new();
+
+        input.read_line(&mut line).unwrap();
+        assert_eq!(line, "first\n");
+        line.clear();
+        input.read_line(&mut line).unwrap();
+        assert_eq!(line, "second");
+        assert!(input.fill_buf().unwrap().is_empty());
+    }
+}
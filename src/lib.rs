@@ -0,0 +1,24 @@
+pub mod opcode;
+pub mod value;
+pub mod error;
+pub mod vm;
+pub mod memory;
+pub mod examples;
+pub mod disassembler;
+pub mod callframe;
+pub mod instruction;
+pub mod assembler;
+pub mod verifier;
+pub mod io;
+pub mod native;
+pub mod snapshot;
+pub mod module;
+pub mod linker;
+pub mod optimizer;
+pub mod cfg;
+pub mod heap;
+pub mod coroutine;
+pub mod record;
+pub mod variant;
+pub mod format;
+pub mod stdlib;
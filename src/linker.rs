@@ -0,0 +1,414 @@
+// Combines several modules into one runnable image. Modules are laid out back to back in the
+// order given, so the first one's code starts at address 0 and is where execution begins. Every
+// address operand is moved by the module's new position, import sites are pointed at the
+// exporting module's function, and each module's private globals get a `module.name` prefix so
+// two modules can use the same global name without sharing it. Record types and enums are shared
+// by name, modules that declare the same one have to agree on its fields or variants.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::VMError;
+use crate::instruction::{decode_all, Instruction, Operand};
+use crate::module::Module;
+use crate::opcode::OpCode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    // two modules export the same name
+    DuplicateSymbol { name: String, first: String, second: String },
+    // two modules declare a record type of the same name with different fields
+    ConflictingRecord { name: String, first: String, second: String },
+    // two modules declare an enum of the same name with different variants
+    ConflictingEnum { name: String, first: String, second: String },
+    // (module, symbol) for every import nothing exports
+    UnresolvedSymbols(Vec<(String, String)>),
+    // a module's code doesn't decode
+    Decode { module: String, error: VMError },
+    // an address operand that isn't the start of an instruction in its own module
+    BadAddress { module: String, offset: usize, target: usize },
+    // an import site that isn't an instruction with an address operand
+    BadImportSite { module: String, name: String, site: usize },
+    // a private global whose prefixed name no longer fits in a name operand
+    NameTooLong { module: String, name: String },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol { name, first, second } => {
+                write!(f, "symbol '{}' is exported by both '{}' and '{}'", name, first, second)
+            }
+            LinkError::ConflictingRecord { name, first, second } => {
+                write!(f, "record '{}' is declared differently by '{}' and '{}'", name, first, second)
+            }
+            LinkError::ConflictingEnum { name, first, second } => {
+                write!(f, "enum '{}' is declared differently by '{}' and '{}'", name, first, second)
+            }
+            LinkError::UnresolvedSymbols(symbols) => {
+                let list: Vec<String> = symbols
+                    .iter()
+                    .map(|(module, name)| format!("'{}' (imported by '{}')", name, module))
+                    .collect();
+                write!(f, "unresolved symbols: {}", list.join(", "))
+            }
+            LinkError::Decode { module, error } => {
+                write!(f, "module '{}': {}", module, error)
+            }
+            LinkError::BadAddress { module, offset, target } => {
+                write!(f, "module '{}' {:04}: target {} is not an instruction boundary", module, offset, target)
+            }
+            LinkError::BadImportSite { module, name, site } => {
+                write!(f, "module '{}': import '{}' site {} has no address operand", module, name, site)
+            }
+            LinkError::NameTooLong { module, name } => {
+                write!(f, "module '{}': global '{}' is too long once prefixed", module, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+// a module after decoding, with the addresses its instructions will have in the linked image
+struct Placed {
+    name: String,
+    instructions: Vec<(usize, Instruction)>,
+    // original offset -> offset in the linked image
+    relocations: HashMap<usize, usize>,
+}
+
+pub fn link(modules: &[Module]) -> Result<Module, LinkError> {
+    let names: Vec<String> = modules
+        .iter()
+        .enumerate()
+        .map(|(index, module)| {
+            if module.name.is_empty() { format!("module{}", index) } else { module.name.clone() }
+        })
+        .collect();
+
+    // symbol table: export name -> (module index, original address)
+    let mut symbols: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (index, module) in modules.iter().enumerate() {
+        for export in &module.exports {
+            if let Some(&(first, _)) = symbols.get(export.name.as_str()) {
+                return Err(LinkError::DuplicateSymbol {
+                    name: export.name.clone(),
+                    first: names[first].clone(),
+                    second: names[index].clone(),
+                });
+            }
+            symbols.insert(&export.name, (index, export.address));
+        }
+    }
+
+    // record name -> index of the first module declaring it
+    let mut records: HashMap<&str, usize> = HashMap::new();
+    for (index, module) in modules.iter().enumerate() {
+        for record in &module.records {
+            match records.get(record.name.as_str()) {
+                Some(&first) if modules[first].record(&record.name) != Some(record) => {
+                    return Err(LinkError::ConflictingRecord {
+                        name: record.name.clone(),
+                        first: names[first].clone(),
+                        second: names[index].clone(),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    records.insert(&record.name, index);
+                }
+            }
+        }
+    }
+
+    // the same for enums
+    let mut enums: HashMap<&str, usize> = HashMap::new();
+    for (index, module) in modules.iter().enumerate() {
+        for declared in &module.enums {
+            match enums.get(declared.name.as_str()) {
+                Some(&first) if modules[first].enum_type(&declared.name) != Some(declared) => {
+                    return Err(LinkError::ConflictingEnum {
+                        name: declared.name.clone(),
+                        first: names[first].clone(),
+                        second: names[index].clone(),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    enums.insert(&declared.name, index);
+                }
+            }
+        }
+    }
+
+    let unresolved: Vec<(String, String)> = modules
+        .iter()
+        .enumerate()
+        .flat_map(|(index, module)| {
+            module.imports.iter().map(move |import| (index, &import.name))
+        })
+        .filter(|(_, name)| !symbols.contains_key(name.as_str()))
+        .map(|(index, name)| (names[index].clone(), name.clone()))
+        .collect();
+    if !unresolved.is_empty() {
+        return Err(LinkError::UnresolvedSymbols(unresolved));
+    }
+
+    // lay the modules out, renaming private globals first since that changes instruction sizes
+    let mut placed = Vec::with_capacity(modules.len());
+    let mut base = 0;
+    for (module, name) in modules.iter().zip(&names) {
+        let mut instructions = decode_all(&module.code)
+            .map_err(|error| LinkError::Decode { module: name.clone(), error })?;
+
+        let mut relocations = HashMap::new();
+        for (offset, instruction) in instructions.iter_mut() {
+            if let (OpCode::StoreVar | OpCode::LoadVar, Operand::Name(global)) =
+                (instruction.opcode, &mut instruction.operand)
+            {
+                if module.private_globals.contains(global) {
+                    *global = format!("{}.{}", name, global);
+                    if global.len() > u8::MAX as usize {
+                        return Err(LinkError::NameTooLong { module: name.clone(), name: global.clone() });
+                    }
+                }
+            }
+            relocations.insert(*offset, base);
+            base += instruction.encoded_len();
+        }
+
+        placed.push(Placed { name: name.clone(), instructions, relocations });
+    }
+
+    // where each exported symbol ends up
+    let mut resolved: HashMap<&str, usize> = HashMap::new();
+    for (symbol, &(index, address)) in &symbols {
+        let target = placed[index].relocations.get(&address).ok_or_else(|| LinkError::BadAddress {
+            module: names[index].clone(),
+            offset: address,
+            target: address,
+        })?;
+        resolved.insert(symbol, *target);
+    }
+
+    let mut code = Vec::with_capacity(base);
+    for (module, unit) in modules.iter().zip(placed.iter_mut()) {
+        let mut import_sites: HashMap<usize, &str> = HashMap::new();
+        for import in &module.imports {
+            for &site in &import.sites {
+                import_sites.insert(site, &import.name);
+            }
+        }
+
+        for (offset, instruction) in unit.instructions.iter_mut() {
+            if let Some(name) = import_sites.remove(offset) {
+                match instruction.targets_mut().pop() {
+                    Some(addr) => *addr = resolved[name],
+                    None => {
+                        return Err(LinkError::BadImportSite {
+                            module: unit.name.clone(),
+                            name: name.to_string(),
+                            site: *offset,
+                        })
+                    }
+                }
+                instruction.encode(&mut code);
+                continue;
+            }
+
+            for target in instruction.targets_mut() {
+                *target = *unit.relocations.get(target).ok_or_else(|| LinkError::BadAddress {
+                    module: unit.name.clone(),
+                    offset: *offset,
+                    target: *target,
+                })?;
+            }
+            instruction.encode(&mut code);
+        }
+
+        // sites left over didn't match any instruction start
+        if let Some((&site, &name)) = import_sites.iter().next() {
+            return Err(LinkError::BadImportSite { module: unit.name.clone(), name: name.to_string(), site });
+        }
+    }
+
+    let mut linked = Module::new(code);
+    linked.name = modules.first().map(|module| module.name.clone()).unwrap_or_default();
+    for (module, unit) in modules.iter().zip(&placed) {
+        for export in &module.exports {
+            linked.add_export(&export.name, unit.relocations[&export.address], export.arity);
+        }
+        for record in &module.records {
+            if linked.record(&record.name).is_none() {
+                linked.records.push(record.clone());
+            }
+        }
+        for declared in &module.enums {
+            if linked.enum_type(&declared.name).is_none() {
+                linked.enums.push(declared.clone());
+            }
+        }
+    }
+
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_module;
+    use crate::io::OutputBuffer;
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    const MAIN: &str = r#"
+        .module main
+        .import square
+        .import bump
+        .import reset
+            CALL reset
+            PUSH 7
+            CALL square
+            PRINT_VAL
+            PUSH 1
+            STORE_VAR "count"
+            CALL bump
+            CALL bump
+            LOAD_VAR "count"
+            HALT
+    "#;
+
+    const LIB: &str = r#"
+        .module lib
+        .export square 1
+        .export bump 0
+        .export reset 0
+        .private count
+        reset:
+            PUSH 0
+            STORE_VAR "count"
+            RETURN
+        square:
+            DUP
+            MUL
+            RETURN
+        bump:
+            LOAD_VAR "count"
+            PUSH 10
+            ADD
+            STORE_VAR "count"
+            RETURN
+    "#;
+
+    fn run(image: &Module) -> (VM, String) {
+        let output = OutputBuffer::new();
+        let mut vm = VM::with_output(output.clone());
+        vm.load_module(image.clone());
+        vm.run_solution().unwrap();
+        (vm, output.contents())
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let main = assemble_module(MAIN).unwrap();
+        let lib = assemble_module(LIB).unwrap();
+        let square = lib.export("square").unwrap().address;
+        let image = link(&[main.clone(), lib]).unwrap();
+
+        // "count" in reset grew by the four bytes of its "lib." prefix
+        assert_eq!(image.export("square").map(|e| e.address), Some(main.code.len() + square + 4));
+
+        let (vm, output) = run(&image);
+        assert_eq!(output, "49");
+        // main's "count" and lib's private one are different globals
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(1)));
+        assert_eq!(vm.get_variable("lib.count"), Ok(Value::Integer(20)));
+    }
+
+    #[test]
+    fn test_internal_jumps_are_relocated() {
+        let lib = assemble_module(
+            ".export count_down 1\ncount_down:\nloop:\nPUSH -1\nADD\nDUP\nJUMP_IF_TRUE loop\nRETURN",
+        )
+        .unwrap();
+        let main = assemble_module(".import count_down\nPUSH 3\nCALL count_down\nHALT").unwrap();
+
+        let (vm, _) = run(&link(&[main, lib]).unwrap());
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(0)));
+    }
+
+    #[test]
+    fn test_duplicate_symbol() {
+        let a = assemble_module(".module a\n.export f 0\nf:\nRETURN").unwrap();
+        let b = assemble_module(".module b\n.export f 0\nf:\nRETURN").unwrap();
+
+        assert_eq!(
+            link(&[a, b]),
+            Err(LinkError::DuplicateSymbol { name: "f".to_string(), first: "a".to_string(), second: "b".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_unresolved_symbols() {
+        let main = assemble_module(".import f\n.import g\nCALL f\nCALL g\nHALT").unwrap();
+        let err = link(&[main]).unwrap_err();
+
+        assert_eq!(
+            err,
+            LinkError::UnresolvedSymbols(vec![
+                ("module0".to_string(), "f".to_string()),
+                ("module0".to_string(), "g".to_string()),
+            ])
+        );
+        assert!(err.to_string().contains("'g' (imported by 'module0')"));
+    }
+
+    #[test]
+    fn test_bad_address() {
+        let mut main = assemble_module("JUMP 3\nHALT").unwrap();
+        main.name = "main".to_string();
+
+        assert_eq!(
+            link(&[main]),
+            Err(LinkError::BadAddress { module: "main".to_string(), offset: 0, target: 3 })
+        );
+    }
+
+    #[test]
+    fn test_shared_records() {
+        let main = assemble_module(
+            ".record Point x y\n.import origin\nCALL origin\nGET_FIELD \"y\"\nPRINT_VAL\nHALT",
+        )
+        .unwrap();
+        let lib = assemble_module(
+            ".module lib\n.record Point x y\n.export origin 0\norigin:\nPUSH 0\nPUSH 5\nNEW_RECORD \"Point\"\nRETURN",
+        )
+        .unwrap();
+        let image = link(&[main, lib]).unwrap();
+        assert_eq!(image.records.len(), 1);
+        assert_eq!(run(&image).1, "5");
+
+        let other = assemble_module(".module other\n.record Point y x\nRETURN").unwrap();
+        let main = assemble_module(".module main\n.record Point x y\nHALT").unwrap();
+        assert_eq!(
+            link(&[main, other]),
+            Err(LinkError::ConflictingRecord {
+                name: "Point".to_string(),
+                first: "main".to_string(),
+                second: "other".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_conflicting_enums() {
+        let a = assemble_module(".module a\n.enum Option None Some:1\nHALT").unwrap();
+        let b = assemble_module(".module b\n.enum Option None Some:1\nRETURN").unwrap();
+        assert_eq!(link(&[a.clone(), b]).unwrap().enums, a.enums);
+
+        let c = assemble_module(".module c\n.enum Option None Some:2\nRETURN").unwrap();
+        let err = link(&[a, c]).unwrap_err();
+        assert_eq!(err.to_string(), "enum 'Option' is declared differently by 'a' and 'c'");
+    }
+}
@@ -1,16 +1,7 @@
-mod opcode;
-mod value;
-mod error;
-mod vm;
-mod memory;
-mod examples;
-mod disassembler;
-mod callframe;
+use bytecode_vm::vm::VM;
+use bytecode_vm::opcode::OpCode;
 
-use vm::VM;
-use opcode::OpCode;
-
-use examples::*;
+use bytecode_vm::examples::*;
 
 fn main() {
     
@@ -32,13 +23,14 @@ struct BytecodeBuilder {
     code: Vec<u8>,
 }
 
+#[allow(dead_code)]
 impl BytecodeBuilder {
     fn new() -> Self {
         BytecodeBuilder { code: Vec::new()}
     }
     fn push_solution(mut self, value: i64) -> Self {
         self.code.push(OpCode::Push.convert_to_u8());
-        self.code.push(value as u8);
+        self.code.extend(value.to_le_bytes());
         self
     }
     
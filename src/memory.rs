@@ -23,10 +23,17 @@ impl Memory {
     pub fn load_solution(&self, name: &str) -> Result<Value, VMError> {
         self.variables
             .get(name)
-            .copied()       //copy the value (works because Value implements Copy)
+            .cloned()       //strings share their allocation, so this stays cheap
             .ok_or_else(|| VMError::UndefinedVariable(name.to_string()))
     }
 
+    // globals sorted by name, so the order is stable across runs
+    pub fn variables(&self) -> Vec<(&str, &Value)> {
+        let mut variables: Vec<_> = self.variables.iter().map(|(name, value)| (name.as_str(), value)).collect();
+        variables.sort_by(|a, b| a.0.cmp(b.0));
+        variables
+    }
+
     pub fn clear(&mut self) {
         self.variables.clear();
     }
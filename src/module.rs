@@ -0,0 +1,78 @@
+// A compiled unit: bytecode plus a table of named functions the host (or another module) can
+// call. Export addresses are absolute offsets into `code`. Modules can also reference functions
+// they don't define through imports, which the linker resolves against other modules' exports.
+// The record types and enums its code builds with NEW_RECORD and NEW_VARIANT are declared here too
+
+use crate::record::RecordType;
+use crate::variant::EnumType;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub address: usize,
+    pub arity: usize,
+}
+
+// a symbol this module needs from elsewhere, `sites` are the offsets of the instructions whose
+// address operand should point at it once linked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub name: String,
+    pub sites: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+    // globals only this module uses, the linker gives them a per-module name so they can't clash
+    pub private_globals: Vec<String>,
+    pub records: Vec<RecordType>,
+    pub enums: Vec<EnumType>,
+}
+
+impl Module {
+    pub fn new(code: Vec<u8>) -> Self {
+        Module { code, ..Module::default() }
+    }
+
+    pub fn add_import_site(&mut self, name: &str, site: usize) {
+        match self.imports.iter_mut().find(|import| import.name == name) {
+            Some(import) => import.sites.push(site),
+            None => self.imports.push(Import { name: name.to_string(), sites: vec![site] }),
+        }
+    }
+
+    pub fn add_export(&mut self, name: &str, address: usize, arity: usize) {
+        self.exports.push(Export { name: name.to_string(), address, arity });
+    }
+
+    pub fn export(&self, name: &str) -> Option<&Export> {
+        self.exports.iter().find(|export| export.name == name)
+    }
+
+    pub fn record(&self, name: &str) -> Option<&RecordType> {
+        self.records.iter().find(|record| record.name == name)
+    }
+
+    pub fn enum_type(&self, name: &str) -> Option<&EnumType> {
+        self.enums.iter().find(|declared| declared.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_lookup() {
+        let mut module = Module::new(vec![5, 17]);
+        module.add_export("main", 0, 0);
+        module.add_export("noop", 1, 0);
+
+        assert_eq!(module.export("noop").map(|e| e.address), Some(1));
+        assert!(module.export("missing").is_none());
+    }
+}
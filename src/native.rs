@@ -0,0 +1,66 @@
+// Host functions callable from bytecode through CALL_NATIVE. The host registers a Rust closure
+// under a name with a fixed arity; at runtime the arguments are popped off the stack (first
+// argument deepest) and the closure's result is pushed back
+
+use std::rc::Rc;
+
+use crate::error::VMError;
+use crate::value::Value;
+use crate::vm::VM;
+
+pub type NativeFn = Rc<dyn Fn(&mut NativeContext, &[Value]) -> Result<Value, VMError>>;
+
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+// the slice of the VM a native function is allowed to touch
+pub struct NativeContext<'a> {
+    vm: &'a mut VM,
+}
+
+impl<'a> NativeContext<'a> {
+    pub(crate) fn new(vm: &'a mut VM) -> Self {
+        NativeContext { vm }
+    }
+
+    pub fn get_global(&self, name: &str) -> Result<Value, VMError> {
+        self.vm.get_variable(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.vm.set_variable(name, value);
+    }
+
+    // run a bytecode function and get its result, see VM::call_function
+    pub fn call_function(&mut self, address: usize, args: &[Value]) -> Result<Value, VMError> {
+        self.vm.call_function(address, args)
+    }
+
+    // call a function value, such as a callback passed in as an argument, see VM::call_value
+    pub fn call_value(&mut self, function: &Value, args: &[Value]) -> Result<Value, VMError> {
+        self.vm.call_value(function, args)
+    }
+
+    // keep a value alive across collections while the native holds it, see VM::pin
+    pub fn pin(&mut self, value: &Value) {
+        self.vm.pin(value);
+    }
+
+    pub fn unpin(&mut self, value: &Value) {
+        self.vm.unpin(value);
+    }
+
+    // how PRINT_VAL would show a value, records and closures included
+    pub fn display_value(&self, value: &Value) -> String {
+        self.vm.display_value(value)
+    }
+
+    // write through the VM's output sink, so natives show up in captured output
+    pub fn write_output(&mut self, text: &str) -> Result<(), VMError> {
+        self.vm.write_output(text)
+    }
+}
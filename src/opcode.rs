@@ -6,6 +6,51 @@ pub enum OpCode {
     Mul,
     Div,
     Push,
+    Mod,
+    Neg,
+
+    //bitwise operations
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,    //arithmetic shift, keeps the sign
+    Ushr,   //logical shift, fills with zeroes
+
+    //stack manipulation
+    Pop,    // a ->
+    Dup,    // a -> a a
+    Swap,   // a b -> b a
+    Over,   // a b -> a b a
+    Rot,    // a b c -> b c a
+    Pick,   // copies the value n slots below the top, PICK 0 is DUP
+    PushNil,
+
+    //types
+    TypeOf, // a -> the name of a's type, as a string
+    IsType, // a -> whether a has the named type
+
+    //records
+    NewRecord,  //pops a value per field of the named record type, first field deepest
+    GetField,   //record -> the named field's value
+    SetField,   //record value -> , assigns the named field
+    GetFieldAt, //like GetField with the field's position instead of its name
+    SetFieldAt, //like SetField with the field's position instead of its name
+
+    //enums
+    NewVariant, //pops the payload of the variant named "Enum.Variant", first value deepest
+    VariantTag, //variant -> its tag, the variant's position in the enum declaration
+    IsVariant,  //a -> whether a is the variant named "Enum.Variant"
+    PayloadAt,  //variant -> its nth payload value
+    Unpack,     //variant -> all its payload values, first deepest
+
+    //formatting, see format.rs for the format string syntax
+    Format,         //pops a value per placeholder, first deepest, pushes the formatted string
+    PrintFormat,    //like Format but writes the result to the output
+
+    //arrays
+    NewArray,   //pops n values, first deepest, pushes them as an array
 
     //variables
     StoreVar,
@@ -19,13 +64,32 @@ pub enum OpCode {
     Eq,
     Neq,
 
+    //logical operators, operands are judged by truthiness
+    Not,
+    And,
+    Or,
+
     //control flow
     Jump,   //unconditional jump
     JumpIfFalse,    //jump if T.O.S is falsy
+    JumpIfTrue,     //jump if T.O.S is truthy
+    JumpIfFalseKeep,    //like JumpIfFalse but leaves the condition on the stack
+    JumpIfTrueKeep,     //like JumpIfTrue but leaves the condition on the stack
+    TableSwitch,    //pops an integer, jumps to the target for it in a dense range of cases
+    LookupSwitch,   //pops an integer, jumps to the target of the matching key among sparse cases
 
     // function operators
     Call,
     Return,
+    TailCall,   //like CALL followed by RETURN, but the callee takes over the current frame
+    CallNative, //calls a host function registered on the VM by name
+    MakeClosure,    //pushes a function value for an address, capturing the named locals
+    CallValue,      //pops a function value and calls it, arguments stay on the stack
+    //coroutines
+    NewCoroutine,   //pops a function value, pushes a suspended coroutine that will run it
+    Resume,         //coroutine value -> value it yields or returns, the value on top is sent in
+    Yield,          //suspends the running coroutine, handing the top value to its resumer
+    CoroutineStatus,    //pops a coroutine, pushes 0 suspended, 1 running or 2 dead
     StoreLocal,
     LoadLocal,
 
@@ -33,6 +97,10 @@ pub enum OpCode {
     Print,
     PrintVal,
     PrintLn,
+    ReadInt,    //reads the next whitespace separated integer
+    ReadLine,   //reads a line without its line ending, as a string
+    ReadByte,   //reads a single raw byte as an integer
+    Eof,        //pushes whether the input is exhausted
 
     //program termination
     Halt
@@ -64,6 +132,57 @@ impl OpCode {
             20 => Some(OpCode::Print),
             21 => Some(OpCode::PrintVal),
             22 => Some(OpCode::PrintLn),
+            23 => Some(OpCode::Mod),
+            24 => Some(OpCode::Neg),
+            25 => Some(OpCode::BitAnd),
+            26 => Some(OpCode::BitOr),
+            27 => Some(OpCode::BitXor),
+            28 => Some(OpCode::BitNot),
+            29 => Some(OpCode::Shl),
+            30 => Some(OpCode::Shr),
+            31 => Some(OpCode::Ushr),
+            32 => Some(OpCode::Not),
+            33 => Some(OpCode::And),
+            34 => Some(OpCode::Or),
+            35 => Some(OpCode::JumpIfTrue),
+            36 => Some(OpCode::JumpIfFalseKeep),
+            37 => Some(OpCode::JumpIfTrueKeep),
+            38 => Some(OpCode::Pop),
+            39 => Some(OpCode::Dup),
+            40 => Some(OpCode::Swap),
+            41 => Some(OpCode::Over),
+            42 => Some(OpCode::Rot),
+            43 => Some(OpCode::Pick),
+            44 => Some(OpCode::ReadInt),
+            45 => Some(OpCode::ReadLine),
+            46 => Some(OpCode::ReadByte),
+            47 => Some(OpCode::Eof),
+            48 => Some(OpCode::CallNative),
+            49 => Some(OpCode::MakeClosure),
+            50 => Some(OpCode::CallValue),
+            51 => Some(OpCode::NewCoroutine),
+            52 => Some(OpCode::Resume),
+            53 => Some(OpCode::Yield),
+            54 => Some(OpCode::CoroutineStatus),
+            55 => Some(OpCode::TailCall),
+            56 => Some(OpCode::TableSwitch),
+            57 => Some(OpCode::LookupSwitch),
+            58 => Some(OpCode::PushNil),
+            59 => Some(OpCode::TypeOf),
+            60 => Some(OpCode::IsType),
+            61 => Some(OpCode::NewRecord),
+            62 => Some(OpCode::GetField),
+            63 => Some(OpCode::SetField),
+            64 => Some(OpCode::GetFieldAt),
+            65 => Some(OpCode::SetFieldAt),
+            66 => Some(OpCode::NewVariant),
+            67 => Some(OpCode::VariantTag),
+            68 => Some(OpCode::IsVariant),
+            69 => Some(OpCode::PayloadAt),
+            70 => Some(OpCode::Unpack),
+            71 => Some(OpCode::Format),
+            72 => Some(OpCode::PrintFormat),
+            73 => Some(OpCode::NewArray),
             _ => None,
         }
     }
@@ -92,6 +211,57 @@ impl OpCode {
             OpCode::Print => 20,
             OpCode::PrintVal => 21,
             OpCode::PrintLn => 22,
+            OpCode::Mod => 23,
+            OpCode::Neg => 24,
+            OpCode::BitAnd => 25,
+            OpCode::BitOr => 26,
+            OpCode::BitXor => 27,
+            OpCode::BitNot => 28,
+            OpCode::Shl => 29,
+            OpCode::Shr => 30,
+            OpCode::Ushr => 31,
+            OpCode::Not => 32,
+            OpCode::And => 33,
+            OpCode::Or => 34,
+            OpCode::JumpIfTrue => 35,
+            OpCode::JumpIfFalseKeep => 36,
+            OpCode::JumpIfTrueKeep => 37,
+            OpCode::Pop => 38,
+            OpCode::Dup => 39,
+            OpCode::Swap => 40,
+            OpCode::Over => 41,
+            OpCode::Rot => 42,
+            OpCode::Pick => 43,
+            OpCode::ReadInt => 44,
+            OpCode::ReadLine => 45,
+            OpCode::ReadByte => 46,
+            OpCode::Eof => 47,
+            OpCode::CallNative => 48,
+            OpCode::MakeClosure => 49,
+            OpCode::CallValue => 50,
+            OpCode::NewCoroutine => 51,
+            OpCode::Resume => 52,
+            OpCode::Yield => 53,
+            OpCode::CoroutineStatus => 54,
+            OpCode::TailCall => 55,
+            OpCode::TableSwitch => 56,
+            OpCode::LookupSwitch => 57,
+            OpCode::PushNil => 58,
+            OpCode::TypeOf => 59,
+            OpCode::IsType => 60,
+            OpCode::NewRecord => 61,
+            OpCode::GetField => 62,
+            OpCode::SetField => 63,
+            OpCode::GetFieldAt => 64,
+            OpCode::SetFieldAt => 65,
+            OpCode::NewVariant => 66,
+            OpCode::VariantTag => 67,
+            OpCode::IsVariant => 68,
+            OpCode::PayloadAt => 69,
+            OpCode::Unpack => 70,
+            OpCode::Format => 71,
+            OpCode::PrintFormat => 72,
+            OpCode::NewArray => 73,
         }
     }
 
@@ -120,8 +290,109 @@ impl OpCode {
             OpCode::PrintVal => "PRINT_VAL",
             OpCode::PrintLn => "PRINT_LN",
             OpCode::Halt => "HALT",
+            OpCode::Mod => "MOD",
+            OpCode::Neg => "NEG",
+            OpCode::BitAnd => "BIT_AND",
+            OpCode::BitOr => "BIT_OR",
+            OpCode::BitXor => "BIT_XOR",
+            OpCode::BitNot => "BIT_NOT",
+            OpCode::Shl => "SHL",
+            OpCode::Shr => "SHR",
+            OpCode::Ushr => "USHR",
+            OpCode::Not => "NOT",
+            OpCode::And => "AND",
+            OpCode::Or => "OR",
+            OpCode::JumpIfTrue => "JUMP_IF_TRUE",
+            OpCode::JumpIfFalseKeep => "JUMP_IF_FALSE_KEEP",
+            OpCode::JumpIfTrueKeep => "JUMP_IF_TRUE_KEEP",
+            OpCode::Pop => "POP",
+            OpCode::Dup => "DUP",
+            OpCode::Swap => "SWAP",
+            OpCode::Over => "OVER",
+            OpCode::Rot => "ROT",
+            OpCode::Pick => "PICK",
+            OpCode::ReadInt => "READ_INT",
+            OpCode::ReadLine => "READ_LINE",
+            OpCode::ReadByte => "READ_BYTE",
+            OpCode::Eof => "EOF",
+            OpCode::CallNative => "CALL_NATIVE",
+            OpCode::MakeClosure => "MAKE_CLOSURE",
+            OpCode::CallValue => "CALL_VALUE",
+            OpCode::NewCoroutine => "NEW_COROUTINE",
+            OpCode::Resume => "RESUME",
+            OpCode::Yield => "YIELD",
+            OpCode::CoroutineStatus => "COROUTINE_STATUS",
+            OpCode::TailCall => "TAIL_CALL",
+            OpCode::TableSwitch => "TABLE_SWITCH",
+            OpCode::LookupSwitch => "LOOKUP_SWITCH",
+            OpCode::PushNil => "PUSH_NIL",
+            OpCode::TypeOf => "TYPE_OF",
+            OpCode::IsType => "IS_TYPE",
+            OpCode::NewRecord => "NEW_RECORD",
+            OpCode::GetField => "GET_FIELD",
+            OpCode::SetField => "SET_FIELD",
+            OpCode::GetFieldAt => "GET_FIELD_AT",
+            OpCode::SetFieldAt => "SET_FIELD_AT",
+            OpCode::NewVariant => "NEW_VARIANT",
+            OpCode::VariantTag => "VARIANT_TAG",
+            OpCode::IsVariant => "IS_VARIANT",
+            OpCode::PayloadAt => "PAYLOAD_AT",
+            OpCode::Unpack => "UNPACK",
+            OpCode::Format => "FORMAT",
+            OpCode::PrintFormat => "PRINT_FORMAT",
+            OpCode::NewArray => "NEW_ARRAY",
         }
     }
+
+    // reverse of name(), used by the assembler
+    pub fn convert_from_name(name: &str) -> Option<OpCode> {
+        (0..=u8::MAX)
+            .filter_map(OpCode::convert_from_u8)
+            .find(|opcode| opcode.name().eq_ignore_ascii_case(name))
+    }
+
+    // what follows the opcode byte in the bytecode
+    pub fn operand_kind(&self) -> OperandKind {
+        match self {
+            OpCode::Push => OperandKind::Int,
+            OpCode::Pick | OpCode::GetFieldAt | OpCode::SetFieldAt |
+            OpCode::PayloadAt | OpCode::NewArray => OperandKind::Byte,
+            OpCode::StoreVar | OpCode::LoadVar |
+            OpCode::StoreLocal | OpCode::LoadLocal | OpCode::Print |
+            OpCode::CallNative | OpCode::IsType |
+            OpCode::NewRecord | OpCode::GetField | OpCode::SetField |
+            OpCode::NewVariant | OpCode::IsVariant |
+            OpCode::Format | OpCode::PrintFormat => OperandKind::Name,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue |
+            OpCode::JumpIfFalseKeep | OpCode::JumpIfTrueKeep | OpCode::Call |
+            OpCode::TailCall => OperandKind::Address,
+            OpCode::MakeClosure => OperandKind::Closure,
+            OpCode::TableSwitch => OperandKind::Table,
+            OpCode::LookupSwitch => OperandKind::Lookup,
+            _ => OperandKind::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    None,
+    // 8 byte little endian i64
+    Int,
+    // a single unsigned byte
+    Byte,
+    // 1 length byte followed by that many utf-8 bytes
+    Name,
+    // 8 byte little endian absolute bytecode offset
+    Address,
+    // an Address, then a count byte and that many Names for the locals to capture
+    Closure,
+    // the first case value as an Int, the default Address, a 4 byte count and that many
+    // Addresses, one per case value counting up from the first
+    Table,
+    // the default Address, a 4 byte count and that many (Int key, Address) pairs, keys in
+    // strictly increasing order
+    Lookup,
 }
 
 
@@ -133,9 +404,21 @@ mod tests {
     fn test_opcode_conversion() {
         assert_eq!(OpCode::convert_from_u8(0), Some(OpCode::Add));
         assert_eq!(OpCode::convert_from_u8(5), Some(OpCode::Halt));
-        assert_eq!(OpCode::convert_from_u8(50), None);
+        assert_eq!(OpCode::convert_from_u8(200), None);
 
         assert_eq!(OpCode::Add.convert_to_u8(), 0);
         assert_eq!(OpCode::Push.convert_to_u8(), 4);
     }
+
+    #[test]
+    fn test_opcode_round_trip() {
+        for byte in 0..=u8::MAX {
+            if let Some(opcode) = OpCode::convert_from_u8(byte) {
+                assert_eq!(opcode.convert_to_u8(), byte);
+                assert_eq!(OpCode::convert_from_name(opcode.name()), Some(opcode));
+            }
+        }
+        assert_eq!(OpCode::convert_from_name("ushr"), Some(OpCode::Ushr));
+        assert_eq!(OpCode::convert_from_name("NOPE"), None);
+    }
 }
\ No newline at end of file
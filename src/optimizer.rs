@@ -0,0 +1,605 @@
+// Bytecode optimizer. Works on the decoded instructions with every address operand replaced by
+// the id of the instruction it points at, so passes can add and delete instructions freely and
+// real offsets are only worked out again when the program is encoded at the end.
+//
+// Entry points the optimizer can see are offset 0, CALL targets and a module's exports. Code
+// that is only reached through `VM::call_function` with a hand computed address should be
+// exported, or dead code removal may delete it.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::error::VMError;
+use crate::instruction::{decode_all, Instruction, Operand};
+use crate::module::Module;
+use crate::opcode::OpCode;
+use crate::value::{OverflowMode, Value};
+
+// which passes to run, all of them by default
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Passes {
+    // PUSH 2 PUSH 3 ADD -> PUSH 5, and conditional jumps on constant conditions
+    pub constant_folding: bool,
+    // STORE_VAR x LOAD_VAR x -> DUP STORE_VAR x
+    pub store_load: bool,
+    // jumps to unconditional jumps go straight to the final target
+    pub jump_threading: bool,
+    // drop instructions no path can reach, and jumps to the next instruction
+    pub dead_code: bool,
+    // CALL f RETURN -> TAIL_CALL f, so recursion in tail position runs in constant stack space
+    pub tail_calls: bool,
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Passes { constant_folding: true, store_load: true, jump_threading: true, dead_code: true, tail_calls: true }
+    }
+}
+
+impl Passes {
+    pub fn none() -> Self {
+        Passes {
+            constant_folding: false,
+            store_load: false,
+            jump_threading: false,
+            dead_code: false,
+            tail_calls: false,
+        }
+    }
+}
+
+// upper bound on rounds, each round has to change something to carry on
+const MAX_ROUNDS: usize = 16;
+
+#[derive(Debug, Clone)]
+struct Item {
+    id: usize,
+    // address operands hold instruction ids, not offsets
+    instruction: Instruction,
+}
+
+struct Program {
+    items: Vec<Item>,
+    // ids that must survive and stay addressable from outside, like exports
+    roots: Vec<usize>,
+}
+
+pub fn optimize(bytecode: &[u8], passes: &Passes) -> Result<Vec<u8>, VMError> {
+    optimize_module(&Module::new(bytecode.to_vec()), passes).map(|module| module.code)
+}
+
+// like optimize(), also keeping exports and import sites pointing at the right instructions
+pub fn optimize_module(module: &Module, passes: &Passes) -> Result<Module, VMError> {
+    let decoded = decode_all(&module.code)?;
+    let id_of: HashMap<usize, usize> = decoded
+        .iter()
+        .enumerate()
+        .map(|(id, (offset, _))| (*offset, id))
+        .collect();
+    let to_id = |offset: usize| id_of.get(&offset).copied().ok_or(VMError::InvalidOperand);
+
+    let mut items = Vec::with_capacity(decoded.len());
+    for (id, (_, mut instruction)) in decoded.into_iter().enumerate() {
+        for target in instruction.targets_mut() {
+            *target = to_id(*target)?;
+        }
+        items.push(Item { id, instruction });
+    }
+
+    // in the same order as the exports, so each export can find its instruction again
+    let mut roots = vec![];
+    for export in &module.exports {
+        roots.push(to_id(export.address)?);
+    }
+    let mut program = Program { items, roots };
+
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+        if passes.constant_folding {
+            changed |= program.fold_constants();
+        }
+        if passes.store_load {
+            changed |= program.store_load();
+        }
+        if passes.jump_threading {
+            changed |= program.thread_jumps();
+        }
+        if passes.tail_calls {
+            changed |= program.tail_calls();
+        }
+        if passes.dead_code {
+            changed |= program.remove_dead_code();
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // lay the program out again and turn ids back into offsets
+    let mut offsets = HashMap::new();
+    let mut offset = 0;
+    for item in &program.items {
+        offsets.insert(item.id, offset);
+        offset += item.instruction.encoded_len();
+    }
+
+    let mut code = Vec::with_capacity(offset);
+    for item in &mut program.items {
+        for target in item.instruction.targets_mut() {
+            *target = offsets[target];
+        }
+        item.instruction.encode(&mut code);
+    }
+
+    let mut optimized = Module::new(code);
+    optimized.name = module.name.clone();
+    optimized.private_globals = module.private_globals.clone();
+    optimized.records = module.records.clone();
+    optimized.enums = module.enums.clone();
+    // the exported instruction itself may be gone, the root has moved on to what replaced it
+    for (export, root) in module.exports.iter().zip(&program.roots) {
+        optimized.add_export(&export.name, offsets[root], export.arity);
+    }
+    for import in &module.imports {
+        for site in &import.sites {
+            // a site whose call was removed as unreachable no longer needs resolving
+            if let Some(offset) = id_of.get(site).and_then(|id| offsets.get(id)) {
+                optimized.add_import_site(&import.name, *offset);
+            }
+        }
+    }
+
+    Ok(optimized)
+}
+
+impl Program {
+    fn jump_targets(&self) -> HashSet<usize> {
+        let mut targets: HashSet<usize> = self.roots.iter().copied().collect();
+        for item in &self.items {
+            targets.extend(item.instruction.targets());
+        }
+        targets
+    }
+
+    // drops items[index], any jump to it moves on to the instruction that follows
+    fn remove(&mut self, index: usize) {
+        let removed = self.items.remove(index).id;
+        let Some(next) = self.items.get(index).map(|item| item.id) else { return };
+        for item in &mut self.items {
+            for target in item.instruction.targets_mut() {
+                if *target == removed {
+                    *target = next;
+                }
+            }
+        }
+        for root in &mut self.roots {
+            if *root == removed {
+                *root = next;
+            }
+        }
+    }
+
+    // remove() that also keeps a set from jump_targets() up to date
+    fn remove_target(&mut self, index: usize, targets: &mut HashSet<usize>) {
+        let removed = self.items[index].id;
+        let next = self.items.get(index + 1).map(|item| item.id);
+        self.remove(index);
+        if targets.remove(&removed) {
+            targets.extend(next);
+        }
+    }
+
+    fn fold_constants(&mut self) -> bool {
+        let mut changed = false;
+        let mut index = 0;
+        // computed once per round, a target whose jump was folded away only leaves a pattern unfolded
+        let mut targets = self.jump_targets();
+
+        while index < self.items.len() {
+            // instructions after the first of a pattern must not be reachable on their own
+            let window: Vec<&Instruction> = self.items[index..]
+                .iter()
+                .enumerate()
+                .take_while(|(n, item)| *n == 0 || !targets.contains(&item.id))
+                .take(4)
+                .map(|(_, item)| &item.instruction)
+                .collect();
+
+            match fold(&window) {
+                // dropping the last instructions would leave jumps to them nowhere to go
+                Some((len, None)) if index + len >= self.items.len() => index += 1,
+                Some((len, replacement)) => {
+                    for _ in 1..len {
+                        self.remove_target(index + 1, &mut targets);
+                    }
+                    match replacement {
+                        Some(instruction) => self.items[index].instruction = instruction,
+                        None => self.remove_target(index, &mut targets),
+                    }
+                    changed = true;
+                    // the result may now fold with the instructions before it
+                    index = index.saturating_sub(2);
+                }
+                None => index += 1,
+            }
+        }
+
+        changed
+    }
+
+    fn store_load(&mut self) -> bool {
+        let targets = self.jump_targets();
+        let mut changed = false;
+
+        for index in 1..self.items.len() {
+            let (before, after) = self.items.split_at_mut(index);
+            let (store, load) = (&mut before[index - 1], &mut after[0]);
+            if targets.contains(&load.id) {
+                continue;
+            }
+            let same_global = store.instruction.opcode == OpCode::StoreVar
+                && load.instruction.opcode == OpCode::LoadVar
+                && store.instruction.operand == load.instruction.operand;
+            if same_global {
+                // keep the store in place, jumps into it must still store
+                load.instruction = store.instruction.clone();
+                store.instruction = Instruction::simple(OpCode::Dup);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    fn thread_jumps(&mut self) -> bool {
+        let jump_to: HashMap<usize, usize> = self.items
+            .iter()
+            .filter(|item| item.instruction.opcode == OpCode::Jump)
+            .filter_map(|item| item.instruction.targets().first().map(|target| (item.id, *target)))
+            .collect();
+        let mut changed = false;
+
+        for item in &mut self.items {
+            if !matches!(
+                item.instruction.opcode,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue
+                    | OpCode::JumpIfFalseKeep | OpCode::JumpIfTrueKeep
+                    | OpCode::TableSwitch | OpCode::LookupSwitch
+            ) {
+                continue;
+            }
+            for target in item.instruction.targets_mut() {
+                // follow the chain, giving up on jumps that loop back on themselves
+                let mut seen = HashSet::new();
+                let mut next = *target;
+                while let Some(&further) = jump_to.get(&next) {
+                    if !seen.insert(next) {
+                        break;
+                    }
+                    next = further;
+                }
+                if next != *target && !seen.contains(&next) {
+                    *target = next;
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn tail_calls(&mut self) -> bool {
+        let mut changed = false;
+
+        for index in 1..self.items.len() {
+            let (before, after) = self.items.split_at_mut(index);
+            let (call, ret) = (&mut before[index - 1].instruction, &after[0].instruction);
+            // the RETURN stays, jumps to it still need it, and otherwise dead code removal drops it
+            if call.opcode == OpCode::Call && ret.opcode == OpCode::Return {
+                call.opcode = OpCode::TailCall;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    fn remove_dead_code(&mut self) -> bool {
+        let index_of: HashMap<usize, usize> = self.items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (item.id, index))
+            .collect();
+
+        let mut reachable = vec![false; self.items.len()];
+        let mut worklist: Vec<usize> = self.roots.iter().map(|root| index_of[root]).collect();
+        if !self.items.is_empty() {
+            worklist.push(0);
+        }
+        while let Some(index) = worklist.pop() {
+            if index >= self.items.len() || reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            let instruction = &self.items[index].instruction;
+            worklist.extend(instruction.targets().iter().map(|target| index_of[target]));
+            let falls_through = !matches!(
+                instruction.opcode,
+                OpCode::Jump | OpCode::TableSwitch | OpCode::LookupSwitch
+                    | OpCode::TailCall | OpCode::Return | OpCode::Halt
+            );
+            if falls_through {
+                worklist.push(index + 1);
+            }
+        }
+
+        let before = self.items.len();
+        let mut index = 0;
+        self.items.retain(|_| {
+            index += 1;
+            reachable[index - 1]
+        });
+
+        // a jump to the very next instruction does nothing
+        let mut index = 0;
+        while index + 1 < self.items.len() {
+            let item = &self.items[index];
+            if item.instruction.opcode == OpCode::Jump && item.instruction.targets() == [self.items[index + 1].id] {
+                self.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+
+        self.items.len() != before
+    }
+}
+
+// matches a foldable pattern at the start of `window`, returning how many instructions it
+// covers and what replaces them (None to drop them altogether)
+fn fold(window: &[&Instruction]) -> Option<(usize, Option<Instruction>)> {
+    let constant = |n: usize| match window.get(n) {
+        Some(Instruction { opcode: OpCode::Push, operand: Operand::Int(value) }) => Some(Value::Integer(*value)),
+        _ => None,
+    };
+    let opcode = |n: usize| window.get(n).map(|instruction| instruction.opcode);
+
+    let a = constant(0)?;
+
+    if let Some(b) = constant(1) {
+        if let Some(result) = opcode(2).and_then(|op| binary(op, &a, &b)) {
+            return match result {
+                Value::Integer(n) => Some((3, Some(Instruction::new(OpCode::Push, Operand::Int(n))))),
+                // a comparison, only foldable when a conditional jump consumes it straight away
+                condition => branch(window.get(3)?, &condition).map(|replacement| (4, replacement)),
+            };
+        }
+    }
+
+    match opcode(1)? {
+        OpCode::Neg => match a.neg_solution(OverflowMode::Trap).ok()? {
+            Value::Integer(n) => Some((2, Some(Instruction::new(OpCode::Push, Operand::Int(n))))),
+            _ => None,
+        },
+        OpCode::BitNot => match a.bitnot_solution()? {
+            Value::Integer(n) => Some((2, Some(Instruction::new(OpCode::Push, Operand::Int(n))))),
+            _ => None,
+        },
+        _ => branch(window[1], &a).map(|replacement| (2, replacement)),
+    }
+}
+
+// evaluates a binary operator on constants. Anything that would fail at runtime (division by
+// zero, overflow) is left alone so the program still fails the same way, and since only exact
+// results fold, the VM's overflow mode doesn't change the answer
+fn binary(opcode: OpCode, a: &Value, b: &Value) -> Option<Value> {
+    let mode = OverflowMode::Trap;
+    match opcode {
+        OpCode::Add => a.add_solution(b, mode).ok(),
+        OpCode::Sub => a.sub_solution(b, mode).ok(),
+        OpCode::Mul => a.mul_solution(b, mode).ok(),
+        OpCode::Div => a.div_solution(b, mode).ok(),
+        OpCode::Mod => a.mod_solution(b, mode).ok(),
+        OpCode::Shl => a.shl_solution(b, mode).ok(),
+        OpCode::Shr => a.shr_solution(b, mode).ok(),
+        OpCode::Ushr => a.ushr_solution(b, mode).ok(),
+        OpCode::BitAnd => a.bitand_solution(b),
+        OpCode::BitOr => a.bitor_solution(b),
+        OpCode::BitXor => a.bitxor_solution(b),
+        OpCode::Gt => a.gt_solution(b),
+        OpCode::Lt => a.lt_solution(b),
+        OpCode::Gte => a.gte_solution(b),
+        OpCode::Lte => a.lte_solution(b),
+        OpCode::Eq => a.eq_solution(b),
+        OpCode::Neq => a.neq_solution(b),
+        _ => None,
+    }
+}
+
+// a popping conditional jump on a known condition is either a plain jump or nothing at all
+fn branch(jump: &Instruction, condition: &Value) -> Option<Option<Instruction>> {
+    let jumps_when = match jump.opcode {
+        OpCode::JumpIfTrue => true,
+        OpCode::JumpIfFalse => false,
+        _ => return None,
+    };
+    if condition.is_truthy_solution() == jumps_when {
+        Some(Some(Instruction::new(OpCode::Jump, jump.operand.clone())))
+    } else {
+        Some(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, assemble_module};
+    use crate::io::OutputBuffer;
+    use crate::vm::VM;
+
+    // output and final stack of running the program
+    fn run(bytecode: Vec<u8>) -> (String, Vec<Value>) {
+        let output = OutputBuffer::new();
+        let mut vm = VM::with_output(output.clone());
+        vm.load_bytecode_solution(bytecode);
+        vm.run_solution().unwrap();
+        (output.contents(), vm.get_stack().to_vec())
+    }
+
+    // optimizes with `passes` and checks nothing observable changed
+    fn check(source: &str, passes: Passes) -> Vec<u8> {
+        let bytecode = assemble(source).unwrap();
+        let optimized = optimize(&bytecode, &passes).unwrap();
+        assert_eq!(run(optimized.clone()), run(bytecode));
+        optimized
+    }
+
+    const PROGRAM: &str = r#"
+            PUSH 2
+            PUSH 3
+            ADD
+            PUSH 4
+            MUL
+            STORE_VAR "x"
+            LOAD_VAR "x"
+            PRINT_VAL
+            PUSH 1
+            PUSH 2
+            LT
+            JUMP_IF_FALSE skip
+            PRINT "yes"
+        skip:
+            JUMP hop
+            PRINT "never"
+        hop:
+            JUMP loop
+        loop:
+            LOAD_VAR "x"
+            PUSH -1
+            ADD
+            STORE_VAR "x"
+            LOAD_VAR "x"
+            DUP
+            PRINT_VAL
+            JUMP_IF_TRUE hop
+            HALT
+    "#;
+
+    #[test]
+    fn test_all_passes_keep_output() {
+        let optimized = check(PROGRAM, Passes::default());
+        assert!(optimized.len() < assemble(PROGRAM).unwrap().len());
+    }
+
+    #[test]
+    fn test_each_pass_on_its_own() {
+        let only = [
+            Passes { constant_folding: true, ..Passes::none() },
+            Passes { store_load: true, ..Passes::none() },
+            Passes { jump_threading: true, ..Passes::none() },
+            Passes { dead_code: true, ..Passes::none() },
+            Passes { tail_calls: true, ..Passes::none() },
+        ];
+        for passes in only {
+            check(PROGRAM, passes);
+        }
+        assert_eq!(check(PROGRAM, Passes::none()), assemble(PROGRAM).unwrap());
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let passes = Passes { constant_folding: true, ..Passes::none() };
+        assert_eq!(check("PUSH 2\nPUSH 3\nADD\nPUSH 6\nMUL\nNEG\nHALT", passes), assemble("PUSH -30\nHALT").unwrap());
+
+        // failures stay in the program so they still happen at runtime
+        let bytecode = assemble("PUSH 1\nPUSH 0\nDIV\nHALT").unwrap();
+        assert_eq!(optimize(&bytecode, &passes).unwrap(), bytecode);
+
+        // a jump into the middle of the pattern blocks it
+        let source = "PUSH 1\nJUMP add\nPUSH 5\nadd:\nPUSH 2\nADD\nHALT";
+        assert_eq!(check(source, passes), assemble(source).unwrap());
+    }
+
+    #[test]
+    fn test_store_load() {
+        let passes = Passes { store_load: true, ..Passes::none() };
+        assert_eq!(
+            check("PUSH 7\nSTORE_VAR \"x\"\nLOAD_VAR \"x\"\nHALT", passes),
+            assemble("PUSH 7\nDUP\nSTORE_VAR \"x\"\nHALT").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_jump_threading_and_dead_code() {
+        let passes = Passes { jump_threading: true, dead_code: true, ..Passes::none() };
+        let optimized = check("JUMP a\nPRINT \"dead\"\na:\nJUMP b\nb:\nPUSH 1\nHALT", passes);
+        assert_eq!(optimized, assemble("PUSH 1\nHALT").unwrap());
+
+        // a loop made only of jumps is left alone rather than followed forever
+        let bytecode = assemble("JUMP b\na:\nJUMP b\nb:\nJUMP a").unwrap();
+        optimize(&bytecode, &Passes::default()).unwrap();
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let passes = Passes { tail_calls: true, ..Passes::none() };
+        let source = "PUSH 3\nCALL f\nHALT\nf:\nCALL g\nRETURN\ng:\nPUSH 1\nADD\nRETURN";
+        assert_eq!(
+            check(source, passes),
+            assemble("PUSH 3\nCALL f\nHALT\nf:\nTAIL_CALL g\nRETURN\ng:\nPUSH 1\nADD\nRETURN").unwrap()
+        );
+
+        // with dead code removal the RETURN that can't be reached any more goes too
+        let optimized = check(source, Passes { dead_code: true, ..passes });
+        assert_eq!(optimized, assemble("PUSH 3\nCALL f\nHALT\nf:\nTAIL_CALL g\ng:\nPUSH 1\nADD\nRETURN").unwrap());
+    }
+
+    #[test]
+    fn test_exports_survive() {
+        let module = assemble_module(
+            ".export square 1\nPUSH 1\nPUSH 2\nADD\nHALT\nsquare:\nDUP\nMUL\nRETURN",
+        )
+        .unwrap();
+        let optimized = optimize_module(&module, &Passes::default()).unwrap();
+
+        assert_eq!(optimized.export("square").map(|e| e.address), Some(10));
+        let mut vm = VM::new();
+        vm.load_module(optimized);
+        assert_eq!(vm.call_export("square", &[Value::Integer(9)]), Ok(Value::Integer(81)));
+
+        // the exported instruction itself is optimized away, a jump to the next instruction and
+        // a conditional jump that folds to nothing
+        for source in [
+            ".export f 0\nHALT\nf:\nJUMP g\ng:\nRETURN",
+            ".export f 0\nHALT\nf:\nPUSH 0\nJUMP_IF_TRUE g\ng:\nRETURN",
+        ] {
+            let optimized = optimize_module(&assemble_module(source).unwrap(), &Passes::default()).unwrap();
+            assert_eq!(optimized.export("f").map(|e| e.address), Some(1));
+            assert_eq!(optimized.code, vec![OpCode::Halt.convert_to_u8(), OpCode::Return.convert_to_u8()]);
+        }
+    }
+
+    #[test]
+    fn test_declared_types_survive() {
+        let source = "
+            .record Point x y
+            .enum Option None Some:1
+            PUSH 1
+            PUSH 2
+            NEW_RECORD \"Point\"
+            GET_FIELD \"y\"
+            NEW_VARIANT \"Option.Some\"
+            PRINT_VAL
+            HALT
+        ";
+        let module = assemble_module(source).unwrap();
+        let optimized = optimize_module(&module, &Passes::default()).unwrap();
+        assert_eq!(optimized.records, module.records);
+        assert_eq!(optimized.enums, module.enums);
+
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.load_module(optimized);
+        vm.run_solution().unwrap();
+        assert_eq!(buffer.contents(), "Some(2)");
+    }
+}
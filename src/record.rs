@@ -0,0 +1,61 @@
+// Records are values with a fixed set of named fields, declared by the module (`.record Point x y`
+// in assembly). NEW_RECORD builds one from the top values of the stack, GET_FIELD/SET_FIELD and
+// their _AT variants read and write a field by name or by position. Records are mutable and live
+// on the heap, so copies of a record value all see the same fields.
+
+use std::rc::Rc;
+
+use crate::heap::{trace_value, Handle};
+use crate::value::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordType {
+    pub name: String,
+    // in declaration order, which is also the order NEW_RECORD takes the values in
+    pub fields: Vec<String>,
+}
+
+impl RecordType {
+    pub fn new(name: &str, fields: &[&str]) -> Self {
+        RecordType {
+            name: name.to_string(),
+            fields: fields.iter().map(|field| field.to_string()).collect(),
+        }
+    }
+
+    pub fn field_index(&self, field: &str) -> Option<usize> {
+        self.fields.iter().position(|name| name == field)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub kind: Rc<RecordType>,
+    // one per field of the type
+    pub values: Vec<Value>,
+}
+
+impl Record {
+    pub fn trace(&self, out: &mut Vec<Handle>) {
+        for value in &self.values {
+            trace_value(value, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_lookup_and_trace() {
+        let point = Rc::new(RecordType::new("Point", &["x", "y"]));
+        assert_eq!(point.field_index("y"), Some(1));
+        assert_eq!(point.field_index("z"), None);
+
+        let record = Record { kind: point, values: vec![Value::Integer(1), Value::Function(Handle(4, 0))] };
+        let mut handles = Vec::new();
+        record.trace(&mut handles);
+        assert_eq!(handles, vec![Handle(4, 0)]);
+    }
+}
@@ -0,0 +1,593 @@
+// Checkpoint and restore of a running VM. A snapshot holds everything execution depends on (value
+// stack, call frames with their locals, globals, ip, heap, running coroutines) plus a hash of the
+// bytecode it was taken against, and serializes to a small versioned binary format:
+//
+//   "BVMS" | version u16 | bytecode hash u64 | ip u64 | running u8
+//   | stack: count u32, values | frames: count u32, (return address u64, elided u64, locals)
+//   | globals: count u32, (name, value) | heap: count u32, slots
+//   | resumers: count u32, (coroutine handle, context) | cells: count u32, values
+//
+// All integers are little endian, strings are a u32 length followed by utf-8 bytes, handles are
+// a slot index u32 and a generation u32. Locals and closure upvalues are written as indexes into
+// the cell table at the end, so a local that a closure captured is still shared between the two
+// after a restore. Heap slots are written one for one with their generation, empty ones included,
+// so function values keep their handles. Host state (natives, I/O,
+// limits, pinned handles, gc settings) is not part of a snapshot, the restoring VM keeps its own
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::callframe::CallFrame;
+use crate::error::VMError;
+use crate::coroutine::{Coroutine, CoroutineStatus, ExecutionContext};
+use crate::heap::{Handle, HeapObject, Slot};
+use crate::record::{Record, RecordType};
+use crate::variant::{EnumType, Variant, VariantType};
+use crate::value::{Closure, Upvalue, Value};
+
+const MAGIC: &[u8; 4] = b"BVMS";
+pub const SNAPSHOT_VERSION: u16 = 6;
+
+const TAG_INTEGER: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_STR: u8 = 2;
+const TAG_FUNCTION: u8 = 3;
+const TAG_COROUTINE: u8 = 4;
+const TAG_NIL: u8 = 5;
+const TAG_RECORD: u8 = 6;
+const TAG_VARIANT: u8 = 7;
+const TAG_ARRAY: u8 = 8;
+
+const SLOT_EMPTY: u8 = 0;
+const SLOT_CLOSURE: u8 = 1;
+const SLOT_COROUTINE: u8 = 2;
+const SLOT_RECORD: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub bytecode_hash: u64,
+    pub ip: usize,
+    pub running: bool,
+    pub stack: Vec<Value>,
+    pub call_stack: Vec<CallFrame>,
+    pub globals: Vec<(String, Value)>,
+    pub heap: Vec<Slot>,
+    // the coroutines running when the snapshot was taken, with their resumers' contexts
+    pub resumers: Vec<(Handle, ExecutionContext)>,
+}
+
+// 64 bit FNV-1a, stable across platforms and rust versions unlike DefaultHasher
+pub fn hash_bytecode(bytecode: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytecode {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cells = CellTable::default();
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.bytecode_hash.to_le_bytes());
+        out.extend_from_slice(&(self.ip as u64).to_le_bytes());
+        out.push(self.running as u8);
+
+        write_stack(&mut out, &self.stack);
+        write_frames(&mut out, &self.call_stack, &mut cells);
+
+        write_u32(&mut out, self.globals.len());
+        for (name, value) in &self.globals {
+            write_str(&mut out, name);
+            write_value(&mut out, value);
+        }
+
+        write_u32(&mut out, self.heap.len());
+        for slot in &self.heap {
+            write_u32(&mut out, slot.generation as usize);
+            match &slot.object {
+                None => out.push(SLOT_EMPTY),
+                Some(HeapObject::Closure(closure)) => {
+                    out.push(SLOT_CLOSURE);
+                    out.extend_from_slice(&(closure.address as u64).to_le_bytes());
+                    write_u32(&mut out, closure.upvalues.len());
+                    for (name, cell) in &closure.upvalues {
+                        write_str(&mut out, name);
+                        write_u32(&mut out, cells.id(cell));
+                    }
+                }
+                Some(HeapObject::Coroutine(coroutine)) => {
+                    out.push(SLOT_COROUTINE);
+                    out.push(coroutine.status.code());
+                    write_context(&mut out, &coroutine.context, &mut cells);
+                }
+                // the type goes along with every record, the restoring VM may not declare it
+                Some(HeapObject::Record(record)) => {
+                    out.push(SLOT_RECORD);
+                    write_str(&mut out, &record.kind.name);
+                    write_u32(&mut out, record.kind.fields.len());
+                    for (field, value) in record.kind.fields.iter().zip(&record.values) {
+                        write_str(&mut out, field);
+                        write_value(&mut out, value);
+                    }
+                }
+            }
+        }
+
+        write_u32(&mut out, self.resumers.len());
+        for (handle, context) in &self.resumers {
+            write_handle(&mut out, *handle);
+            write_context(&mut out, context, &mut cells);
+        }
+
+        // values only refer to heap objects by handle, so every cell is known by now
+        write_u32(&mut out, cells.cells.len());
+        for cell in &cells.cells {
+            write_value(&mut out, &cell.borrow());
+        }
+
+        out
+    }
+
+    // a copy that shares no cells with this one, so running on from either can't affect the other.
+    // Fails like from_bytes() does when a value holds a handle the heap has no such object for
+    pub fn detached(&self) -> Result<Snapshot, VMError> {
+        Snapshot::from_bytes(&self.to_bytes())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Snapshot, VMError> {
+        let mut reader = SnapshotReader { data, offset: 0, cells: Vec::new(), handles: Vec::new() };
+
+        if reader.take(4)? != MAGIC {
+            return Err(invalid("not a VM snapshot"));
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {}", version)));
+        }
+
+        let bytecode_hash = u64::from_le_bytes(reader.array()?);
+        let ip = reader.usize()?;
+        let running = match reader.byte()? {
+            0 => false,
+            1 => true,
+            other => return Err(invalid(&format!("bad running flag {}", other))),
+        };
+
+        let stack = reader.stack()?;
+        let call_stack = reader.frames()?;
+        if call_stack.is_empty() {
+            return Err(invalid("snapshot has no call frames"));
+        }
+
+        let mut globals = Vec::new();
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let value = reader.value()?;
+            globals.push((name, value));
+        }
+
+        let mut heap = Vec::new();
+        for _ in 0..reader.u32()? {
+            let generation = reader.u32()?;
+            let object = match reader.byte()? {
+                SLOT_EMPTY => None,
+                SLOT_CLOSURE => {
+                    let address = reader.usize()?;
+                    let mut upvalues = Vec::new();
+                    for _ in 0..reader.u32()? {
+                        let name = reader.string()?;
+                        upvalues.push((name, reader.cell()?));
+                    }
+                    Some(HeapObject::Closure(Closure { address, upvalues }))
+                }
+                SLOT_COROUTINE => {
+                    let code = reader.byte()?;
+                    let status = CoroutineStatus::from_code(code)
+                        .ok_or_else(|| invalid(&format!("bad coroutine status {}", code)))?;
+                    let context = reader.context()?;
+                    Some(HeapObject::Coroutine(Coroutine { status, context }))
+                }
+                SLOT_RECORD => {
+                    let name = reader.string()?;
+                    let (mut fields, mut values) = (Vec::new(), Vec::new());
+                    for _ in 0..reader.u32()? {
+                        fields.push(reader.string()?);
+                        values.push(reader.value()?);
+                    }
+                    let kind = Rc::new(RecordType { name, fields });
+                    Some(HeapObject::Record(Record { kind, values }))
+                }
+                kind => return Err(invalid(&format!("unknown heap object kind {}", kind))),
+            };
+            heap.push(Slot { generation, object });
+        }
+
+        let mut resumers = Vec::new();
+        for _ in 0..reader.u32()? {
+            let handle = reader.handle()?;
+            reader.handles.push(Value::Coroutine(handle));
+            resumers.push((handle, reader.context()?));
+        }
+
+        let count = reader.u32()? as usize;
+        if reader.cells.len() > count {
+            return Err(invalid("cell index out of range"));
+        }
+        for id in 0..count {
+            let value = reader.value()?;
+            *reader.cell_by_id(id as u32).borrow_mut() = value;
+        }
+
+        if reader.offset != data.len() {
+            return Err(invalid("trailing bytes after snapshot"));
+        }
+        // every handle has to point at a live object of the kind the value says it is
+        let dangling = reader.handles.iter().any(|value| {
+            let object = value
+                .handle()
+                .and_then(|handle| heap.get(handle.index()).filter(|slot| slot.generation == handle.generation()))
+                .and_then(|slot| slot.object.as_ref());
+            !matches!(
+                (value, object),
+                (Value::Function(_), Some(HeapObject::Closure(_)))
+                    | (Value::Coroutine(_), Some(HeapObject::Coroutine(_)))
+                    | (Value::Record(_), Some(HeapObject::Record(_)))
+            )
+        });
+        if dangling {
+            return Err(invalid("handle to an empty heap slot"));
+        }
+
+        Ok(Snapshot { bytecode_hash, ip, running, stack, call_stack, globals, heap, resumers })
+    }
+}
+
+fn invalid(message: &str) -> VMError {
+    VMError::InvalidSnapshot(message.to_string())
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_handle(out: &mut Vec<u8>, handle: Handle) {
+    out.extend_from_slice(&handle.0.to_le_bytes());
+    out.extend_from_slice(&handle.1.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// gives every distinct cell an index, in the order they are first seen
+#[derive(Default)]
+struct CellTable {
+    cells: Vec<Upvalue>,
+    ids: HashMap<*const RefCell<Value>, u32>,
+}
+
+impl CellTable {
+    fn id(&mut self, cell: &Upvalue) -> usize {
+        let next = self.cells.len() as u32;
+        let id = *self.ids.entry(Rc::as_ptr(cell)).or_insert(next);
+        if id == next {
+            self.cells.push(cell.clone());
+        }
+        id as usize
+    }
+}
+
+fn write_stack(out: &mut Vec<u8>, stack: &[Value]) {
+    write_u32(out, stack.len());
+    for value in stack {
+        write_value(out, value);
+    }
+}
+
+fn write_frames(out: &mut Vec<u8>, frames: &[CallFrame], cells: &mut CellTable) {
+    write_u32(out, frames.len());
+    for frame in frames {
+        out.extend_from_slice(&(frame.return_address() as u64).to_le_bytes());
+        out.extend_from_slice(&(frame.elided() as u64).to_le_bytes());
+        let locals = frame.locals();
+        write_u32(out, locals.len());
+        for (name, cell) in locals {
+            write_str(out, name);
+            write_u32(out, cells.id(cell));
+        }
+    }
+}
+
+// a coroutine's or resumer's saved context: ip u64 | stack | frames
+fn write_context(out: &mut Vec<u8>, context: &ExecutionContext, cells: &mut CellTable) {
+    out.extend_from_slice(&(context.ip as u64).to_le_bytes());
+    write_stack(out, &context.stack);
+    write_frames(out, &context.call_stack, cells);
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Integer(n) => {
+            out.push(TAG_INTEGER);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        Value::Boolean(b) => {
+            out.push(TAG_BOOLEAN);
+            out.push(*b as u8);
+        }
+        Value::Str(s) => {
+            out.push(TAG_STR);
+            write_str(out, s);
+        }
+        Value::Function(handle) => {
+            out.push(TAG_FUNCTION);
+            write_handle(out, *handle);
+        }
+        Value::Coroutine(handle) => {
+            out.push(TAG_COROUTINE);
+            write_handle(out, *handle);
+        }
+        Value::Record(handle) => {
+            out.push(TAG_RECORD);
+            write_handle(out, *handle);
+        }
+        Value::Array(items) => {
+            out.push(TAG_ARRAY);
+            write_stack(out, items);
+        }
+        // enum | tag u32 | payload values, the enum being name, count u32, (name, arity u32)
+        Value::Variant(variant) => {
+            out.push(TAG_VARIANT);
+            write_str(out, &variant.kind.name);
+            write_u32(out, variant.kind.variants.len());
+            for declared in &variant.kind.variants {
+                write_str(out, &declared.name);
+                write_u32(out, declared.arity);
+            }
+            write_u32(out, variant.tag);
+            for value in &variant.payload {
+                write_value(out, value);
+            }
+        }
+        Value::Nil => out.push(TAG_NIL),
+    }
+}
+
+struct SnapshotReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    // cells by index, filled in with their values once the table at the end is read
+    cells: Vec<Upvalue>,
+    // every function, coroutine and record value read, checked against the heap once it has been read
+    handles: Vec<Value>,
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VMError> {
+        let bytes = self.data
+            .get(self.offset..self.offset.saturating_add(len))
+            .ok_or_else(|| invalid("snapshot is truncated"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], VMError> {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, VMError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, VMError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn handle(&mut self) -> Result<Handle, VMError> {
+        let index = self.u32()?;
+        Ok(Handle(index, self.u32()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, VMError> {
+        usize::try_from(u64::from_le_bytes(self.array()?)).map_err(|_| invalid("address out of range"))
+    }
+
+    fn string(&mut self) -> Result<String, VMError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| VMError::InvalidString)
+    }
+
+    fn cell(&mut self) -> Result<Upvalue, VMError> {
+        let id = self.u32()?;
+        // anything past the end of the data can't be a real index, don't allocate for it
+        if id as usize > self.data.len() {
+            return Err(invalid("cell index out of range"));
+        }
+        Ok(self.cell_by_id(id))
+    }
+
+    fn cell_by_id(&mut self, id: u32) -> Upvalue {
+        while self.cells.len() <= id as usize {
+            self.cells.push(Rc::new(RefCell::new(Value::Integer(0))));
+        }
+        self.cells[id as usize].clone()
+    }
+
+    fn stack(&mut self) -> Result<Vec<Value>, VMError> {
+        let mut stack = Vec::new();
+        for _ in 0..self.u32()? {
+            stack.push(self.value()?);
+        }
+        Ok(stack)
+    }
+
+    fn frames(&mut self) -> Result<Vec<CallFrame>, VMError> {
+        let mut frames = Vec::new();
+        for _ in 0..self.u32()? {
+            let return_address = self.usize()?;
+            let mut frame = CallFrame::new_solution(return_address).with_elided(self.usize()?);
+            for _ in 0..self.u32()? {
+                let name = self.string()?;
+                let cell = self.cell()?;
+                frame.bind_local(name, cell);
+            }
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    fn context(&mut self) -> Result<ExecutionContext, VMError> {
+        let ip = self.usize()?;
+        let stack = self.stack()?;
+        let call_stack = self.frames()?;
+        Ok(ExecutionContext { stack, call_stack, ip })
+    }
+
+    fn value(&mut self) -> Result<Value, VMError> {
+        let tag = self.byte()?;
+        match tag {
+            TAG_INTEGER => Ok(Value::Integer(i64::from_le_bytes(self.array()?))),
+            TAG_BOOLEAN => Ok(Value::Boolean(self.byte()? != 0)),
+            TAG_STR => Ok(Value::str_solution(&self.string()?)),
+            TAG_NIL => Ok(Value::Nil),
+            TAG_ARRAY => Ok(Value::array_solution(self.stack()?)),
+            TAG_VARIANT => {
+                let name = self.string()?;
+                let mut variants = Vec::new();
+                for _ in 0..self.u32()? {
+                    let name = self.string()?;
+                    variants.push(VariantType { name, arity: self.u32()? as usize });
+                }
+                let tag = self.u32()? as usize;
+                let arity = variants.get(tag).ok_or_else(|| invalid("variant tag out of range"))?.arity;
+                let mut payload = Vec::new();
+                for _ in 0..arity {
+                    payload.push(self.value()?);
+                }
+                let kind = Rc::new(EnumType { name, variants });
+                Ok(Value::Variant(Rc::new(Variant { kind, tag, payload })))
+            }
+            TAG_FUNCTION | TAG_COROUTINE | TAG_RECORD => {
+                let handle = self.handle()?;
+                let value = match tag {
+                    TAG_FUNCTION => Value::Function(handle),
+                    TAG_COROUTINE => Value::Coroutine(handle),
+                    _ => Value::Record(handle),
+                };
+                self.handles.push(value.clone());
+                Ok(value)
+            }
+            tag => Err(invalid(&format!("unknown value tag {}", tag))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut frame = CallFrame::new_solution(42);
+        frame.store_local_solution("n".to_string(), Value::Integer(-3));
+        frame.store_local_solution("flag".to_string(), Value::Boolean(true));
+
+        let snapshot = Snapshot {
+            bytecode_hash: hash_bytecode(&[1, 2, 3]),
+            ip: 17,
+            running: true,
+            stack: vec![Value::Integer(1), Value::str_solution("héllo"), Value::Nil],
+            call_stack: vec![CallFrame::new_solution(0), frame],
+            globals: vec![("x".to_string(), Value::Integer(i64::MIN))],
+            heap: vec![],
+            resumers: vec![],
+        };
+
+        let decoded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(decoded.ip, 17);
+        assert_eq!(decoded.stack, snapshot.stack);
+        assert_eq!(decoded.globals, snapshot.globals);
+        assert_eq!(decoded.call_stack[1].return_address(), 42);
+        assert_eq!(decoded.call_stack[1].load_local_solution("n"), Some(Value::Integer(-3)));
+        assert_eq!(decoded.to_bytes(), snapshot.to_bytes());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_data() {
+        let snapshot = Snapshot {
+            bytecode_hash: 0,
+            ip: 0,
+            running: false,
+            stack: vec![Value::Integer(5)],
+            call_stack: vec![CallFrame::new_solution(0)],
+            globals: vec![],
+            heap: vec![],
+            resumers: vec![],
+        };
+        let bytes = snapshot.to_bytes();
+
+        assert!(Snapshot::from_bytes(b"nope").is_err());
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 99;
+        assert_eq!(
+            Snapshot::from_bytes(&wrong_version),
+            Err(VMError::InvalidSnapshot("unsupported snapshot version 99".to_string()))
+        );
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(Snapshot::from_bytes(&trailing).is_err());
+    }
+
+    #[test]
+    fn test_heap_slots_keep_their_handles() {
+        let cell = Rc::new(RefCell::new(Value::Function(Handle(2, 0))));
+        let closure = Closure { address: 9, upvalues: vec![("me".to_string(), cell)] };
+        let mut snapshot = Snapshot {
+            bytecode_hash: 0,
+            ip: 0,
+            running: false,
+            stack: vec![Value::Function(Handle(2, 0))],
+            call_stack: vec![CallFrame::new_solution(0)],
+            globals: vec![],
+            heap: vec![
+                Slot::default(),
+                Slot { generation: 1, object: None },
+                Slot { generation: 0, object: Some(HeapObject::Closure(closure)) },
+            ],
+            resumers: vec![],
+        };
+
+        let decoded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(decoded.heap.len(), 3);
+        assert_eq!(decoded.stack, snapshot.stack);
+        assert_eq!(decoded.to_bytes(), snapshot.to_bytes());
+
+        assert_eq!(decoded.heap[1].generation, 1);
+
+        // an empty slot, and the right slot under an older generation
+        for handle in [Handle(1, 1), Handle(2, 1)] {
+            snapshot.stack = vec![Value::Function(handle)];
+            assert_eq!(
+                Snapshot::from_bytes(&snapshot.to_bytes()),
+                Err(VMError::InvalidSnapshot("handle to an empty heap slot".to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_hash_depends_on_every_byte() {
+        assert_ne!(hash_bytecode(&[1, 2, 3]), hash_bytecode(&[1, 2, 4]));
+        assert_ne!(hash_bytecode(&[0]), hash_bytecode(&[0, 0]));
+    }
+}
@@ -0,0 +1,357 @@
+// The standard library, a set of natives any host can install with `stdlib::register(&mut vm)`
+// and bytecode calls with CALL_NATIVE like its own. Arguments of the wrong type fail with
+// InvalidOperand, anything else that can go wrong is listed with the routine.
+//
+//   math
+//   abs(n)               IntegerOverflow for the smallest int, which has no positive counterpart
+//   min(a, b)
+//   max(a, b)
+//   pow(base, exp)       Native error for a negative exponent, IntegerOverflow if it doesn't fit
+//   sqrt(n)              rounded down, Native error for a negative n
+//   clamp(n, lo, hi)     Native error when lo > hi
+//   gcd(a, b)            never negative, gcd(0, 0) is 0. IntegerOverflow when the result is 2^63
+//
+//   strings
+//   split(s, sep)        array of the pieces between each sep, Native error for an empty sep
+//   join(array, sep)     the elements as PRINT_VAL shows them, with sep between each two
+//   trim(s)              without leading and trailing whitespace
+//   find(s, needle)      character index of the first occurrence, or nil
+//   parse_int(s)         the decimal int s spells out, or nil if it doesn't
+//   len(s | array)       characters in a string or elements in an array
+//
+//   arrays, built with NEW_ARRAY or returned by split
+//   get(array, i)        the ith element, Native error when i is out of range
+//   sort(array)          ascending, the elements must be all ints or all strings
+//   reverse(array)
+//   map(array, fn)       calls fn with each element, errors from fn are passed through
+//
+// Arrays are immutable, the array routines return a new one
+
+use std::cmp::Ordering;
+
+use crate::error::VMError;
+use crate::native::NativeContext;
+use crate::value::Value;
+use crate::vm::VM;
+
+pub fn register(vm: &mut VM) {
+    vm.register_native("abs", 1, |_, args| {
+        Ok(Value::int_solution(int(&args[0])?.checked_abs().ok_or(VMError::IntegerOverflow)?))
+    });
+    vm.register_native("min", 2, |_, args| Ok(Value::int_solution(int(&args[0])?.min(int(&args[1])?))));
+    vm.register_native("max", 2, |_, args| Ok(Value::int_solution(int(&args[0])?.max(int(&args[1])?))));
+    vm.register_native("pow", 2, |_, args| pow(int(&args[0])?, int(&args[1])?));
+    vm.register_native("sqrt", 1, |_, args| {
+        let n = int(&args[0])?;
+        if n < 0 {
+            return Err(VMError::Native(format!("sqrt of negative number {}", n)));
+        }
+        Ok(Value::int_solution(n.isqrt()))
+    });
+    vm.register_native("clamp", 3, |_, args| {
+        let (n, lo, hi) = (int(&args[0])?, int(&args[1])?, int(&args[2])?);
+        if lo > hi {
+            return Err(VMError::Native(format!("clamp bounds {} > {}", lo, hi)));
+        }
+        Ok(Value::int_solution(n.clamp(lo, hi)))
+    });
+    vm.register_native("gcd", 2, |_, args| gcd(int(&args[0])?, int(&args[1])?));
+
+    vm.register_native("split", 2, |_, args| {
+        let (s, sep) = (string(&args[0])?, string(&args[1])?);
+        if sep.is_empty() {
+            return Err(VMError::Native("split with an empty separator".to_string()));
+        }
+        Ok(Value::array_solution(s.split(sep).map(Value::str_solution).collect()))
+    });
+    vm.register_native("join", 2, |ctx, args| {
+        let (items, sep) = (array(&args[0])?, string(&args[1])?);
+        let items: Vec<String> = items.iter().map(|item| ctx.display_value(item)).collect();
+        Ok(Value::str_solution(&items.join(sep)))
+    });
+    vm.register_native("trim", 1, |_, args| Ok(Value::str_solution(string(&args[0])?.trim())));
+    vm.register_native("find", 2, |_, args| {
+        let (s, needle) = (string(&args[0])?, string(&args[1])?);
+        Ok(match s.find(needle) {
+            Some(byte) => Value::int_solution(s[..byte].chars().count() as i64),
+            None => Value::Nil,
+        })
+    });
+    vm.register_native("parse_int", 1, |_, args| {
+        Ok(string(&args[0])?.parse().map(Value::int_solution).unwrap_or(Value::Nil))
+    });
+    vm.register_native("len", 1, |_, args| {
+        let len = match &args[0] {
+            Value::Str(s) => s.chars().count(),
+            Value::Array(items) => items.len(),
+            _ => return Err(VMError::InvalidOperand),
+        };
+        Ok(Value::int_solution(len as i64))
+    });
+
+    vm.register_native("get", 2, |_, args| {
+        let (items, index) = (array(&args[0])?, int(&args[1])?);
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| items.get(index))
+            .cloned()
+            .ok_or_else(|| VMError::Native(format!("index {} out of range for length {}", index, items.len())))
+    });
+    vm.register_native("sort", 1, |_, args| {
+        let mut items = array(&args[0])?.to_vec();
+        let comparable = items.iter().all(|item| matches!(item, Value::Integer(_)))
+            || items.iter().all(|item| matches!(item, Value::Str(_)));
+        if !comparable {
+            return Err(VMError::InvalidOperand);
+        }
+        items.sort_by(compare);
+        Ok(Value::array_solution(items))
+    });
+    vm.register_native("reverse", 1, |_, args| {
+        Ok(Value::array_solution(array(&args[0])?.iter().rev().cloned().collect()))
+    });
+    vm.register_native("map", 2, map);
+}
+
+fn int(value: &Value) -> Result<i64, VMError> {
+    value.as_int_solution().ok_or(VMError::InvalidOperand)
+}
+
+fn string(value: &Value) -> Result<&str, VMError> {
+    value.as_str_solution().ok_or(VMError::InvalidOperand)
+}
+
+fn array(value: &Value) -> Result<&[Value], VMError> {
+    value.as_array_solution().ok_or(VMError::InvalidOperand)
+}
+
+fn pow(base: i64, exp: i64) -> Result<Value, VMError> {
+    if exp < 0 {
+        return Err(VMError::Native(format!("pow with negative exponent {}", exp)));
+    }
+    // past u32::MAX only 0, 1 and -1 don't overflow, and for -1 all that matters is the parity
+    let exp = u32::try_from(exp).unwrap_or(if exp % 2 == 0 { u32::MAX - 1 } else { u32::MAX });
+    base.checked_pow(exp).map(Value::int_solution).ok_or(VMError::IntegerOverflow)
+}
+
+fn gcd(a: i64, b: i64) -> Result<Value, VMError> {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    i64::try_from(a).map(Value::int_solution).map_err(|_| VMError::IntegerOverflow)
+}
+
+// sort only lets through arrays of all ints or all strings
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+        (Value::Str(a), Value::Str(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    }
+}
+
+// the results so far are pinned, fn may allocate and collect before the array holding them exists
+fn map(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, VMError> {
+    let items = array(&args[0])?;
+    let mut results = Vec::with_capacity(items.len());
+    let mut outcome = Ok(());
+    for item in items {
+        match ctx.call_value(&args[1], std::slice::from_ref(item)) {
+            Ok(result) => {
+                ctx.pin(&result);
+                results.push(result);
+            }
+            Err(error) => {
+                outcome = Err(error);
+                break;
+            }
+        }
+    }
+    for result in &results {
+        ctx.unpin(result);
+    }
+    outcome.map(|_| Value::array_solution(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::GcConfig;
+    use crate::io::OutputBuffer;
+
+    // runs the assembly with the standard library installed, returns the stack or the error
+    fn run(source: &str) -> Result<Vec<Value>, VMError> {
+        let mut vm = VM::new();
+        register(&mut vm);
+        vm.load_module(crate::assembler::assemble_module(source).unwrap());
+        vm.run_solution()?;
+        Ok(vm.get_stack().to_vec())
+    }
+
+    fn ints(values: &[i64]) -> Vec<Value> {
+        values.iter().map(|n| Value::Integer(*n)).collect()
+    }
+
+    #[test]
+    fn test_math() {
+        let source = "
+            PUSH -5
+            CALL_NATIVE \"abs\"
+            PUSH 3
+            PUSH 9
+            CALL_NATIVE \"min\"
+            PUSH 3
+            PUSH 9
+            CALL_NATIVE \"max\"
+            PUSH 2
+            PUSH 10
+            CALL_NATIVE \"pow\"
+            PUSH 17
+            CALL_NATIVE \"sqrt\"
+            PUSH 15
+            PUSH 0
+            PUSH 10
+            CALL_NATIVE \"clamp\"
+            PUSH -12
+            PUSH 18
+            CALL_NATIVE \"gcd\"
+            HALT
+        ";
+        assert_eq!(run(source), Ok(ints(&[5, 3, 9, 1024, 4, 10, 6])));
+        assert_eq!(run("PUSH -1\nPUSH 4294967296\nCALL_NATIVE \"pow\"\nHALT"), Ok(ints(&[1])));
+    }
+
+    #[test]
+    fn test_math_errors() {
+        assert_eq!(run("PUSH -9223372036854775808\nCALL_NATIVE \"abs\"\nHALT"), Err(VMError::IntegerOverflow));
+        assert_eq!(run("PUSH 2\nPUSH 63\nCALL_NATIVE \"pow\"\nHALT"), Err(VMError::IntegerOverflow));
+        assert_eq!(
+            run("PUSH 2\nPUSH -1\nCALL_NATIVE \"pow\"\nHALT"),
+            Err(VMError::Native("pow with negative exponent -1".to_string()))
+        );
+        assert_eq!(
+            run("PUSH -4\nCALL_NATIVE \"sqrt\"\nHALT"),
+            Err(VMError::Native("sqrt of negative number -4".to_string()))
+        );
+        assert_eq!(
+            run("PUSH 1\nPUSH 5\nPUSH 2\nCALL_NATIVE \"clamp\"\nHALT"),
+            Err(VMError::Native("clamp bounds 5 > 2".to_string()))
+        );
+        assert_eq!(run("PUSH -9223372036854775808\nPUSH 0\nCALL_NATIVE \"gcd\"\nHALT"), Err(VMError::IntegerOverflow));
+        assert_eq!(run("PUSH_NIL\nCALL_NATIVE \"abs\"\nHALT"), Err(VMError::InvalidOperand));
+    }
+
+    #[test]
+    fn test_strings() {
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        register(&mut vm);
+        let source = "
+            .record Point x y
+            PUSH 1
+            PUSH 2
+            NEW_RECORD \"Point\"
+            PUSH 3
+            NEW_ARRAY 2
+            FORMAT \" | \"
+            STORE_VAR \"sep\"
+            FORMAT \"  a,b,,c \"
+            CALL_NATIVE \"trim\"
+            FORMAT \",\"
+            CALL_NATIVE \"split\"
+            DUP
+            CALL_NATIVE \"len\"
+            PRINT_VAL
+            LOAD_VAR \"sep\"
+            CALL_NATIVE \"join\"
+            PRINT_VAL
+            FORMAT \"héllo\"
+            FORMAT \"llo\"
+            CALL_NATIVE \"find\"
+            FORMAT \"héllo\"
+            FORMAT \"z\"
+            CALL_NATIVE \"find\"
+            FORMAT \"-42\"
+            CALL_NATIVE \"parse_int\"
+            FORMAT \"4x2\"
+            CALL_NATIVE \"parse_int\"
+            HALT
+        ";
+        vm.load_module(crate::assembler::assemble_module(source).unwrap());
+        vm.run_solution().unwrap();
+        assert_eq!(buffer.contents(), "4a | b |  | c");
+        let array = vm.get_stack()[0].clone();
+        assert_eq!(vm.display_value(&array), "[Point { x: 1, y: 2 }, 3]");
+        assert_eq!(
+            vm.get_stack()[1..],
+            [Value::Integer(2), Value::Nil, Value::Integer(-42), Value::Nil]
+        );
+
+        let source = "FORMAT \"abc\"\nFORMAT \"\"\nCALL_NATIVE \"split\"\nHALT";
+        assert_eq!(run(source), Err(VMError::Native("split with an empty separator".to_string())));
+        assert_eq!(run("PUSH 1\nCALL_NATIVE \"trim\"\nHALT"), Err(VMError::InvalidOperand));
+    }
+
+    #[test]
+    fn test_arrays() {
+        let source = "
+            PUSH 3
+            PUSH 1
+            PUSH 2
+            NEW_ARRAY 3
+            DUP
+            CALL_NATIVE \"sort\"
+            SWAP
+            CALL_NATIVE \"reverse\"
+            DUP
+            PUSH 0
+            CALL_NATIVE \"get\"
+            HALT
+        ";
+        assert_eq!(
+            run(source),
+            Ok(vec![Value::array_solution(ints(&[1, 2, 3])), Value::array_solution(ints(&[2, 1, 3])), Value::Integer(2)])
+        );
+
+        let source = "FORMAT \"b\"\nFORMAT \"a\"\nNEW_ARRAY 2\nCALL_NATIVE \"sort\"\nHALT";
+        let strings = vec![Value::str_solution("a"), Value::str_solution("b")];
+        assert_eq!(run(source), Ok(vec![Value::array_solution(strings)]));
+        let source = "FORMAT \"b\"\nPUSH 1\nNEW_ARRAY 2\nCALL_NATIVE \"sort\"\nHALT";
+        assert_eq!(run(source), Err(VMError::InvalidOperand));
+        assert_eq!(
+            run("NEW_ARRAY 0\nPUSH -1\nCALL_NATIVE \"get\"\nHALT"),
+            Err(VMError::Native("index -1 out of range for length 0".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        // each call makes a new record, and collects on every allocation, so the results mapped
+        // so far have to stay reachable until map returns its array
+        let source = "
+            .record Box v
+            PUSH 1
+            PUSH 2
+            PUSH 3
+            NEW_ARRAY 3
+            MAKE_CLOSURE boxed
+            CALL_NATIVE \"map\"
+            HALT
+        boxed:
+            NEW_RECORD \"Box\"
+            RETURN
+        ";
+        let mut vm = VM::new();
+        vm.set_gc_config(GcConfig { stress: true, ..GcConfig::default() });
+        register(&mut vm);
+        vm.load_module(crate::assembler::assemble_module(source).unwrap());
+        vm.run_solution().unwrap();
+        let boxes = vm.get_stack()[0].clone();
+        assert_eq!(vm.display_value(&boxes), "[Box { v: 1 }, Box { v: 2 }, Box { v: 3 }]");
+        assert_eq!(vm.gc_stats().live, 4);
+
+        let source = "PUSH 0\nNEW_ARRAY 1\nMAKE_CLOSURE f\nCALL_NATIVE \"map\"\nHALT\nf:\nPUSH 1\nSWAP\nDIV\nRETURN";
+        assert_eq!(run(source), Err(VMError::DivisionByZero));
+    }
+}
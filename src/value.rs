@@ -1,7 +1,99 @@
-#[derive(Debug, Clone, Copy, PartialEq)]
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::error::VMError;
+use crate::heap::Handle;
+use crate::variant::Variant;
+
+// what integer arithmetic does when the result doesn't fit in an i64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowMode {
+    // raise VMError::IntegerOverflow
+    #[default]
+    Trap,
+    // two's complement wrap around
+    Wrap,
+    // clamp to i64::MIN / i64::MAX
+    Saturate,
+}
+
+impl OverflowMode {
+    // pick the result matching this mode, `checked` is None when the operation overflowed
+    fn apply(self, checked: Option<i64>, wrapped: i64, saturated: i64) -> Result<Value, VMError> {
+        match self {
+            OverflowMode::Trap => checked.map(Value::Integer).ok_or(VMError::IntegerOverflow),
+            OverflowMode::Wrap => Ok(Value::Integer(wrapped)),
+            OverflowMode::Saturate => Ok(Value::Integer(saturated)),
+        }
+    }
+}
+
+// the same variant of the same enum, with payloads that EQ would call equal
+fn variants_equal(a: &Variant, b: &Variant) -> bool {
+    a.tag == b.tag && a.kind == b.kind && items_equal(&a.payload, &b.payload)
+}
+
+// as many values, pairwise equal by EQ
+fn items_equal(a: &[Value], b: &[Value]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.eq_solution(y) == Some(Value::Boolean(true)))
+}
+
+// valid shift amounts for a 64 bit integer
+fn shift_amount(n: i64) -> Option<u32> {
+    (0..64).contains(&n).then_some(n as u32)
+}
+
+// a local variable slot. Frames and the closures that capture their locals hold the same cell,
+// so an assignment on either side is seen by the other, even after the frame has returned
+pub type Upvalue = Rc<RefCell<Value>>;
+
+// a function: code address plus the locals it captured when it was made. Closures live on the
+// heap, a Value::Function only holds the handle
+#[derive(Clone)]
+pub struct Closure {
+    pub address: usize,
+    pub upvalues: Vec<(String, Upvalue)>,
+}
+
+// captured cells are compared and printed by identity, not by what they currently hold
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        self.address == other.address
+            && self.upvalues.len() == other.upvalues.len()
+            && self.upvalues
+                .iter()
+                .zip(&other.upvalues)
+                .all(|((a, x), (b, y))| a == b && Rc::ptr_eq(x, y))
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.upvalues.iter().map(|(name, _)| name.as_str()).collect();
+        write!(f, "Closure {{ address: {}, upvalues: {:?} }}", self.address, names)
+    }
+}
+
+// names TYPE_OF gives and IS_TYPE accepts
+pub const TYPE_NAMES: [&str; 9] =
+    ["int", "bool", "string", "array", "function", "coroutine", "record", "enum", "nil"];
+
+// strings, arrays and enum variants are immutable, so clones share the same allocation.
+// Functions, coroutines and records are handles into the VM's heap, copying one doesn't copy the
+// object
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Integer(i64),
     Boolean(bool),
+    Str(Rc<str>),
+    Array(Rc<[Value]>),
+    Function(Handle),
+    Coroutine(Handle),
+    Record(Handle),
+    Variant(Rc<Variant>),
+    // the absence of a value
+    Nil,
 }
 
 impl Value {
@@ -19,36 +111,153 @@ impl Value {
         }
     }
     
-    pub fn add_solution(self, other: Value) -> Option<Value> {
+    pub fn add_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Integer(a + b)),
-            _ => None,
+            (&Value::Integer(a), &Value::Integer(b)) => {
+                mode.apply(a.checked_add(b), a.wrapping_add(b), a.saturating_add(b))
+            }
+            _ => Err(VMError::InvalidOperand),
         }
     }
     
-    pub fn sub_solution(self, other: Value) -> Option<Value> {
+    pub fn sub_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Integer(a - b)),
-            _ => None,
+            (&Value::Integer(a), &Value::Integer(b)) => {
+                mode.apply(a.checked_sub(b), a.wrapping_sub(b), a.saturating_sub(b))
+            }
+            _ => Err(VMError::InvalidOperand),
         }
     }
     
-    pub fn mul_solution(self, other: Value) -> Option<Value> {
+    pub fn mul_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Integer(a * b)),
-            _ => None,
+            (&Value::Integer(a), &Value::Integer(b)) => {
+                mode.apply(a.checked_mul(b), a.wrapping_mul(b), a.saturating_mul(b))
+            }
+            _ => Err(VMError::InvalidOperand),
         }
     }
     
-    pub fn div_solution(self, other: Value) -> Option<Value> {
+    // division by zero is always an error; only i64::MIN / -1 can overflow
+    pub fn div_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
+        match (self, other) {
+            (&Value::Integer(_), &Value::Integer(0)) => Err(VMError::DivisionByZero),
+            (&Value::Integer(a), &Value::Integer(b)) => {
+                mode.apply(a.checked_div(b), a.wrapping_div(b), a.saturating_div(b))
+            }
+            _ => Err(VMError::InvalidOperand),
+        }
+    }
+
+    // remainder takes the sign of the dividend, same as rust's %
+    pub fn mod_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
+        match (self, other) {
+            (&Value::Integer(_), &Value::Integer(0)) => Err(VMError::DivisionByZero),
+            (&Value::Integer(a), &Value::Integer(b)) => {
+                mode.apply(a.checked_rem(b), a.wrapping_rem(b), a.wrapping_rem(b))
+            }
+            _ => Err(VMError::InvalidOperand),
+        }
+    }
+
+    pub fn neg_solution(&self, mode: OverflowMode) -> Result<Value, VMError> {
+        match *self {
+            Value::Integer(a) => mode.apply(a.checked_neg(), a.wrapping_neg(), a.saturating_neg()),
+            _ => Err(VMError::InvalidOperand),
+        }
+    }
+
+    pub fn bitand_solution(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => {
-                if b == 0 {
-                    None 
-                } else {
-                    Some(Value::Integer(a / b))
-                }
-            },
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Integer(a & b)),
+            _ => None,
+        }
+    }
+
+    pub fn bitor_solution(&self, other: &Value) -> Option<Value> {
+        match (self, other) {
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Integer(a | b)),
+            _ => None,
+        }
+    }
+
+    pub fn bitxor_solution(&self, other: &Value) -> Option<Value> {
+        match (self, other) {
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Integer(a ^ b)),
+            _ => None,
+        }
+    }
+
+    pub fn bitnot_solution(&self) -> Option<Value> {
+        match *self {
+            Value::Integer(a) => Some(Value::Integer(!a)),
+            _ => None,
+        }
+    }
+
+    // shift amounts outside 0..64 trap, are masked to the low 6 bits when wrapping, and are
+    // clamped when saturating. SHL additionally overflows when it shifts out significant bits
+    pub fn shl_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
+        let (a, b) = Self::shift_operands(self, other)?;
+        let checked = shift_amount(b).and_then(|n| {
+            let shifted = a << n;
+            (shifted >> n == a).then_some(shifted)
+        });
+        let saturated = if a == 0 {
+            0
+        } else {
+            match shift_amount(b.max(0)) {
+                Some(n) if (a << n) >> n == a => a << n,
+                _ if a > 0 => i64::MAX,
+                _ => i64::MIN,
+            }
+        };
+        mode.apply(checked, a.wrapping_shl(b as u32), saturated)
+    }
+
+    pub fn shr_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
+        let (a, b) = Self::shift_operands(self, other)?;
+        // shifting a signed value right by 64 or more leaves only the sign bits
+        let saturated = a >> b.clamp(0, 63);
+        mode.apply(shift_amount(b).map(|n| a >> n), a.wrapping_shr(b as u32), saturated)
+    }
+
+    pub fn ushr_solution(&self, other: &Value, mode: OverflowMode) -> Result<Value, VMError> {
+        let (a, b) = Self::shift_operands(self, other)?;
+        let a = a as u64;
+        let saturated = shift_amount(b.max(0)).map_or(0, |n| a >> n);
+        mode.apply(
+            shift_amount(b).map(|n| (a >> n) as i64),
+            a.wrapping_shr(b as u32) as i64,
+            saturated as i64,
+        )
+    }
+
+    fn shift_operands(a: &Value, b: &Value) -> Result<(i64, i64), VMError> {
+        match (a, b) {
+            (&Value::Integer(a), &Value::Integer(b)) => Ok((a, b)),
+            _ => Err(VMError::InvalidOperand),
+        }
+    }
+
+    pub fn str_solution(s: &str) -> Self {
+        Value::Str(Rc::from(s))
+    }
+
+    pub fn array_solution(items: Vec<Value>) -> Self {
+        Value::Array(Rc::from(items))
+    }
+
+    pub fn as_array_solution(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_str_solution(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
             _ => None,
         }
     }
@@ -68,52 +277,107 @@ impl Value {
     pub fn is_truthy_solution(&self) -> bool {
         match self {
             Value::Boolean(b) => *b,
-            Value::Integer(n) => *n != 0
+            Value::Integer(n) => *n != 0,
+            Value::Str(s) => !s.is_empty(),
+            Value::Array(items) => !items.is_empty(),
+            Value::Function(_) | Value::Coroutine(_) | Value::Record(_) | Value::Variant(_) => true,
+            Value::Nil => false,
         }
     }
 
+    // logical operators accept any value and always produce a boolean
+    pub fn not_solution(&self) -> Value {
+        Value::Boolean(!self.is_truthy_solution())
+    }
+
+    pub fn and_solution(&self, other: &Value) -> Value {
+        Value::Boolean(self.is_truthy_solution() && other.is_truthy_solution())
+    }
+
+    pub fn or_solution(&self, other: &Value) -> Value {
+        Value::Boolean(self.is_truthy_solution() || other.is_truthy_solution())
+    }
+
     // cases for greater than, less than, equal to, >=, <=
-    pub fn gt_solution(self, other: Value) -> Option<Value> {
+    pub fn gt_solution(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Boolean(a>b)),
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Boolean(a>b)),
             _ => None,
         }
     }
 
-    pub fn lt_solution(self, other: Value) -> Option<Value> {
+    pub fn lt_solution(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Boolean(a<b)),
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Boolean(a<b)),
             _ => None,
         }
     }
 
-    pub fn gte_solution(self, other: Value) -> Option<Value> {
+    pub fn gte_solution(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Boolean(a>=b)),
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Boolean(a>=b)),
             _ => None,
         }
     }
-    pub fn lte_solution(self, other: Value) -> Option<Value> {
+    pub fn lte_solution(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => Some(Value::Boolean(a<=b)),
+            (&Value::Integer(a), &Value::Integer(b)) => Some(Value::Boolean(a<=b)),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "int",
+            Value::Boolean(_) => "bool",
+            Value::Str(_) => "string",
+            Value::Array(_) => "array",
+            Value::Function(_) => "function",
+            Value::Coroutine(_) => "coroutine",
+            Value::Record(_) => "record",
+            Value::Variant(_) => "enum",
+            Value::Nil => "nil",
+        }
+    }
+
+    // the heap object this value refers to, if any
+    pub fn handle(&self) -> Option<Handle> {
+        match self {
+            Value::Function(handle) | Value::Coroutine(handle) | Value::Record(handle) => Some(*handle),
             _ => None,
         }
     }
 
-    pub fn eq_solution(self, other: Value) -> Option<Value> {
+    // values of different types can't be compared, except that anything can be compared with
+    // nil, which is only equal to itself
+    pub fn eq_solution(&self, other: &Value) -> Option<Value> {
         let result = match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => a == b,
-            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Nil, _) | (_, Value::Nil) => self == other,
+            (&Value::Integer(a), &Value::Integer(b)) => a == b,
+            (&Value::Boolean(a), &Value::Boolean(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Coroutine(a), Value::Coroutine(b)) => a == b,
+            (Value::Record(a), Value::Record(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => items_equal(a, b),
+            (Value::Variant(a), Value::Variant(b)) => variants_equal(a, b),
             _ => return None,
         };
 
         Some(Value::Boolean(result))
     }
 
-    pub fn neq_solution(self, other: Value) -> Option<Value> {
+    pub fn neq_solution(&self, other: &Value) -> Option<Value> {
         let result = match (self, other) {
-            (Value::Integer(a), Value::Integer(b)) => a != b,
-            (Value::Boolean(a), Value::Boolean(b)) => a != b,
+            (Value::Nil, _) | (_, Value::Nil) => self != other,
+            (&Value::Integer(a), &Value::Integer(b)) => a != b,
+            (&Value::Boolean(a), &Value::Boolean(b)) => a != b,
+            (Value::Str(a), Value::Str(b)) => a != b,
+            (Value::Function(a), Value::Function(b)) => a != b,
+            (Value::Coroutine(a), Value::Coroutine(b)) => a != b,
+            (Value::Record(a), Value::Record(b)) => a != b,
+            (Value::Array(a), Value::Array(b)) => !items_equal(a, b),
+            (Value::Variant(a), Value::Variant(b)) => !variants_equal(a, b),
             _ => return None,
         };
 
@@ -121,6 +385,26 @@ impl Value {
     }
 }
 
+// how PRINT_VAL shows a value
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Function(handle) => write!(f, "<fn #{}>", handle.0),
+            Value::Coroutine(handle) => write!(f, "<coroutine #{}>", handle.0),
+            Value::Record(handle) => write!(f, "<record #{}>", handle.0),
+            Value::Variant(variant) => write!(f, "{}", variant),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,13 +420,14 @@ mod tests {
         let a = Value::int_solution(10);
         let b = Value::int_solution(5);
         
-        assert_eq!(a.add_solution(b), Some(Value::Integer(15)));
-        assert_eq!(a.sub_solution(b), Some(Value::Integer(5)));
-        assert_eq!(a.mul_solution(b), Some(Value::Integer(50)));
-        assert_eq!(a.div_solution(b), Some(Value::Integer(2)));
+        let mode = OverflowMode::Trap;
+        assert_eq!(a.add_solution(&b, mode), Ok(Value::Integer(15)));
+        assert_eq!(a.sub_solution(&b, mode), Ok(Value::Integer(5)));
+        assert_eq!(a.mul_solution(&b, mode), Ok(Value::Integer(50)));
+        assert_eq!(a.div_solution(&b, mode), Ok(Value::Integer(2)));
         
         let zero = Value::int_solution(0);
-        assert_eq!(a.div_solution(zero), None); 
+        assert_eq!(a.div_solution(&zero, mode), Err(VMError::DivisionByZero)); 
     }
     
     #[test]
@@ -150,7 +435,197 @@ mod tests {
         let a = Value::int_solution(-10);
         let b = Value::int_solution(5);
         
-        assert_eq!(a.add_solution(b), Some(Value::Integer(-5)));
-        assert_eq!(a.mul_solution(b), Some(Value::Integer(-50)));
+        assert_eq!(a.add_solution(&b, OverflowMode::Trap), Ok(Value::Integer(-5)));
+        assert_eq!(a.mul_solution(&b, OverflowMode::Trap), Ok(Value::Integer(-50)));
+    }
+
+    #[test]
+    fn test_overflow_trap() {
+        let max = Value::int_solution(i64::MAX);
+        let min = Value::int_solution(i64::MIN);
+        let one = Value::int_solution(1);
+        let minus_one = Value::int_solution(-1);
+        let mode = OverflowMode::Trap;
+
+        assert_eq!(max.add_solution(&one, mode), Err(VMError::IntegerOverflow));
+        assert_eq!(min.sub_solution(&one, mode), Err(VMError::IntegerOverflow));
+        assert_eq!(max.mul_solution(&Value::int_solution(2), mode), Err(VMError::IntegerOverflow));
+        assert_eq!(min.div_solution(&minus_one, mode), Err(VMError::IntegerOverflow));
+
+        // right at the boundary is still fine
+        assert_eq!(max.sub_solution(&one, mode), Ok(Value::Integer(i64::MAX - 1)));
+        assert_eq!(min.add_solution(&one, mode), Ok(Value::Integer(i64::MIN + 1)));
+        assert_eq!(max.div_solution(&minus_one, mode), Ok(Value::Integer(-i64::MAX)));
+    }
+
+    #[test]
+    fn test_overflow_wrap() {
+        let max = Value::int_solution(i64::MAX);
+        let min = Value::int_solution(i64::MIN);
+        let one = Value::int_solution(1);
+        let mode = OverflowMode::Wrap;
+
+        assert_eq!(max.add_solution(&one, mode), Ok(Value::Integer(i64::MIN)));
+        assert_eq!(min.sub_solution(&one, mode), Ok(Value::Integer(i64::MAX)));
+        assert_eq!(max.mul_solution(&Value::int_solution(2), mode), Ok(Value::Integer(-2)));
+        assert_eq!(min.div_solution(&Value::int_solution(-1), mode), Ok(Value::Integer(i64::MIN)));
+    }
+
+    #[test]
+    fn test_overflow_saturate() {
+        let max = Value::int_solution(i64::MAX);
+        let min = Value::int_solution(i64::MIN);
+        let one = Value::int_solution(1);
+        let mode = OverflowMode::Saturate;
+
+        assert_eq!(max.add_solution(&one, mode), Ok(Value::Integer(i64::MAX)));
+        assert_eq!(min.sub_solution(&one, mode), Ok(Value::Integer(i64::MIN)));
+        assert_eq!(min.mul_solution(&Value::int_solution(2), mode), Ok(Value::Integer(i64::MIN)));
+        assert_eq!(min.div_solution(&Value::int_solution(-1), mode), Ok(Value::Integer(i64::MAX)));
+    }
+
+    #[test]
+    fn test_division_by_zero_ignores_mode() {
+        let a = Value::int_solution(7);
+        let zero = Value::int_solution(0);
+
+        for mode in [OverflowMode::Trap, OverflowMode::Wrap, OverflowMode::Saturate] {
+            assert_eq!(a.div_solution(&zero, mode), Err(VMError::DivisionByZero));
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_on_booleans_is_invalid() {
+        let t = Value::bool_solution(true);
+        let one = Value::int_solution(1);
+
+        assert_eq!(t.add_solution(&one, OverflowMode::Wrap), Err(VMError::InvalidOperand));
+        assert_eq!(one.div_solution(&t, OverflowMode::Wrap), Err(VMError::InvalidOperand));
+    }
+
+    #[test]
+    fn test_mod_and_neg() {
+        let mode = OverflowMode::Trap;
+        let a = Value::int_solution(-7);
+        let b = Value::int_solution(3);
+
+        assert_eq!(a.mod_solution(&b, mode), Ok(Value::Integer(-1)));
+        assert_eq!(a.mod_solution(&Value::int_solution(0), mode), Err(VMError::DivisionByZero));
+        assert_eq!(a.neg_solution(mode), Ok(Value::Integer(7)));
+
+        let min = Value::int_solution(i64::MIN);
+        let minus_one = Value::int_solution(-1);
+        assert_eq!(min.mod_solution(&minus_one, mode), Err(VMError::IntegerOverflow));
+        assert_eq!(min.mod_solution(&minus_one, OverflowMode::Wrap), Ok(Value::Integer(0)));
+        assert_eq!(min.neg_solution(mode), Err(VMError::IntegerOverflow));
+        assert_eq!(min.neg_solution(OverflowMode::Wrap), Ok(Value::Integer(i64::MIN)));
+        assert_eq!(min.neg_solution(OverflowMode::Saturate), Ok(Value::Integer(i64::MAX)));
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let a = Value::int_solution(0b1100);
+        let b = Value::int_solution(0b1010);
+
+        assert_eq!(a.bitand_solution(&b), Some(Value::Integer(0b1000)));
+        assert_eq!(a.bitor_solution(&b), Some(Value::Integer(0b1110)));
+        assert_eq!(a.bitxor_solution(&b), Some(Value::Integer(0b0110)));
+        assert_eq!(a.bitnot_solution(), Some(Value::Integer(!0b1100)));
+        assert_eq!(Value::bool_solution(true).bitnot_solution(), None);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mode = OverflowMode::Trap;
+        let minus_eight = Value::int_solution(-8);
+        let one = Value::int_solution(1);
+
+        assert_eq!(one.shl_solution(&Value::int_solution(4), mode), Ok(Value::Integer(16)));
+        assert_eq!(minus_eight.shr_solution(&one, mode), Ok(Value::Integer(-4)));
+        assert_eq!(
+            minus_eight.ushr_solution(&one, mode),
+            Ok(Value::Integer(((-8i64 as u64) >> 1) as i64))
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_shift_overflow() {
+        let one = Value::int_solution(1);
+        let minus_one = Value::int_solution(-1);
+        let sixty_four = Value::int_solution(64);
+        let sixty_three = Value::int_solution(63);
+
+        // out of range amounts
+        assert_eq!(one.shl_solution(&sixty_four, OverflowMode::Trap), Err(VMError::IntegerOverflow));
+        assert_eq!(one.shr_solution(&minus_one, OverflowMode::Trap), Err(VMError::IntegerOverflow));
+        assert_eq!(one.shl_solution(&sixty_four, OverflowMode::Wrap), Ok(Value::Integer(1)));
+        assert_eq!(minus_one.shr_solution(&sixty_four, OverflowMode::Saturate), Ok(Value::Integer(-1)));
+        assert_eq!(minus_one.ushr_solution(&sixty_four, OverflowMode::Saturate), Ok(Value::Integer(0)));
+
+        // 1 << 63 flips the sign bit
+        assert_eq!(one.shl_solution(&sixty_three, OverflowMode::Trap), Err(VMError::IntegerOverflow));
+        assert_eq!(one.shl_solution(&sixty_three, OverflowMode::Wrap), Ok(Value::Integer(i64::MIN)));
+        assert_eq!(one.shl_solution(&sixty_three, OverflowMode::Saturate), Ok(Value::Integer(i64::MAX)));
+        assert_eq!(minus_one.shl_solution(&sixty_three, OverflowMode::Trap), Ok(Value::Integer(i64::MIN)));
+        assert_eq!(
+            Value::int_solution(-2).shl_solution(&sixty_four, OverflowMode::Saturate),
+            Ok(Value::Integer(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let t = Value::bool_solution(true);
+        let f = Value::bool_solution(false);
+        let zero = Value::int_solution(0);
+        let seven = Value::int_solution(7);
+
+        assert_eq!(t.not_solution(), Value::Boolean(false));
+        assert_eq!(zero.not_solution(), Value::Boolean(true));
+        assert_eq!(t.and_solution(&seven), Value::Boolean(true));
+        assert_eq!(t.and_solution(&zero), Value::Boolean(false));
+        assert_eq!(f.or_solution(&seven), Value::Boolean(true));
+        assert_eq!(f.or_solution(&zero), Value::Boolean(false));
+    }
+
+    #[test]
+    fn test_nil() {
+        let nil = Value::Nil;
+        let zero = Value::int_solution(0);
+
+        assert!(!nil.is_truthy_solution());
+        assert_eq!(nil.not_solution(), Value::Boolean(true));
+        assert_eq!(nil.eq_solution(&Value::Nil), Some(Value::Boolean(true)));
+        assert_eq!(nil.eq_solution(&zero), Some(Value::Boolean(false)));
+        assert_eq!(zero.neq_solution(&nil), Some(Value::Boolean(true)));
+        // other mixed types still can't be compared
+        assert_eq!(zero.eq_solution(&Value::bool_solution(false)), None);
+        assert_eq!(nil.add_solution(&zero, OverflowMode::Wrap), Err(VMError::InvalidOperand));
+
+        assert_eq!(nil.to_string(), "nil");
+        assert_eq!(nil.type_name(), "nil");
+        assert_eq!(Value::str_solution("").type_name(), "string");
+        assert!(TYPE_NAMES.contains(&Value::Function(Handle(0, 0)).type_name()));
+    }
+
+    #[test]
+    fn test_variant_equality() {
+        use crate::variant::{EnumType, Variant};
+
+        let option = Rc::new(EnumType::new("Option", &[("None", 0), ("Some", 1)]));
+        let variant = |tag: usize, payload: Vec<Value>| {
+            Value::Variant(Rc::new(Variant { kind: option.clone(), tag, payload }))
+        };
+        let none = variant(0, vec![]);
+        let some = variant(1, vec![Value::int_solution(1)]);
+
+        // compared by contents, not by identity
+        assert_eq!(none.eq_solution(&variant(0, vec![])), Some(Value::Boolean(true)));
+        assert_eq!(some.eq_solution(&variant(1, vec![Value::int_solution(1)])), Some(Value::Boolean(true)));
+        assert_eq!(some.neq_solution(&variant(1, vec![Value::bool_solution(true)])), Some(Value::Boolean(true)));
+        assert_eq!(some.eq_solution(&none), Some(Value::Boolean(false)));
+        assert_eq!(some.eq_solution(&Value::int_solution(1)), None);
+        assert_eq!(some.type_name(), "enum");
+        assert_eq!(some.to_string(), "Some(1)");
+    }
+}
@@ -0,0 +1,90 @@
+// Tagged unions. A module declares an enum as a list of variants, each carrying a fixed number of
+// payload values (`.enum Option None Some:1` in assembly). A value of the enum is one variant,
+// identified by its tag (its position in the declaration), plus that variant's payload.
+//
+// Variants are immutable, so like strings they're held inline and shared between copies rather
+// than living on the heap, and two of them are equal when their variant and payload are.
+// A `match` lowers to VARIANT_TAG followed by TABLE_SWITCH, then PAYLOAD_AT or UNPACK per arm.
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::value::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariantType {
+    pub name: String,
+    // how many payload values the variant carries
+    pub arity: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumType {
+    pub name: String,
+    // in declaration order, a variant's position is its tag
+    pub variants: Vec<VariantType>,
+}
+
+impl EnumType {
+    pub fn new(name: &str, variants: &[(&str, usize)]) -> Self {
+        EnumType {
+            name: name.to_string(),
+            variants: variants
+                .iter()
+                .map(|(name, arity)| VariantType { name: name.to_string(), arity: *arity })
+                .collect(),
+        }
+    }
+
+    pub fn tag(&self, variant: &str) -> Option<usize> {
+        self.variants.iter().position(|declared| declared.name == variant)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub kind: Rc<EnumType>,
+    pub tag: usize,
+    pub payload: Vec<Value>,
+}
+
+impl Variant {
+    pub fn name(&self) -> &str {
+        &self.kind.variants[self.tag].name
+    }
+
+    // `Enum.Variant`, how NEW_VARIANT and IS_VARIANT name it
+    pub fn full_name(&self) -> String {
+        format!("{}.{}", self.kind.name, self.name())
+    }
+}
+
+// `None`, `Some(5)`, `Rect(2, 3)`
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if self.payload.is_empty() {
+            return Ok(());
+        }
+        let payload: Vec<String> = self.payload.iter().map(|value| value.to_string()).collect();
+        write!(f, "({})", payload.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags_and_display() {
+        let option = Rc::new(EnumType::new("Option", &[("None", 0), ("Some", 1)]));
+        assert_eq!(option.tag("Some"), Some(1));
+        assert_eq!(option.tag("Other"), None);
+
+        let some = Variant { kind: option.clone(), tag: 1, payload: vec![Value::Integer(5)] };
+        let none = Variant { kind: option, tag: 0, payload: vec![] };
+        assert_eq!(some.to_string(), "Some(5)");
+        assert_eq!(none.to_string(), "None");
+        assert_eq!(some.full_name(), "Option.Some");
+    }
+}
@@ -0,0 +1,343 @@
+// Static checks on bytecode before it is run. Walks every reachable path and makes sure each
+// instruction decodes, every jump lands on an instruction boundary and no instruction can pop
+// more values than the path has pushed, that IS_TYPE names a type that exists and that format
+// strings parse.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::error::VMError;
+use crate::instruction::{decode_all, Instruction, Operand};
+use crate::opcode::OpCode;
+use crate::format::FormatString;
+use crate::value::TYPE_NAMES;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    // the bytes at this offset don't form an instruction
+    Decode { offset: usize, error: VMError },
+    // a jump or call whose target is not the start of an instruction
+    BadTarget { offset: usize, target: usize },
+    // the instruction needs more values than the stack is guaranteed to hold
+    StackUnderflow { offset: usize, needed: usize, available: usize },
+    // two paths reach the same instruction with different stack depths
+    InconsistentStack { offset: usize, expected: usize, found: usize },
+    // execution can run past the last instruction
+    FallsOffEnd { offset: usize },
+    // IS_TYPE with a name no value has, so it could never be true
+    UnknownType { offset: usize, name: String },
+    // a FORMAT or PRINT_FORMAT string that doesn't parse
+    BadFormat { offset: usize, message: String },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Decode { offset, error } => {
+                write!(f, "{:04}: {}", offset, error)
+            }
+            VerifyError::BadTarget { offset, target } => {
+                write!(f, "{:04}: target {} is not an instruction boundary", offset, target)
+            }
+            VerifyError::StackUnderflow { offset, needed, available } => {
+                write!(f, "{:04}: needs {} stack value(s) but only {} available", offset, needed, available)
+            }
+            VerifyError::InconsistentStack { offset, expected, found } => {
+                write!(f, "{:04}: stack depth {} here conflicts with {} from another path", offset, found, expected)
+            }
+            VerifyError::FallsOffEnd { offset } => {
+                write!(f, "{:04}: execution runs past the end of the bytecode", offset)
+            }
+            VerifyError::UnknownType { offset, name } => {
+                write!(f, "{:04}: unknown type '{}'", offset, name)
+            }
+            VerifyError::BadFormat { offset, message } => {
+                write!(f, "{:04}: bad format string: {}", offset, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// (values popped, values pushed). None when it can't be known statically, which is the case for
+// calls since a function may consume its arguments and leave any number of results
+pub fn stack_effect(instruction: &Instruction) -> Option<(usize, usize)> {
+    let effect = match instruction.opcode {
+        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod |
+        OpCode::BitAnd | OpCode::BitOr | OpCode::BitXor |
+        OpCode::Shl | OpCode::Shr | OpCode::Ushr |
+        OpCode::Gt | OpCode::Lt | OpCode::Gte | OpCode::Lte | OpCode::Eq | OpCode::Neq |
+        OpCode::And | OpCode::Or => (2, 1),
+        OpCode::Neg | OpCode::BitNot | OpCode::Not => (1, 1),
+        OpCode::NewCoroutine | OpCode::Yield | OpCode::CoroutineStatus => (1, 1),
+        OpCode::TypeOf | OpCode::IsType => (1, 1),
+        OpCode::GetField | OpCode::GetFieldAt => (1, 1),
+        OpCode::VariantTag | OpCode::IsVariant | OpCode::PayloadAt => (1, 1),
+        OpCode::SetField | OpCode::SetFieldAt => (2, 0),
+        OpCode::Resume => (2, 1),
+
+        OpCode::Push | OpCode::PushNil | OpCode::LoadVar | OpCode::LoadLocal | OpCode::MakeClosure => (0, 1),
+        OpCode::StoreVar | OpCode::StoreLocal => (1, 0),
+
+        OpCode::Pop => (1, 0),
+        OpCode::Dup => (1, 2),
+        OpCode::Swap => (2, 2),
+        OpCode::Over => (2, 3),
+        OpCode::Rot => (3, 3),
+        OpCode::Pick => match instruction.operand {
+            Operand::Byte(n) => (n as usize + 1, n as usize + 2),
+            _ => (1, 2),
+        },
+        OpCode::NewArray => match instruction.operand {
+            Operand::Byte(n) => (n as usize, 1),
+            _ => return None,
+        },
+
+        OpCode::Jump => (0, 0),
+        OpCode::JumpIfFalse | OpCode::JumpIfTrue => (1, 0),
+        OpCode::TableSwitch | OpCode::LookupSwitch => (1, 0),
+        OpCode::JumpIfFalseKeep | OpCode::JumpIfTrueKeep => (1, 1),
+        // the arity of a native lives in the host's registry and the size of a record or a
+        // variant's payload in the module, not in the bytecode
+        OpCode::Call | OpCode::TailCall | OpCode::CallNative | OpCode::CallValue => return None,
+        OpCode::NewRecord | OpCode::NewVariant | OpCode::Unpack => return None,
+        OpCode::Return => (0, 0),
+
+        OpCode::Format | OpCode::PrintFormat => {
+            let arguments = match &instruction.operand {
+                Operand::Name(format) => FormatString::parse(format).ok()?.arguments(),
+                _ => return None,
+            };
+            (arguments, if instruction.opcode == OpCode::Format { 1 } else { 0 })
+        }
+        OpCode::Print | OpCode::PrintLn => (0, 0),
+        OpCode::PrintVal => (1, 0),
+        OpCode::ReadInt | OpCode::ReadLine | OpCode::ReadByte | OpCode::Eof => (0, 1),
+        OpCode::Halt => (0, 0),
+    };
+    Some(effect)
+}
+
+// where control can go after this instruction: (fallthrough?, jump targets)
+fn successors(instruction: &Instruction) -> (bool, Vec<usize>) {
+    match instruction.opcode {
+        OpCode::Jump | OpCode::TableSwitch | OpCode::LookupSwitch => (false, instruction.targets()),
+        OpCode::Return | OpCode::Halt => (false, vec![]),
+        // the callee is analysed on its own, control comes back to the next instruction
+        OpCode::Call | OpCode::MakeClosure => (true, vec![]),
+        // the callee returns straight to our caller
+        OpCode::TailCall => (false, vec![]),
+        _ => (true, instruction.targets()),
+    }
+}
+
+pub fn verify(bytecode: &[u8]) -> Result<(), VerifyError> {
+    let instructions = decode_all_with_offset(bytecode)?;
+    let index_of: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, (offset, _))| (*offset, index))
+        .collect();
+
+    // every address operand has to land on an instruction
+    let mut function_entries = vec![];
+    for (offset, instruction) in &instructions {
+        match (instruction.opcode, &instruction.operand) {
+            (OpCode::IsType, Operand::Name(name)) if !TYPE_NAMES.contains(&name.as_str()) => {
+                return Err(VerifyError::UnknownType { offset: *offset, name: name.clone() });
+            }
+            (OpCode::Format | OpCode::PrintFormat, Operand::Name(format)) => {
+                if let Err(message) = FormatString::parse(format) {
+                    return Err(VerifyError::BadFormat { offset: *offset, message });
+                }
+            }
+            _ => {}
+        }
+        for target in instruction.targets() {
+            if !index_of.contains_key(&target) {
+                return Err(VerifyError::BadTarget { offset: *offset, target });
+            }
+            if matches!(instruction.opcode, OpCode::Call | OpCode::TailCall | OpCode::MakeClosure) {
+                function_entries.push(target);
+            }
+        }
+    }
+
+    if instructions.is_empty() {
+        return Ok(());
+    }
+
+    // depth: None means unknown, which is the state at a function entry or after a call
+    let mut depths: BTreeMap<usize, Option<usize>> = BTreeMap::new();
+    let mut worklist: Vec<(usize, Option<usize>)> = vec![(0, Some(0))];
+    worklist.extend(function_entries.into_iter().map(|entry| (index_of[&entry], None)));
+
+    while let Some((index, depth)) = worklist.pop() {
+        let (offset, instruction) = &instructions[index];
+
+        match depths.get(&index) {
+            Some(&Some(expected)) => {
+                if let Some(found) = depth {
+                    if found != expected {
+                        return Err(VerifyError::InconsistentStack { offset: *offset, expected, found });
+                    }
+                }
+                continue;
+            }
+            // a known depth refines an unknown one, so the path is walked again
+            Some(None) if depth.is_none() => continue,
+            _ => {}
+        }
+        depths.insert(index, depth);
+
+        let after = match (stack_effect(instruction), depth) {
+            (Some((pops, pushes)), Some(available)) => {
+                if available < pops {
+                    return Err(VerifyError::StackUnderflow { offset: *offset, needed: pops, available });
+                }
+                Some(available - pops + pushes)
+            }
+            _ => None,
+        };
+
+        let (falls_through, targets) = successors(instruction);
+        for target in targets {
+            worklist.push((index_of[&target], after));
+        }
+        if falls_through {
+            if index + 1 >= instructions.len() {
+                return Err(VerifyError::FallsOffEnd { offset: *offset });
+            }
+            worklist.push((index + 1, after));
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_all_with_offset(bytecode: &[u8]) -> Result<Vec<(usize, Instruction)>, VerifyError> {
+    decode_all(bytecode).map_err(|error| {
+        // find where decoding stopped so the error points at the right instruction
+        let mut offset = 0;
+        while let Ok(instruction) = Instruction::decode(bytecode, offset) {
+            offset += instruction.encoded_len();
+        }
+        VerifyError::Decode { offset, error }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn check(source: &str) -> Result<(), VerifyError> {
+        verify(&assemble(source).unwrap())
+    }
+
+    #[test]
+    fn test_valid_programs() {
+        assert_eq!(check("PUSH 1\nPUSH 2\nSWAP\nOVER\nROT\nPOP\nPOP\nPOP\nHALT"), Ok(()));
+        assert_eq!(check("PUSH 1\nDUP\nPICK 1\nADD\nADD\nPRINT_VAL\nHALT"), Ok(()));
+        assert_eq!(
+            check("PUSH 0\nloop:\nDUP\nPUSH 10\nLT\nJUMP_IF_FALSE end\nPUSH 1\nADD\nJUMP loop\nend:\nHALT"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_underflow() {
+        assert_eq!(
+            check("PUSH 1\nSWAP\nHALT"),
+            Err(VerifyError::StackUnderflow { offset: 9, needed: 2, available: 1 })
+        );
+        assert!(matches!(check("PUSH 1\nPICK 1\nHALT"), Err(VerifyError::StackUnderflow { .. })));
+        assert!(matches!(check("PUSH 1\nPUSH 2\nROT\nHALT"), Err(VerifyError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn test_inconsistent_depth_at_join() {
+        // the loop pushes one extra value every time around
+        let result = check("loop:\nPUSH 1\nPUSH 1\nJUMP_IF_TRUE loop\nHALT");
+        assert!(matches!(result, Err(VerifyError::InconsistentStack { offset: 0, .. })));
+    }
+
+    #[test]
+    fn test_bad_target_and_fall_off() {
+        assert_eq!(check("JUMP 3\nHALT"), Err(VerifyError::BadTarget { offset: 0, target: 3 }));
+        assert_eq!(check("PUSH 1\nPOP"), Err(VerifyError::FallsOffEnd { offset: 9 }));
+    }
+
+    #[test]
+    fn test_switches() {
+        // every case is walked, a case that leaves the stack uneven is caught where they join
+        let source = "PUSH 7\nPUSH 1\nTABLE_SWITCH 0 a a b\na:\nPRINT_VAL\nHALT\nb:\nJUMP a";
+        assert_eq!(check(source), Ok(()));
+        let source = "PUSH 7\nPUSH 1\nLOOKUP_SWITCH a 5:b\na:\nPRINT_VAL\nHALT\nb:\nPUSH 8\nJUMP a";
+        assert!(matches!(check(source), Err(VerifyError::InconsistentStack { .. })));
+
+        // a case in the middle of an instruction
+        assert_eq!(
+            check("PUSH 1\nTABLE_SWITCH 0 0 3\nHALT"),
+            Err(VerifyError::BadTarget { offset: 9, target: 3 })
+        );
+        assert_eq!(
+            check("PUSH 1\nLOOKUP_SWITCH 0 1:12\nHALT"),
+            Err(VerifyError::BadTarget { offset: 9, target: 12 })
+        );
+    }
+
+    #[test]
+    fn test_functions_are_checked_from_unknown_depth() {
+        let source = "
+            PUSH 4
+            CALL square
+            PRINT_VAL
+            HALT
+        square:
+            STORE_LOCAL \"n\"
+            LOAD_LOCAL \"n\"
+            LOAD_LOCAL \"n\"
+            MUL
+            RETURN
+        ";
+        assert_eq!(check(source), Ok(()));
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let mut bytecode = assemble("PUSH 1").unwrap();
+        bytecode.push(250);
+        assert_eq!(
+            verify(&bytecode),
+            Err(VerifyError::Decode { offset: 9, error: VMError::InvalidOpCode(250) })
+        );
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        assert_eq!(check("PUSH_NIL\nIS_TYPE \"nil\"\nHALT"), Ok(()));
+        let error = check("PUSH 1\nIS_TYPE \"integer\"\nHALT").unwrap_err();
+        assert_eq!(error, VerifyError::UnknownType { offset: 9, name: "integer".to_string() });
+        assert_eq!(error.to_string(), "0009: unknown type 'integer'");
+    }
+
+    #[test]
+    fn test_format_strings() {
+        // the placeholders say how many values are taken
+        assert_eq!(check("PUSH 1\nPUSH 2\nFORMAT \"{} {:x}\"\nPRINT_VAL\nHALT"), Ok(()));
+        assert_eq!(
+            check("PUSH 1\nPRINT_FORMAT \"{} {}\"\nHALT"),
+            Err(VerifyError::StackUnderflow { offset: 9, needed: 2, available: 1 })
+        );
+        assert_eq!(
+            check("PRINT_FORMAT \"{\"\nHALT"),
+            Err(VerifyError::BadFormat { offset: 0, message: "unclosed '{'".to_string() })
+        );
+        assert_eq!(
+            check("PUSH 1\nPRINT_FORMAT \"{:99999999}\"\nHALT"),
+            Err(VerifyError::BadFormat { offset: 9, message: "99999999 is larger than 65535".to_string() })
+        );
+    }
+}
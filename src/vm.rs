@@ -1,9 +1,20 @@
 use crate::opcode::OpCode;
-use crate::value::Value;
+use crate::value::{Closure, OverflowMode, Value};
 use crate::error::VMError;
 use crate::memory::Memory;
 use crate::callframe::CallFrame;
-use std::io::{self, Write};
+use crate::native::{NativeContext, NativeFunction};
+use crate::snapshot::{hash_bytecode, Snapshot};
+use crate::module::{Export, Module};
+use crate::heap::{trace_value, GcConfig, GcStats, Handle, Heap, HeapObject};
+use crate::coroutine::{Coroutine, CoroutineStatus, ExecutionContext};
+use crate::record::{Record, RecordType};
+use crate::variant::{EnumType, Variant};
+use crate::format::FormatString;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+use std::io::{self, BufRead, BufReader, Write};
 
 pub struct VM {
     // intermediate values are stored in this stack
@@ -23,6 +34,55 @@ pub struct VM {
 
     //memory for storing variables
     memory: Memory,
+
+    // what ADD/SUB/MUL/DIV do when a result doesn't fit in an i64
+    overflow_mode: OverflowMode,
+
+    // where PRINT, PRINT_VAL and PRINT_LN write to, stdout by default
+    output: Box<dyn Write>,
+
+    // where the READ_* opcodes take their data from, stdin by default
+    input: Box<dyn BufRead>,
+
+    // host functions reachable through CALL_NATIVE, looked up by name
+    natives: HashMap<String, NativeFunction>,
+
+    // named functions of the loaded module
+    exports: HashMap<String, Export>,
+
+    // record types the loaded module declares, by name
+    records: HashMap<String, Rc<RecordType>>,
+
+    // enums the loaded module declares, by name
+    enums: HashMap<String, Rc<EnumType>>,
+
+    // instructions a single run may execute before it's treated as an infinite loop
+    max_instructions: Option<usize>,
+
+    // closures and any other values that can refer back to other values
+    heap: Heap,
+
+    // the coroutines currently running, innermost last, each with the context of whoever
+    // resumed it. YIELD and the coroutine's final RETURN switch back to that context
+    resumers: Vec<(Handle, ExecutionContext)>,
+
+    // YIELD can't go further out than this many resumers, so a coroutine can't be suspended
+    // from inside a function the host called into (call_function from a native)
+    yield_floor: usize,
+
+    // where the case tables of LOOKUP_SWITCH instructions that have run start. Each table's keys
+    // are checked to be in order the first time it runs, so the search can be binary after that
+    sorted_lookups: HashSet<usize>,
+
+    // the stacks of everyone waiting on call_function, innermost last. The function runs on a
+    // stack of its own, so it can't pop what its caller left there
+    callers: Vec<Vec<Value>>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
@@ -34,9 +94,65 @@ impl VM {
             ip: 0,
             running: false,
             memory: Memory::new_solution(),
+            overflow_mode: OverflowMode::default(),
+            output: Box::new(io::stdout()),
+            input: Box::new(BufReader::new(io::stdin())),
+            natives: HashMap::new(),
+            exports: HashMap::new(),
+            records: HashMap::new(),
+            enums: HashMap::new(),
+            max_instructions: Some(10_000),
+            heap: Heap::new(GcConfig::default()),
+            resumers: Vec::new(),
+            yield_floor: 0,
+            sorted_lookups: HashSet::new(),
+            callers: Vec::new(),
         }
     }
 
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        let mut vm = VM::new();
+        vm.set_output(output);
+        vm
+    }
+
+    // like the overflow mode, the sink stays in place when new bytecode is loaded
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Box::new(input);
+    }
+
+    // registering a name twice replaces the earlier function
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut NativeContext, &[Value]) -> Result<Value, VMError> + 'static,
+    {
+        let native = NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Rc::new(function),
+        };
+        self.natives.insert(name.to_string(), native);
+    }
+
+    pub fn with_overflow_mode(mode: OverflowMode) -> Self {
+        let mut vm = VM::new();
+        vm.overflow_mode = mode;
+        vm
+    }
+
+    // the mode is a property of the VM, so it survives loading new bytecode
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow_mode = mode;
+    }
+
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow_mode
+    }
+
     //load into stack
     pub fn load_bytecode_solution(&mut self, code: Vec<u8>) {
         self.stack.clear();
@@ -45,6 +161,33 @@ impl VM {
         self.ip = 0;
         self.running = false;
         self.memory.clear();
+        self.exports.clear();
+        self.records.clear();
+        self.enums.clear();
+        self.heap = Heap::new(self.heap.config());
+        self.resumers.clear();
+        self.yield_floor = 0;
+        self.sorted_lookups.clear();
+        self.callers.clear();
+    }
+
+    // load the module's code, make its exports callable by name and its record types and enums
+    // buildable
+    pub fn load_module(&mut self, module: Module) {
+        self.load_bytecode_solution(module.code);
+        for export in module.exports {
+            self.exports.insert(export.name.clone(), export);
+        }
+        for record in module.records {
+            self.records.insert(record.name.clone(), Rc::new(record));
+        }
+        for declared in module.enums {
+            self.enums.insert(declared.name.clone(), Rc::new(declared));
+        }
+    }
+
+    pub fn exports(&self) -> impl Iterator<Item = &Export> {
+        self.exports.values()
     }
 
     fn push(&mut self, value: Value) {
@@ -58,6 +201,15 @@ impl VM {
         self.stack.pop().ok_or(VMError::StackUnderflow)
     }
     
+    // look at the value `depth` slots below the top without popping it
+    fn peek_solution(&self, depth: usize) -> Result<Value, VMError> {
+        self.stack
+            .len()
+            .checked_sub(depth + 1)
+            .map(|index| self.stack[index].clone())
+            .ok_or(VMError::StackUnderflow)
+    }
+
     // Read the next byte from bytecode
     fn read_byte(&mut self) -> Result<u8, VMError> {
 
@@ -73,6 +225,18 @@ impl VM {
         Ok(byte)
     }
 
+    // reads a format string operand, pops a value per placeholder and renders them
+    fn format_solution(&mut self) -> Result<String, VMError> {
+        let format = self.read_string_solution()?;
+        let format = FormatString::parse(&format).map_err(VMError::InvalidFormat)?;
+        let count = format.arguments();
+        if self.stack.len() < count {
+            return Err(VMError::StackUnderflow);
+        }
+        let args = self.stack.split_off(self.stack.len() - count);
+        format.render(&args, |value| self.display_value(value)).map_err(VMError::InvalidFormat)
+    }
+
     fn read_string_solution(&mut self) -> Result<String, VMError> {
 
         // reading the length and that many bytes
@@ -88,20 +252,92 @@ impl VM {
 
     fn read_i64_solution(&mut self) -> Result<i64, VMError> {
         let mut bytes = [0u8; 8];
-        for i in 0..8 {
-            bytes[i] = self.read_byte()?;
+        for byte in bytes.iter_mut() {
+            *byte = self.read_byte()?;
         }
         Ok(i64::from_le_bytes(bytes))
     }
 
     fn read_usize_solution(&mut self) -> Result<usize, VMError> {
         let mut bytes = [0u8; 8];
-        for i in 0..8 {
-            bytes[i] = self.read_byte()?;
+        for byte in bytes.iter_mut() {
+            *byte = self.read_byte()?;
         }
         Ok(usize::from_le_bytes(bytes))
     }
 
+    fn read_u32_solution(&mut self) -> Result<u32, VMError> {
+        let mut bytes = [0u8; 4];
+        for byte in bytes.iter_mut() {
+            *byte = self.read_byte()?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    // the 8 bytes at `offset` without moving the ip, for looking things up in a switch's table
+    fn word_at(&self, offset: usize) -> Result<[u8; 8], VMError> {
+        let bytes = self.bytecode
+            .get(offset..offset.saturating_add(8))
+            .ok_or(VMError::OutOfBounds)?;
+        let mut word = [0u8; 8];
+        word.copy_from_slice(bytes);
+        Ok(word)
+    }
+
+    pub(crate) fn write_output(&mut self, text: &str) -> Result<(), VMError> {
+        self.output.write_all(text.as_bytes())?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn next_input_byte(&mut self) -> Result<Option<u8>, VMError> {
+        Ok(self.input.fill_buf()?.first().copied())
+    }
+
+    // skips leading whitespace, reads one token and then eats whitespace up to the end of the
+    // line, so a program reading one number per line sees EOF right after the last one
+    fn read_int_input_solution(&mut self) -> Result<i64, VMError> {
+        while let Some(byte) = self.next_input_byte()? {
+            if !byte.is_ascii_whitespace() {
+                break;
+            }
+            self.input.consume(1);
+        }
+
+        let mut token = Vec::new();
+        while let Some(byte) = self.next_input_byte()? {
+            if byte.is_ascii_whitespace() {
+                break;
+            }
+            token.push(byte);
+            self.input.consume(1);
+        }
+        if token.is_empty() {
+            return Err(VMError::EndOfInput);
+        }
+
+        while let Some(byte) = self.next_input_byte()? {
+            if !byte.is_ascii_whitespace() {
+                break;
+            }
+            self.input.consume(1);
+            if byte == b'\n' {
+                break;
+            }
+        }
+
+        let text = String::from_utf8_lossy(&token).into_owned();
+        text.parse::<i64>().map_err(|_| VMError::InvalidInput(text))
+    }
+
+    fn jump_solution(&mut self, address: usize) -> Result<(), VMError> {
+        if address >= self.bytecode.len() {
+            return Err(VMError::OutOfBounds);
+        }
+        self.ip = address;
+        Ok(())
+    }
+
     fn current_frame_mut(&mut self) -> Result<&mut CallFrame, VMError> {
         self.call_stack.last_mut().ok_or(VMError::StackUnderflow)
     }
@@ -111,11 +347,22 @@ impl VM {
     }
 
     fn print_stack_trace(&self) {
-        eprintln!("\n=== Call Stack Trace ===");
+        eprint!("{}", self.stack_trace());
+    }
+
+    // the frames innermost first. A frame that took over from others through tail calls says
+    // how many, those calls are gone from the call stack
+    pub fn stack_trace(&self) -> String {
+        let mut trace = String::from("\n=== Call Stack Trace ===\n");
         for (i, frame) in self.call_stack.iter().enumerate().rev() {
-            eprintln!("  #{} at IP {}", i, frame.return_address());
+            trace += &format!("  #{} at IP {}", i, frame.return_address());
+            if frame.elided() > 0 {
+                trace += &format!(" ({} tail call frame(s) elided)", frame.elided());
+            }
+            trace += "\n";
         }
-        eprintln!("  Current IP: {}", self.ip); 
+        trace += &format!("  Current IP: {}\n", self.ip);
+        trace
     }
     
     // Execute a single instruction
@@ -123,7 +370,6 @@ impl VM {
         // 1. Read the next byte (this is the opcode)
         // 2. Convert it to an OpCode using OpCode::from_u8
         // 3. Match on the opcode and execute it:
-        let start_ip = self.ip;
         let byte = self.read_byte()?;
         let opcode = OpCode::convert_from_u8(byte).ok_or(VMError::InvalidOpCode(byte))?;
 
@@ -132,34 +378,116 @@ impl VM {
                 let b  = self.pop()?;
                 let a  = self.pop()?;
 
-                let res = a.add_solution(b).ok_or(VMError::InvalidOperand)?;
+                let res = a.add_solution(&b, self.overflow_mode)?;
                 self.push(res);
             }
             OpCode::Sub => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.sub_solution(b)
-                    .ok_or(VMError::InvalidOperand)?;
+                let result = a.sub_solution(&b, self.overflow_mode)?;
                 self.push(result);
             }
             OpCode::Mul => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.mul_solution(b)
-                    .ok_or(VMError::InvalidOperand)?;
+                let result = a.mul_solution(&b, self.overflow_mode)?;
                 self.push(result);
             }
             OpCode::Div => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.div_solution(b)
-                    .ok_or(VMError::DivisionByZero)?;
+                let result = a.div_solution(&b, self.overflow_mode)?;
+                self.push(result);
+            }
+            OpCode::Mod => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.mod_solution(&b, self.overflow_mode)?;
+                self.push(result);
+            }
+            OpCode::Neg => {
+                let a = self.pop()?;
+                let result = a.neg_solution(self.overflow_mode)?;
+                self.push(result);
+            }
+            OpCode::BitAnd => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.bitand_solution(&b)
+                    .ok_or(VMError::InvalidOperand)?;
+                self.push(result);
+            }
+            OpCode::BitOr => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.bitor_solution(&b)
+                    .ok_or(VMError::InvalidOperand)?;
+                self.push(result);
+            }
+            OpCode::BitXor => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.bitxor_solution(&b)
+                    .ok_or(VMError::InvalidOperand)?;
+                self.push(result);
+            }
+            OpCode::BitNot => {
+                let a = self.pop()?;
+                let result = a.bitnot_solution()
+                    .ok_or(VMError::InvalidOperand)?;
+                self.push(result);
+            }
+            OpCode::Shl => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.shl_solution(&b, self.overflow_mode)?;
+                self.push(result);
+            }
+            OpCode::Shr => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.shr_solution(&b, self.overflow_mode)?;
+                self.push(result);
+            }
+            OpCode::Ushr => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let result = a.ushr_solution(&b, self.overflow_mode)?;
                 self.push(result);
             }
             OpCode::Push => {
                 // Read the next byte as the value to push
                 let value_byte = self.read_i64_solution()?;
-                self.push(Value::int_solution(value_byte as i64));
+                self.push(Value::int_solution(value_byte));
+            }
+            OpCode::Pop => {
+                self.pop()?;
+            }
+            OpCode::Dup => {
+                let a = self.peek_solution(0)?;
+                self.push(a);
+            }
+            OpCode::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b);
+                self.push(a);
+            }
+            OpCode::Over => {
+                let a = self.peek_solution(1)?;
+                self.push(a);
+            }
+            OpCode::Rot => {
+                if self.stack.len() < 3 {
+                    return Err(VMError::StackUnderflow);
+                }
+                let third = self.stack.len() - 3;
+                self.stack[third..].rotate_left(1);
+            }
+            OpCode::Pick => {
+                let depth = self.read_byte()? as usize;
+                let a = self.peek_solution(depth)?;
+                self.push(a);
             }
             OpCode::StoreVar => {
                 let name = self.read_string_solution()?;
@@ -174,53 +502,78 @@ impl VM {
             OpCode::Gt => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.gt_solution(b)
+                let result = a.gt_solution(&b)
                     .ok_or(VMError::InvalidOperand)?;
                 self.push(result);
             }
             OpCode::Lt => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.lt_solution(b)
+                let result = a.lt_solution(&b)
                     .ok_or(VMError::InvalidOperand)?;
                 self.push(result);
             }
             OpCode::Gte => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.gte_solution(b)
+                let result = a.gte_solution(&b)
                     .ok_or(VMError::InvalidOperand)?;
                 self.push(result);
             }
             OpCode::Lte => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.lte_solution(b)
+                let result = a.lte_solution(&b)
                     .ok_or(VMError::InvalidOperand)?;
                 self.push(result);
             }
             OpCode::Eq => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.eq_solution(b)
+                let result = a.eq_solution(&b)
                     .ok_or(VMError::InvalidOperand)?;
                 self.push(result);
             }
             OpCode::Neq => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                let result = a.neq_solution(b)
+                let result = a.neq_solution(&b)
                     .ok_or(VMError::InvalidOperand)?;
                 self.push(result);
             }
 
+            OpCode::PushNil => {
+                self.push(Value::Nil);
+            }
+            OpCode::TypeOf => {
+                let a = self.pop()?;
+                self.push(Value::str_solution(a.type_name()));
+            }
+            OpCode::IsType => {
+                let name = self.read_string_solution()?;
+                let a = self.pop()?;
+                self.push(Value::bool_solution(a.type_name() == name));
+            }
+
+            OpCode::Not => {
+                let a = self.pop()?;
+                self.push(a.not_solution());
+            }
+            OpCode::And => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a.and_solution(&b));
+            }
+            OpCode::Or => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a.or_solution(&b));
+            }
+
             //read the address and set the ip to that address
             OpCode::Jump => {
                 let address = self.read_usize_solution()?;
-                if address >= self.bytecode.len() {
-                    return Err(VMError::OutOfBounds);
-                }
-                self.ip = address;
+                self.jump_solution(address)?;
             }
 
             //read address, pop a value, jump to address if falsy or continue
@@ -229,13 +582,37 @@ impl VM {
                 let condition = self.pop()?;
 
                 if !condition.is_truthy_solution() {
-                    if address >= self.bytecode.len() {
-                        return Err(VMError::OutOfBounds);
-                    }
-                    self.ip = address;
+                    self.jump_solution(address)?;
                 }
                 //after this it'll just continue as ip has already advanced
-             }
+            }
+            OpCode::JumpIfTrue => {
+                let address = self.read_usize_solution()?;
+                let condition = self.pop()?;
+
+                if condition.is_truthy_solution() {
+                    self.jump_solution(address)?;
+                }
+            }
+
+            // the keep variants leave the condition on the stack so `a && b` / `a || b`
+            // can short circuit with the deciding operand as the result
+            OpCode::JumpIfFalseKeep => {
+                let address = self.read_usize_solution()?;
+                let condition = self.peek_solution(0)?;
+
+                if !condition.is_truthy_solution() {
+                    self.jump_solution(address)?;
+                }
+            }
+            OpCode::JumpIfTrueKeep => {
+                let address = self.read_usize_solution()?;
+                let condition = self.peek_solution(0)?;
+
+                if condition.is_truthy_solution() {
+                    self.jump_solution(address)?;
+                }
+            }
              OpCode::Call => {
                 let function_address = self.read_usize_solution()?;
                 let return_address = self.ip;
@@ -248,16 +625,233 @@ impl VM {
 
                 self.ip = function_address;
              }
+             OpCode::TableSwitch => {
+                let low = self.read_i64_solution()?;
+                let default = self.read_usize_solution()?;
+                let count = self.read_u32_solution()? as i64;
+                let table = self.ip;
+                let value = self.pop()?.as_int_solution().ok_or(VMError::InvalidOperand)?;
+
+                let target = match value.checked_sub(low) {
+                    Some(index) if (0..count).contains(&index) => {
+                        usize::from_le_bytes(self.word_at(table + index as usize * 8)?)
+                    }
+                    _ => default,
+                };
+                self.jump_solution(target)?;
+             }
+             OpCode::LookupSwitch => {
+                let default = self.read_usize_solution()?;
+                let count = self.read_u32_solution()? as usize;
+                let cases = self.ip;
+                let value = self.pop()?.as_int_solution().ok_or(VMError::InvalidOperand)?;
+
+                // each case is a key followed by its target. Decode and the verifier reject keys
+                // that aren't strictly increasing, unverified bytecode is checked here, once
+                if !self.sorted_lookups.contains(&cases) {
+                    let mut previous = None;
+                    for case in (0..count).map(|n| cases + n * 16) {
+                        let key = i64::from_le_bytes(self.word_at(case)?);
+                        if previous.is_some_and(|previous| key <= previous) {
+                            return Err(VMError::InvalidOperand);
+                        }
+                        previous = Some(key);
+                    }
+                    self.sorted_lookups.insert(cases);
+                }
+
+                // binary search
+                let mut target = default;
+                let (mut low, mut high) = (0, count);
+                while low < high {
+                    let middle = low + (high - low) / 2;
+                    let case = cases + middle * 16;
+                    let key = i64::from_le_bytes(self.word_at(case)?);
+                    if key == value {
+                        target = usize::from_le_bytes(self.word_at(case + 8)?);
+                        break;
+                    } else if key < value {
+                        low = middle + 1;
+                    } else {
+                        high = middle;
+                    }
+                }
+                self.jump_solution(target)?;
+             }
+             OpCode::TailCall => {
+                let function_address = self.read_usize_solution()?;
+                if function_address >= self.bytecode.len() {
+                    return Err(VMError::OutOfBounds);
+                }
+
+                let frame = self.current_frame_mut()?;
+                *frame = frame.tail_call();
+                self.ip = function_address;
+             }
              OpCode::Return => {
                 if self.call_stack.len() <= 1 {
-                    self.running = false;
+                    // returning from a coroutine's function finishes the coroutine, its resumer
+                    // gets the return value like it would a yielded one
+                    if self.resumers.is_empty() {
+                        self.running = false;
+                    } else {
+                        let value = self.pop()?;
+                        self.suspend_coroutine(value, CoroutineStatus::Dead)?;
+                    }
                     return Ok(());
                 }
                 
                 let frame = self.call_stack.pop().ok_or(VMError::StackUnderflow)?;
                 self.ip = frame.return_address();
              }
-             OpCode::StoreLocal => {
+             OpCode::CallNative => {
+                let name = self.read_string_solution()?;
+                let native = self.natives
+                    .get(&name)
+                    .cloned()
+                    .ok_or(VMError::UndefinedNative(name))?;
+
+                if self.stack.len() < native.arity {
+                    return Err(VMError::StackUnderflow);
+                }
+                // the arguments stay on the stack until the native returns, so they're still roots
+                // if it calls back into bytecode and that collects
+                let base = self.stack.len() - native.arity;
+                let args = self.stack[base..].to_vec();
+                let result = (native.function)(&mut NativeContext::new(self), &args)?;
+                self.stack.truncate(base);
+                self.push(result);
+            }
+            OpCode::MakeClosure => {
+                let address = self.read_usize_solution()?;
+                let count = self.read_byte()?;
+                let mut upvalues = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let name = self.read_string_solution()?;
+                    let cell = self.current_frame()?
+                        .capture(&name)
+                        .ok_or_else(|| VMError::UndefinedVariable(name.clone()))?;
+                    upvalues.push((name, cell));
+                }
+                let handle = self.alloc(HeapObject::Closure(Closure { address, upvalues }));
+                self.push(Value::Function(handle));
+            }
+            OpCode::CallValue => {
+                let function = self.pop()?;
+                let closure = self.closure(&function)?;
+                let (address, frame) = (closure.address, CallFrame::for_closure(self.ip, closure));
+                self.call_stack.push(frame);
+                self.jump_solution(address)?;
+            }
+            OpCode::NewCoroutine => {
+                let function = self.pop()?;
+                let coroutine = Coroutine::new(self.closure(&function)?);
+                let handle = self.alloc(HeapObject::Coroutine(coroutine));
+                self.push(Value::Coroutine(handle));
+            }
+            OpCode::Resume => {
+                let sent = self.pop()?;
+                let coroutine = self.pop()?;
+                let handle = self.coroutine_handle(&coroutine)?;
+                self.resume_coroutine(handle, sent)?;
+            }
+            OpCode::Yield => {
+                let value = self.pop()?;
+                self.suspend_coroutine(value, CoroutineStatus::Suspended)?;
+            }
+            OpCode::CoroutineStatus => {
+                let coroutine = self.pop()?;
+                let status = self.coroutine_status(&coroutine)?;
+                self.push(Value::int_solution(status.code() as i64));
+            }
+            OpCode::NewRecord => {
+                let name = self.read_string_solution()?;
+                let kind = self.records.get(&name).cloned().ok_or(VMError::UndefinedRecord(name))?;
+                if self.stack.len() < kind.fields.len() {
+                    return Err(VMError::StackUnderflow);
+                }
+                let values = self.stack.split_off(self.stack.len() - kind.fields.len());
+                let handle = self.alloc(HeapObject::Record(Record { kind, values }));
+                self.push(Value::Record(handle));
+            }
+            OpCode::GetField => {
+                let field = self.read_string_solution()?;
+                let record = self.pop()?;
+                let value = self.get_field(&record, &field)?;
+                self.push(value);
+            }
+            OpCode::SetField => {
+                let field = self.read_string_solution()?;
+                let value = self.pop()?;
+                let record = self.pop()?;
+                self.set_field(&record, &field, value)?;
+            }
+            OpCode::GetFieldAt => {
+                let index = self.read_byte()? as usize;
+                let record = self.pop()?;
+                let index = self.field_at(&record, index)?;
+                let value = self.record(&record)?.values[index].clone();
+                self.push(value);
+            }
+            OpCode::SetFieldAt => {
+                let index = self.read_byte()? as usize;
+                let value = self.pop()?;
+                let record = self.pop()?;
+                let index = self.field_at(&record, index)?;
+                self.record_mut(&record)?.values[index] = value;
+            }
+            OpCode::NewVariant => {
+                let name = self.read_string_solution()?;
+                let (kind, tag) = self.variant_type(&name)?;
+                let arity = kind.variants[tag].arity;
+                if self.stack.len() < arity {
+                    return Err(VMError::StackUnderflow);
+                }
+                let payload = self.stack.split_off(self.stack.len() - arity);
+                self.push(Value::Variant(Rc::new(Variant { kind, tag, payload })));
+            }
+            OpCode::VariantTag => {
+                let value = self.pop()?;
+                let tag = as_variant(&value)?.tag;
+                self.push(Value::int_solution(tag as i64));
+            }
+            OpCode::IsVariant => {
+                let name = self.read_string_solution()?;
+                let value = self.pop()?;
+                let matches = matches!(&value, Value::Variant(variant) if variant.full_name() == name);
+                self.push(Value::bool_solution(matches));
+            }
+            OpCode::PayloadAt => {
+                let index = self.read_byte()? as usize;
+                let value = self.pop()?;
+                let variant = as_variant(&value)?;
+                let payload = variant.payload.get(index).cloned().ok_or_else(|| VMError::NoSuchPayload {
+                    variant: variant.full_name(),
+                    index,
+                })?;
+                self.push(payload);
+            }
+            OpCode::Unpack => {
+                let value = self.pop()?;
+                self.stack.extend(as_variant(&value)?.payload.iter().cloned());
+            }
+            OpCode::Format => {
+                let text = self.format_solution()?;
+                self.push(Value::str_solution(&text));
+            }
+            OpCode::PrintFormat => {
+                let text = self.format_solution()?;
+                self.write_output(&text)?;
+            }
+            OpCode::NewArray => {
+                let count = self.read_byte()? as usize;
+                if self.stack.len() < count {
+                    return Err(VMError::StackUnderflow);
+                }
+                let items = self.stack.split_off(self.stack.len() - count);
+                self.push(Value::array_solution(items));
+            }
+            OpCode::StoreLocal => {
                 let name = self.read_string_solution()?;
                 let value = self.pop()?;
                 self.current_frame_mut()?.store_local_solution(name, value);
@@ -272,27 +866,47 @@ impl VM {
             }
             OpCode::Print => {
                 let text = self.read_string_solution()?;
-                print!("{}", text);
-                io::stdout().flush().ok();
+                self.write_output(&text)?;
             }
             OpCode::PrintVal => {
                 let value = self.pop()?;
-                let output = match value {
-                    Value::Integer(n) => n.to_string(),
-                    Value::Boolean(b) => b.to_string(),
-                };
-                print!("{}", output);
-                io::stdout().flush().ok();
+                let text = self.display_value(&value);
+                self.write_output(&text)?;
             }
             
             OpCode::PrintLn => {
-                println!();
+                self.write_output("\n")?;
+            }
+            OpCode::ReadInt => {
+                let n = self.read_int_input_solution()?;
+                self.push(Value::int_solution(n));
+            }
+            OpCode::ReadLine => {
+                let mut bytes = Vec::new();
+                if self.input.read_until(b'\n', &mut bytes)? == 0 {
+                    return Err(VMError::EndOfInput);
+                }
+                if bytes.last() == Some(&b'\n') {
+                    bytes.pop();
+                    if bytes.last() == Some(&b'\r') {
+                        bytes.pop();
+                    }
+                }
+                let line = String::from_utf8(bytes).map_err(|_| VMError::InvalidString)?;
+                self.push(Value::str_solution(&line));
+            }
+            OpCode::ReadByte => {
+                let byte = self.next_input_byte()?.ok_or(VMError::EndOfInput)?;
+                self.input.consume(1);
+                self.push(Value::int_solution(byte as i64));
+            }
+            OpCode::Eof => {
+                let at_end = self.next_input_byte()?.is_none();
+                self.push(Value::bool_solution(at_end));
             }
             OpCode::Halt => {
                 self.running = false;
             }
-            
-            _ => return Err(VMError::InvalidOpCode(opcode.convert_to_u8())),
 
         }
         Ok(())
@@ -303,64 +917,554 @@ impl VM {
     pub fn run_solution(&mut self) -> Result<(), VMError> {
         self.running = true;
 
-        let max_instructions = 100_00;
-        let mut instruction_count = 0;
-        
-        while self.running {
-            instruction_count += 1;
-            if instruction_count > max_instructions {
-                return Err(VMError::InfiniteLoopDetected);
-            }
-            if let Err(e) = self.execute_instruction() {
-                self.print_stack_trace();
-                return Err(e);
-            }
-            // self.execute_instruction()?;
+        if let Err(e) = self.run_until_depth_solution(0, 0) {
+            self.print_stack_trace();
+            return Err(e);
         }
         
         Ok(())
     }
-    
-    // Helper for testing: peek at top of stack without removing
-    pub fn peek_stack(&self) -> Option<Value> {
-        self.stack.last().copied()
-    }
-    
-    // Helper for testing: get the entire stack
-    pub fn get_stack(&self) -> &[Value] {
-        &self.stack
-    }
 
-    // helper function
-    pub fn get_variable(&self, name: &str) -> Result<Value, VMError> {
-        self.memory.load_solution(name)
+    // execute a single instruction, returns whether the program is still running. Lets the
+    // host pause a program at any point, for example to take a snapshot
+    pub fn step_solution(&mut self) -> Result<bool, VMError> {
+        self.running = true;
+        self.execute_instruction()?;
+        Ok(self.running)
     }
 
-    pub fn current_ip(&self) -> usize {
-        self.ip
+    // fails with InvalidSnapshot when the host has stored a handle to an object that doesn't exist
+    pub fn snapshot(&self) -> Result<Snapshot, VMError> {
+        // detached so the snapshot doesn't share local cells with the running program
+        Snapshot {
+            bytecode_hash: hash_bytecode(&self.bytecode),
+            ip: self.ip,
+            running: self.running,
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            globals: self.memory
+                .variables()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            heap: self.heap.slots().to_vec(),
+            resumers: self.resumers.clone(),
+        }
+        .detached()
     }
 
-    pub fn call_stack_depth(&self) -> usize {
-        self.call_stack.len()
-    }
+    // the matching bytecode has to be loaded first, the snapshot only carries its hash
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), VMError> {
+        if snapshot.bytecode_hash != hash_bytecode(&self.bytecode) {
+            return Err(VMError::SnapshotMismatch);
+        }
 
+        let snapshot = snapshot.detached()?;
+        self.stack = snapshot.stack;
+        self.call_stack = snapshot.call_stack;
+        self.ip = snapshot.ip;
+        self.running = snapshot.running;
+        self.memory.clear();
+        for (name, value) in &snapshot.globals {
+            self.memory.store_solution(name.clone(), value.clone());
+        }
+        self.heap.restore(snapshot.heap);
+        self.resumers = snapshot.resumers;
+        self.yield_floor = 0;
+        Ok(())
+    }
+
+    // execute until the program halts or the call stack unwinds to `depth` frames. While more
+    // than `resumers` coroutines are running the call stack is theirs, so it doesn't count
+    fn run_until_depth_solution(&mut self, depth: usize, resumers: usize) -> Result<(), VMError> {
+        let mut instruction_count = 0;
+        
+        while self.running && (self.call_stack.len() > depth || self.resumers.len() > resumers) {
+            instruction_count += 1;
+            if self.max_instructions.is_some_and(|max| instruction_count > max) {
+                return Err(VMError::InfiniteLoopDetected);
+            }
+            self.execute_instruction()?;
+        }
+        
+        Ok(())
+    }
+
+    // Run the function at `address` with `args` pushed in order, as if a CALL had just jumped
+    // there, and return the value it leaves on top of the stack. The function only sees its
+    // arguments, popping past them is a StackUnderflow. The VM's ip, stack and call stack are
+    // back to where they were afterwards, so this is safe to use between runs and from inside a
+    // native function while a program is running
+    pub fn call_function(&mut self, address: usize, args: &[Value]) -> Result<Value, VMError> {
+        self.call_in_frame(address, CallFrame::new_solution(self.ip), args)
+    }
+
+    // call_function for a function value, which runs with the locals the closure captured
+    pub fn call_value(&mut self, function: &Value, args: &[Value]) -> Result<Value, VMError> {
+        let closure = self.closure(function)?;
+        let (address, frame) = (closure.address, CallFrame::for_closure(self.ip, closure));
+        self.call_in_frame(address, frame, args)
+    }
+
+    fn call_in_frame(&mut self, address: usize, frame: CallFrame, args: &[Value]) -> Result<Value, VMError> {
+        if address >= self.bytecode.len() {
+            return Err(VMError::OutOfBounds);
+        }
+
+        let saved_ip = self.ip;
+        let saved_running = self.running;
+        let saved_floor = self.yield_floor;
+        let base_depth = self.call_stack.len();
+        let base_resumers = self.resumers.len();
+
+        let caller = mem::replace(&mut self.stack, args.to_vec());
+        self.callers.push(caller);
+        self.call_stack.push(frame);
+        self.ip = address;
+        self.running = true;
+        self.yield_floor = base_resumers;
+
+        let outcome = self.run_until_depth_solution(base_depth, base_resumers);
+        self.abandon_coroutines(base_resumers);
+        let halted = self.call_stack.len() > base_depth;
+        let result = self.stack.pop();
+
+        self.stack = self.callers.pop().unwrap_or_default();
+        self.call_stack.truncate(base_depth);
+        self.ip = saved_ip;
+        self.running = saved_running;
+        self.yield_floor = saved_floor;
+
+        outcome?;
+        if halted {
+            return Err(VMError::HaltedInCall);
+        }
+        result.ok_or(VMError::StackUnderflow)
+    }
+
+    // like call_function, but by export name and with the declared arity checked
+    pub fn call_export(&mut self, name: &str, args: &[Value]) -> Result<Value, VMError> {
+        let export = self.exports
+            .get(name)
+            .ok_or_else(|| VMError::UndefinedExport(name.to_string()))?;
+
+        if export.arity != args.len() {
+            return Err(VMError::ArityMismatch {
+                name: name.to_string(),
+                expected: export.arity,
+                found: args.len(),
+            });
+        }
+
+        let address = export.address;
+        self.call_function(address, args)
+    }
+
+    // None lifts the limit entirely, for programs that are expected to run for a long time
+    pub fn set_max_instructions(&mut self, max: Option<usize>) {
+        self.max_instructions = max;
+    }
+    
+    // Helper for testing: peek at top of stack without removing
+    pub fn peek_stack(&self) -> Option<Value> {
+        self.stack.last().cloned()
+    }
+    
+    // Helper for testing: get the entire stack
+    pub fn get_stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    // helper function
+    pub fn get_variable(&self, name: &str) -> Result<Value, VMError> {
+        self.memory.load_solution(name)
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        self.memory.store_solution(name.to_string(), value);
+    }
+
+    pub fn current_ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn call_stack_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    // the closure a function value refers to. A handle the collector already freed is an error
+    // rather than a lookup of whatever reuses the slot, as long as the slot is still empty
+    pub fn closure(&self, function: &Value) -> Result<&Closure, VMError> {
+        let handle = match function {
+            Value::Function(handle) => *handle,
+            _ => return Err(VMError::InvalidOperand),
+        };
+        match self.heap.get(handle) {
+            Some(HeapObject::Closure(closure)) => Ok(closure),
+            Some(_) => Err(VMError::InvalidOperand),
+            None => Err(VMError::DanglingHandle(handle.0)),
+        }
+    }
+
+    // how PRINT_VAL shows a value: functions by their code address, records with their fields
+    // like `Point { x: 1, y: 2 }` and variants with their payload like `Some(5)`. A record that contains itself is cut short as `Point { .. }`
+    pub fn display_value(&self, value: &Value) -> String {
+        let mut out = String::new();
+        self.write_value(value, &mut Vec::new(), &mut out);
+        out
+    }
+
+    // `path` holds the records being printed around this value
+    fn write_value(&self, value: &Value, path: &mut Vec<Handle>, out: &mut String) {
+        match (value, value.handle().and_then(|handle| self.heap.get(handle))) {
+            (Value::Function(_), Some(HeapObject::Closure(closure))) => {
+                out.push_str(&format!("<fn {}>", closure.address));
+            }
+            (Value::Record(handle), Some(HeapObject::Record(record))) => {
+                out.push_str(&record.kind.name);
+                if path.contains(handle) {
+                    out.push_str(" { .. }");
+                    return;
+                }
+                path.push(*handle);
+                out.push_str(" {");
+                for (index, (field, value)) in record.kind.fields.iter().zip(&record.values).enumerate() {
+                    out.push_str(if index == 0 { " " } else { ", " });
+                    out.push_str(field);
+                    out.push_str(": ");
+                    self.write_value(value, path, out);
+                }
+                out.push_str(if record.values.is_empty() { "}" } else { " }" });
+                path.pop();
+            }
+            (Value::Array(items), _) => {
+                out.push('[');
+                for (index, value) in items.iter().enumerate() {
+                    if index > 0 {
+                        out.push_str(", ");
+                    }
+                    self.write_value(value, path, out);
+                }
+                out.push(']');
+            }
+            (Value::Variant(variant), _) => {
+                out.push_str(variant.name());
+                if !variant.payload.is_empty() {
+                    out.push('(');
+                    for (index, value) in variant.payload.iter().enumerate() {
+                        if index > 0 {
+                            out.push_str(", ");
+                        }
+                        self.write_value(value, path, out);
+                    }
+                    out.push(')');
+                }
+            }
+            _ => out.push_str(&value.to_string()),
+        }
+    }
+
+    // a variant value from the host, `name` is "Enum.Variant" as NEW_VARIANT takes it
+    pub fn new_variant(&self, name: &str, payload: Vec<Value>) -> Result<Value, VMError> {
+        let (kind, tag) = self.variant_type(name)?;
+        let arity = kind.variants[tag].arity;
+        if payload.len() != arity {
+            return Err(VMError::ArityMismatch { name: name.to_string(), expected: arity, found: payload.len() });
+        }
+        Ok(Value::Variant(Rc::new(Variant { kind, tag, payload })))
+    }
+
+    // the enum and tag for "Enum.Variant"
+    fn variant_type(&self, name: &str) -> Result<(Rc<EnumType>, usize), VMError> {
+        name.split_once('.')
+            .and_then(|(enum_name, variant)| {
+                let kind = self.enums.get(enum_name)?;
+                Some((kind.clone(), kind.tag(variant)?))
+            })
+            .ok_or_else(|| VMError::UndefinedVariant(name.to_string()))
+    }
+
+    // the record a record value refers to
+    pub fn record(&self, record: &Value) -> Result<&Record, VMError> {
+        let handle = self.record_handle(record)?;
+        match self.heap.get(handle) {
+            Some(HeapObject::Record(record)) => Ok(record),
+            Some(_) => Err(VMError::InvalidOperand),
+            None => Err(VMError::DanglingHandle(handle.0)),
+        }
+    }
+
+    fn record_mut(&mut self, record: &Value) -> Result<&mut Record, VMError> {
+        let handle = self.record_handle(record)?;
+        match self.heap.get_mut(handle) {
+            Some(HeapObject::Record(record)) => Ok(record),
+            Some(_) => Err(VMError::InvalidOperand),
+            None => Err(VMError::DanglingHandle(handle.0)),
+        }
+    }
+
+    fn record_handle(&self, record: &Value) -> Result<Handle, VMError> {
+        match record {
+            Value::Record(handle) => Ok(*handle),
+            _ => Err(VMError::NotARecord(record.type_name())),
+        }
+    }
+
+    pub fn get_field(&self, record: &Value, field: &str) -> Result<Value, VMError> {
+        let index = self.field_index(record, field)?;
+        Ok(self.record(record)?.values[index].clone())
+    }
+
+    pub fn set_field(&mut self, record: &Value, field: &str, value: Value) -> Result<(), VMError> {
+        let index = self.field_index(record, field)?;
+        self.record_mut(record)?.values[index] = value;
+        Ok(())
+    }
+
+    fn field_index(&self, record: &Value, field: &str) -> Result<usize, VMError> {
+        let record = self.record(record)?;
+        record.kind.field_index(field).ok_or_else(|| VMError::NoSuchField {
+            record: record.kind.name.clone(),
+            field: field.to_string(),
+        })
+    }
+
+    // `index` if the record has that many fields
+    fn field_at(&self, record: &Value, index: usize) -> Result<usize, VMError> {
+        let record = self.record(record)?;
+        if index < record.values.len() {
+            Ok(index)
+        } else {
+            Err(VMError::NoSuchField { record: record.kind.name.clone(), field: index.to_string() })
+        }
+    }
+
+    pub fn set_gc_config(&mut self, config: GcConfig) {
+        self.heap.set_config(config);
+    }
+
+    pub fn gc_config(&self) -> GcConfig {
+        self.heap.config()
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // keeps a value the host holds alive across collections until it's unpinned as many times.
+    // Arrays and variants pin whatever they hold, other values that don't live on the heap need
+    // no pinning, for them both are no-ops
+    pub fn pin(&mut self, value: &Value) {
+        let mut handles = Vec::new();
+        trace_value(value, &mut handles);
+        for handle in handles {
+            self.heap.pin(handle);
+        }
+    }
+
+    pub fn unpin(&mut self, value: &Value) {
+        let mut handles = Vec::new();
+        trace_value(value, &mut handles);
+        for handle in handles {
+            self.heap.unpin(handle);
+        }
+    }
+
+    // runs a full collection now and returns how many objects it freed
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = self.roots();
+        self.heap.collect(roots)
+    }
+
+    // puts an object on the heap, collecting first when the heap has grown past its threshold.
+    // The object isn't reachable from anywhere yet, so what it refers to counts as a root
+    fn alloc(&mut self, object: HeapObject) -> Handle {
+        if self.heap.should_collect() {
+            let mut roots = self.roots();
+            object.trace(&mut roots);
+            self.heap.collect(roots);
+        }
+        self.heap.allocate(object)
+    }
+
+    // a suspended coroutine that will call `function` on its first resume
+    pub fn new_coroutine(&mut self, function: &Value) -> Result<Value, VMError> {
+        let coroutine = Coroutine::new(self.closure(function)?);
+        Ok(Value::Coroutine(self.alloc(HeapObject::Coroutine(coroutine))))
+    }
+
+    pub fn coroutine_status(&self, coroutine: &Value) -> Result<CoroutineStatus, VMError> {
+        let handle = self.coroutine_handle(coroutine)?;
+        match self.heap.get(handle) {
+            Some(HeapObject::Coroutine(coroutine)) => Ok(coroutine.status),
+            _ => Err(VMError::DanglingHandle(handle.0)),
+        }
+    }
+
+    // RESUME from the host: runs the coroutine until it yields or returns and gives back that
+    // value. Calling this until the status is dead iterates a generator. Like call_function the
+    // VM's own state is left as it was, and a coroutine that fails or halts is dead afterwards.
+    // The host has to pin a suspended coroutine it wants to keep across collections
+    pub fn resume(&mut self, coroutine: &Value, value: Value) -> Result<Value, VMError> {
+        let handle = self.coroutine_handle(coroutine)?;
+        let saved_running = self.running;
+        let saved_floor = self.yield_floor;
+        let base_stack = self.stack.len();
+        let base_depth = self.call_stack.len();
+        let base_resumers = self.resumers.len();
+
+        self.resume_coroutine(handle, value)?;
+        self.running = true;
+        self.yield_floor = base_resumers;
+
+        let outcome = self.run_until_depth_solution(base_depth, base_resumers);
+        let halted = self.resumers.len() > base_resumers;
+        self.abandon_coroutines(base_resumers);
+        let result = if self.stack.len() > base_stack { self.stack.pop() } else { None };
+
+        self.stack.truncate(base_stack);
+        self.running = saved_running;
+        self.yield_floor = saved_floor;
+
+        outcome?;
+        if halted {
+            return Err(VMError::HaltedInCall);
+        }
+        result.ok_or(VMError::StackUnderflow)
+    }
+
+    fn coroutine_handle(&self, coroutine: &Value) -> Result<Handle, VMError> {
+        match coroutine {
+            Value::Coroutine(handle) => Ok(*handle),
+            _ => Err(VMError::InvalidOperand),
+        }
+    }
+
+    // swaps the coroutine's context in and hands it `sent`, the current context is kept until
+    // the coroutine yields or returns
+    fn resume_coroutine(&mut self, handle: Handle, sent: Value) -> Result<(), VMError> {
+        let coroutine = match self.heap.get_mut(handle) {
+            Some(HeapObject::Coroutine(coroutine)) => coroutine,
+            Some(_) => return Err(VMError::InvalidOperand),
+            None => return Err(VMError::DanglingHandle(handle.0)),
+        };
+        if coroutine.status != CoroutineStatus::Suspended {
+            return Err(VMError::CannotResume(coroutine.status));
+        }
+        coroutine.status = CoroutineStatus::Running;
+        let context = mem::take(&mut coroutine.context);
+
+        let resumer = self.switch_context(context);
+        self.resumers.push((handle, resumer));
+        self.push(sent);
+        Ok(())
+    }
+
+    // leaves the innermost running coroutine with `status`, back in its resumer's context with
+    // `value` pushed. A dead coroutine drops its context, there is nothing left to run
+    fn suspend_coroutine(&mut self, value: Value, status: CoroutineStatus) -> Result<(), VMError> {
+        if self.resumers.len() <= self.yield_floor {
+            return Err(VMError::YieldOutsideCoroutine);
+        }
+        let (handle, resumer) = self.resumers.pop().ok_or(VMError::YieldOutsideCoroutine)?;
+        let context = self.switch_context(resumer);
+        if let Some(HeapObject::Coroutine(coroutine)) = self.heap.get_mut(handle) {
+            coroutine.status = status;
+            if status != CoroutineStatus::Dead {
+                coroutine.context = context;
+            }
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    // after an error or HALT inside coroutines resumed since there were `base` resumers: they
+    // are all dead, and the VM is back in the context that resumed the outermost of them
+    fn abandon_coroutines(&mut self, base: usize) {
+        if self.resumers.len() <= base {
+            return;
+        }
+        let abandoned: Vec<_> = self.resumers.drain(base..).collect();
+        for (handle, _) in &abandoned {
+            if let Some(HeapObject::Coroutine(coroutine)) = self.heap.get_mut(*handle) {
+                coroutine.status = CoroutineStatus::Dead;
+            }
+        }
+        if let Some((_, context)) = abandoned.into_iter().next() {
+            self.switch_context(context);
+        }
+    }
+
+    // installs `context` and returns the one it replaced
+    fn switch_context(&mut self, context: ExecutionContext) -> ExecutionContext {
+        ExecutionContext {
+            stack: mem::replace(&mut self.stack, context.stack),
+            call_stack: mem::replace(&mut self.call_stack, context.call_stack),
+            ip: mem::replace(&mut self.ip, context.ip),
+        }
+    }
+
+    // handles the running program can still reach: the value stack, every frame's locals, the
+    // globals, and the coroutines that are running along with their resumers' contexts. Pinned
+    // handles are added by the heap itself
+    fn roots(&self) -> Vec<Handle> {
+        let mut roots = Vec::new();
+        for value in &self.stack {
+            trace_value(value, &mut roots);
+        }
+        for frame in &self.call_stack {
+            for (_, cell) in frame.locals() {
+                trace_value(&cell.borrow(), &mut roots);
+            }
+        }
+        for (_, value) in self.memory.variables() {
+            trace_value(value, &mut roots);
+        }
+        for (handle, context) in &self.resumers {
+            roots.push(*handle);
+            context.trace(&mut roots);
+        }
+        for value in self.callers.iter().flatten() {
+            trace_value(value, &mut roots);
+        }
+        roots
+    }
+
+}
+
+fn as_variant(value: &Value) -> Result<&Variant, VMError> {
+    match value {
+        Value::Variant(variant) => Ok(variant),
+        _ => Err(VMError::NotAVariant(value.type_name())),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::examples::utils::encode_i64;
+    use crate::io::{InputBuffer, OutputBuffer};
+
+    fn push(bytecode: &mut Vec<u8>, n: i64) {
+        bytecode.push(OpCode::Push.convert_to_u8());
+        bytecode.extend(encode_i64(n));
+    }
+
+    fn binary_program(a: i64, b: i64, op: OpCode) -> Vec<u8> {
+        let mut bytecode = vec![];
+        push(&mut bytecode, a);
+        push(&mut bytecode, b);
+        bytecode.push(op.convert_to_u8());
+        bytecode.push(OpCode::Halt.convert_to_u8());
+        bytecode
+    }
     
     #[test]
     fn test_simple_addition() {
         let mut vm = VM::new();
         
         // Bytecode for: PUSH 10, PUSH 5, ADD, HALT
-        let bytecode = vec![
-            0, 10,  // PUSH 10
-            0, 5,   // PUSH 5
-            1,      // ADD
-            5,      // HALT
-        ];
+        let bytecode = binary_program(10, 5, OpCode::Add);
         
         vm.load_bytecode_solution(bytecode);
         vm.run_solution().unwrap();
@@ -374,14 +1478,13 @@ mod tests {
         
         // Bytecode for: PUSH 20, PUSH 4, DIV, PUSH 3, MUL, HALT
         // Should compute: (20 / 4) * 3 = 15
-        let bytecode = vec![
-            0, 20,  // PUSH 20
-            0, 4,   // PUSH 4
-            4,      // DIV
-            0, 3,   // PUSH 3
-            3,      // MUL
-            5,      // HALT
-        ];
+        let mut bytecode = vec![];
+        push(&mut bytecode, 20);
+        push(&mut bytecode, 4);
+        bytecode.push(OpCode::Div.convert_to_u8());
+        push(&mut bytecode, 3);
+        bytecode.push(OpCode::Mul.convert_to_u8());
+        bytecode.push(OpCode::Halt.convert_to_u8());
         
         vm.load_bytecode_solution(bytecode);
         vm.run_solution().unwrap();
@@ -394,7 +1497,10 @@ mod tests {
         let mut vm = VM::new();
         
         // Try to ADD without pushing values first
-        let bytecode = vec![1, 5]; // ADD, HALT
+        let bytecode = vec![
+            OpCode::Add.convert_to_u8(),
+            OpCode::Halt.convert_to_u8(),
+        ];
         
         vm.load_bytecode_solution(bytecode);
         let result = vm.run_solution();
@@ -407,11 +1513,1398 @@ mod tests {
         let mut vm = VM::new();
         
         // PUSH 10, PUSH 0, DIV, HALT
-        let bytecode = vec![0, 10, 0, 0, 4, 5];
+        let bytecode = binary_program(10, 0, OpCode::Div);
         
         vm.load_bytecode_solution(bytecode);
         let result = vm.run_solution();
         
         assert_eq!(result, Err(VMError::DivisionByZero));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_overflow_traps_by_default() {
+        let cases = [
+            (i64::MAX, 1, OpCode::Add),
+            (i64::MIN, 1, OpCode::Sub),
+            (i64::MAX, 2, OpCode::Mul),
+            (i64::MIN, -1, OpCode::Div),
+        ];
+
+        for (a, b, op) in cases {
+            let mut vm = VM::new();
+            vm.load_bytecode_solution(binary_program(a, b, op));
+            assert_eq!(vm.run_solution(), Err(VMError::IntegerOverflow), "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn test_overflow_mode_wrap_and_saturate() {
+        let mut vm = VM::with_overflow_mode(OverflowMode::Wrap);
+        vm.load_bytecode_solution(binary_program(i64::MAX, 1, OpCode::Add));
+        vm.run_solution().unwrap();
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(i64::MIN)));
+
+        // the mode sticks across loads
+        vm.load_bytecode_solution(binary_program(i64::MIN, -1, OpCode::Div));
+        vm.run_solution().unwrap();
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(i64::MIN)));
+
+        vm.set_overflow_mode(OverflowMode::Saturate);
+        vm.load_bytecode_solution(binary_program(i64::MIN, 2, OpCode::Mul));
+        vm.run_solution().unwrap();
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(i64::MIN)));
+        assert_eq!(vm.overflow_mode(), OverflowMode::Saturate);
+    }
+
+    #[test]
+    fn test_invalid_operand_is_not_division_by_zero() {
+        let mut vm = VM::new();
+        let mut bytecode = vec![];
+        push(&mut bytecode, 10);
+        push(&mut bytecode, 1);
+        push(&mut bytecode, 1);
+        bytecode.push(OpCode::Eq.convert_to_u8());
+        bytecode.push(OpCode::Div.convert_to_u8());
+        bytecode.push(OpCode::Halt.convert_to_u8());
+
+        vm.load_bytecode_solution(bytecode);
+        assert_eq!(vm.run_solution(), Err(VMError::InvalidOperand));
+    }
+
+    #[test]
+    fn test_mod_neg_and_bitwise_opcodes() {
+        let cases = [
+            (17, 5, OpCode::Mod, 2),
+            (0b1100, 0b1010, OpCode::BitAnd, 0b1000),
+            (0b1100, 0b1010, OpCode::BitOr, 0b1110),
+            (0b1100, 0b1010, OpCode::BitXor, 0b0110),
+            (3, 4, OpCode::Shl, 48),
+            (-64, 2, OpCode::Shr, -16),
+            (-1, 60, OpCode::Ushr, 15),
+        ];
+
+        for (a, b, op, expected) in cases {
+            let mut vm = VM::new();
+            vm.load_bytecode_solution(binary_program(a, b, op));
+            vm.run_solution().unwrap();
+            assert_eq!(vm.peek_stack(), Some(Value::Integer(expected)), "{:?}", op);
+        }
+
+        let mut bytecode = vec![];
+        push(&mut bytecode, 5);
+        bytecode.push(OpCode::Neg.convert_to_u8());
+        bytecode.push(OpCode::BitNot.convert_to_u8());
+        bytecode.push(OpCode::Halt.convert_to_u8());
+
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(bytecode);
+        vm.run_solution().unwrap();
+        assert_eq!(vm.get_stack(), &[Value::Integer(4)]);
+    }
+
+    #[test]
+    fn test_shift_and_mod_follow_overflow_mode() {
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(binary_program(1, 64, OpCode::Shl));
+        assert_eq!(vm.run_solution(), Err(VMError::IntegerOverflow));
+
+        vm.load_bytecode_solution(binary_program(1, 0, OpCode::Mod));
+        assert_eq!(vm.run_solution(), Err(VMError::DivisionByZero));
+
+        vm.set_overflow_mode(OverflowMode::Wrap);
+        vm.load_bytecode_solution(binary_program(1, 65, OpCode::Shl));
+        vm.run_solution().unwrap();
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(2)));
+    }
+
+    fn run_assembly(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        vm.run_solution().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_logical_opcodes() {
+        let vm = run_assembly("PUSH 0\nNOT\nPUSH 5\nAND\nPUSH 0\nOR\nHALT");
+        assert_eq!(vm.get_stack(), &[Value::Boolean(true)]);
+    }
+
+    #[test]
+    fn test_jump_if_true_pops_condition() {
+        let vm = run_assembly(
+            "PUSH 1\nJUMP_IF_TRUE yes\nPUSH 10\nHALT\nyes:\nPUSH 20\nHALT",
+        );
+        assert_eq!(vm.get_stack(), &[Value::Integer(20)]);
+    }
+
+    #[test]
+    fn test_short_circuit_keep_jumps() {
+        // a && b with a = 0: b is never evaluated and 0 stays as the result
+        let vm = run_assembly(
+            "PUSH 0\nJUMP_IF_FALSE_KEEP end\nPUSH 99\nSTORE_VAR \"evaluated\"\nend:\nHALT",
+        );
+        assert_eq!(vm.get_stack(), &[Value::Integer(0)]);
+        assert!(vm.get_variable("evaluated").is_err());
+
+        // a || b with a = 3: short circuits and keeps 3
+        let vm = run_assembly("PUSH 3\nJUMP_IF_TRUE_KEEP end\nPUSH 4\nend:\nHALT");
+        assert_eq!(vm.get_stack(), &[Value::Integer(3)]);
+
+        // a || b with a = 0: falls through, the stale condition is still below b
+        let vm = run_assembly("PUSH 0\nJUMP_IF_TRUE_KEEP end\nPUSH 4\nend:\nHALT");
+        assert_eq!(vm.get_stack(), &[Value::Integer(0), Value::Integer(4)]);
+    }
+
+    #[test]
+    fn test_keep_jump_on_empty_stack() {
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble("JUMP_IF_TRUE_KEEP 0").unwrap());
+        assert_eq!(vm.run_solution(), Err(VMError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_stack_manipulation() {
+        let vm = run_assembly("PUSH 1\nPUSH 2\nPUSH 3\nROT\nHALT");
+        assert_eq!(vm.get_stack(), &[Value::Integer(2), Value::Integer(3), Value::Integer(1)]);
+
+        let vm = run_assembly("PUSH 1\nPUSH 2\nSWAP\nOVER\nHALT");
+        assert_eq!(vm.get_stack(), &[Value::Integer(2), Value::Integer(1), Value::Integer(2)]);
+
+        let vm = run_assembly("PUSH 7\nDUP\nMUL\nPUSH 0\nPOP\nHALT");
+        assert_eq!(vm.get_stack(), &[Value::Integer(49)]);
+
+        let vm = run_assembly("PUSH 10\nPUSH 20\nPUSH 30\nPICK 2\nPICK 0\nHALT");
+        assert_eq!(vm.get_stack()[3..], [Value::Integer(10), Value::Integer(10)]);
+    }
+
+    #[test]
+    fn test_stack_manipulation_underflow() {
+        let cases = ["POP", "DUP", "PUSH 1\nSWAP", "PUSH 1\nOVER", "PUSH 1\nPUSH 2\nROT", "PUSH 1\nPICK 1"];
+
+        for source in cases {
+            let mut vm = VM::new();
+            vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+            assert_eq!(vm.run_solution(), Err(VMError::StackUnderflow), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_output_goes_to_sink() {
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        let bytecode = crate::assembler::assemble(
+            "PRINT \"5! = \"\nPUSH 120\nPRINT_VAL\nPRINT_LN\nPUSH 1\nPUSH 1\nEQ\nPRINT_VAL\nHALT",
+        ).unwrap();
+
+        vm.load_bytecode_solution(bytecode);
+        vm.run_solution().unwrap();
+
+        assert_eq!(buffer.contents(), "5! = 120\ntrue");
+    }
+
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_failure_is_an_error() {
+        let mut vm = VM::with_output(FailingSink);
+        vm.load_bytecode_solution(crate::assembler::assemble("PRINT \"hi\"\nHALT").unwrap());
+
+        assert_eq!(vm.run_solution(), Err(VMError::Io("pipe closed".to_string())));
+    }
+
+    fn run_with_input(source: &str, input: &str) -> (Result<(), VMError>, String) {
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_input(InputBuffer::new(input));
+        vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        let result = vm.run_solution();
+        (result, buffer.contents())
+    }
+
+    #[test]
+    fn test_sum_integers_until_eof() {
+        let source = "
+            PUSH 0
+        loop:
+            EOF
+            JUMP_IF_TRUE done
+            READ_INT
+            ADD
+            JUMP loop
+        done:
+            PRINT_VAL
+            HALT
+        ";
+
+        assert_eq!(run_with_input(source, "1\n2\n  3 4\n-5\n"), (Ok(()), "5".to_string()));
+        assert_eq!(run_with_input(source, ""), (Ok(()), "0".to_string()));
+    }
+
+    #[test]
+    fn test_read_line_and_byte() {
+        let source = "READ_LINE\nPRINT_VAL\nPRINT \"|\"\nREAD_BYTE\nPRINT_VAL\nREAD_LINE\nPRINT_VAL\nHALT";
+        assert_eq!(run_with_input(source, "hello world\r\nAbc"), (Ok(()), "hello world|65bc".to_string()));
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert_eq!(run_with_input("READ_INT\nHALT", "   \n").0, Err(VMError::EndOfInput));
+        assert_eq!(run_with_input("READ_LINE\nHALT", "").0, Err(VMError::EndOfInput));
+        assert_eq!(run_with_input("READ_BYTE\nHALT", "").0, Err(VMError::EndOfInput));
+        assert_eq!(
+            run_with_input("READ_INT\nHALT", "12abc\n").0,
+            Err(VMError::InvalidInput("12abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_strings_compare_and_print() {
+        let source = "READ_LINE\nREAD_LINE\nEQ\nPRINT_VAL\nHALT";
+        assert_eq!(run_with_input(source, "same\nsame\n").1, "true");
+        assert_eq!(run_with_input(source, "same\nother\n").1, "false");
+    }
+
+    #[test]
+    fn test_call_native() {
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.register_native("sub3", 3, |_, args| {
+            let a = args[0].as_int_solution().ok_or(VMError::InvalidOperand)?;
+            let b = args[1].as_int_solution().ok_or(VMError::InvalidOperand)?;
+            let c = args[2].as_int_solution().ok_or(VMError::InvalidOperand)?;
+            Ok(Value::int_solution(a - b - c))
+        });
+        vm.register_native("log", 1, |ctx, args| {
+            let count = ctx.get_global("logged").unwrap_or(Value::Integer(0));
+            ctx.set_global("logged", count.add_solution(&Value::Integer(1), OverflowMode::Trap)?);
+            ctx.write_output(&format!("[log] {}\n", args[0]))?;
+            Ok(Value::bool_solution(true))
+        });
+
+        let source = "PUSH 100\nPUSH 10\nPUSH 1\nCALL_NATIVE \"sub3\"\nCALL_NATIVE \"log\"\nHALT";
+        vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        vm.run_solution().unwrap();
+
+        assert_eq!(vm.get_stack(), &[Value::Boolean(true)]);
+        assert_eq!(vm.get_variable("logged"), Ok(Value::Integer(1)));
+        assert_eq!(buffer.contents(), "[log] 89\n");
+    }
+
+    #[test]
+    fn test_native_errors() {
+        let mut vm = VM::new();
+        vm.register_native("fail", 0, |_, _| Err(VMError::Native("lookup failed".to_string())));
+        vm.register_native("pair", 2, |_, args| Ok(args[1].clone()));
+
+        vm.load_bytecode_solution(crate::assembler::assemble("CALL_NATIVE \"fail\"\nHALT").unwrap());
+        assert_eq!(vm.run_solution(), Err(VMError::Native("lookup failed".to_string())));
+
+        vm.load_bytecode_solution(crate::assembler::assemble("CALL_NATIVE \"nope\"\nHALT").unwrap());
+        assert_eq!(vm.run_solution(), Err(VMError::UndefinedNative("nope".to_string())));
+
+        vm.load_bytecode_solution(crate::assembler::assemble("PUSH 1\nCALL_NATIVE \"pair\"\nHALT").unwrap());
+        assert_eq!(vm.run_solution(), Err(VMError::StackUnderflow));
+    }
+
+    const SQUARE: &str = "
+            HALT
+        square:
+            STORE_LOCAL \"n\"
+            LOAD_LOCAL \"n\"
+            LOAD_LOCAL \"n\"
+            MUL
+            RETURN
+        sub:
+            STORE_LOCAL \"b\"
+            STORE_LOCAL \"a\"
+            LOAD_LOCAL \"a\"
+            LOAD_LOCAL \"b\"
+            SUB
+            RETURN
+    ";
+
+    #[test]
+    fn test_call_function_from_host() {
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble(SQUARE).unwrap());
+
+        assert_eq!(vm.call_function(1, &[Value::Integer(7)]), Ok(Value::Integer(49)));
+        assert_eq!(vm.call_function(12, &[Value::Integer(10), Value::Integer(3)]), Ok(Value::Integer(7)));
+
+        // nothing is left behind
+        assert_eq!(vm.get_stack(), &[]);
+        assert_eq!(vm.call_stack_depth(), 1);
+        assert_eq!(vm.current_ip(), 0);
+    }
+
+    #[test]
+    fn test_call_function_from_native() {
+        let mut vm = VM::new();
+        vm.register_native("twice", 2, |ctx, args| {
+            let address = args[0].as_int_solution().ok_or(VMError::InvalidOperand)? as usize;
+            let once = ctx.call_function(address, &[args[1].clone()])?;
+            ctx.call_function(address, &[once])
+        });
+
+        // the prefix is 9 + 9 + 7 + 9 + 1 = 35 bytes, so square lands at 36
+        let source = format!("PUSH 36\nPUSH 4\nCALL_NATIVE \"twice\"\nPUSH 1\nADD\n{}", SQUARE);
+        vm.load_bytecode_solution(crate::assembler::assemble(&source).unwrap());
+        vm.run_solution().unwrap();
+
+        assert_eq!(vm.get_stack(), &[Value::Integer(4 * 4 * 4 * 4 + 1)]);
+    }
+
+    #[test]
+    fn test_native_arguments_stay_reachable() {
+        // the closure passed in is only referenced by the native's arguments. The first call
+        // allocates, which would free it and hand its slot to the new closure
+        let mut vm = load_assembly(
+            "MAKE_CLOSURE one\nCALL_NATIVE \"twice\"\nHALT\none:\nMAKE_CLOSURE other\nPOP\nPUSH 1\nRETURN\n\
+             other:\nPUSH 99\nRETURN",
+            GcConfig { stress: true, ..GcConfig::default() },
+        );
+        vm.register_native("twice", 1, |ctx, args| {
+            ctx.call_value(&args[0], &[])?;
+            ctx.call_value(&args[0], &[])
+        });
+        vm.run_solution().unwrap();
+        assert_eq!(vm.get_stack(), &[Value::Integer(1)]);
+    }
+
+    #[test]
+    fn test_callee_cannot_pop_caller_values() {
+        // f pops its argument and then one more, which would be the 5 the program left there
+        let mut vm = load_assembly(
+            "PUSH 5\nMAKE_CLOSURE f\nCALL_NATIVE \"call\"\nHALT\nf:\nPOP\nPOP\nPUSH 1\nRETURN",
+            GcConfig::default(),
+        );
+        vm.register_native("call", 1, |ctx, args| match ctx.call_value(&args[0], &[Value::Integer(2)]) {
+            Err(VMError::StackUnderflow) => Ok(Value::Integer(0)),
+            other => other,
+        });
+        vm.run_solution().unwrap();
+        assert_eq!(vm.get_stack(), &[Value::Integer(5), Value::Integer(0)]);
+    }
+
+    #[test]
+    fn test_call_function_errors_restore_state() {
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble(SQUARE).unwrap());
+
+        // missing argument: STORE_LOCAL underflows
+        assert_eq!(vm.call_function(1, &[]), Err(VMError::StackUnderflow));
+        assert_eq!(vm.call_stack_depth(), 1);
+        assert_eq!(vm.get_stack(), &[]);
+
+        // HALT reached before RETURN
+        assert_eq!(vm.call_function(0, &[]), Err(VMError::HaltedInCall));
+        assert_eq!(vm.call_function(1000, &[]), Err(VMError::OutOfBounds));
+        assert_eq!(vm.call_function(1, &[Value::Integer(2)]), Ok(Value::Integer(4)));
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble("loop:\nJUMP loop").unwrap());
+        assert_eq!(vm.run_solution(), Err(VMError::InfiniteLoopDetected));
+
+        let source = "PUSH 0\nloop:\nPUSH 1\nADD\nDUP\nPUSH 20000\nLT\nJUMP_IF_TRUE loop\nHALT";
+        vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        vm.set_max_instructions(None);
+        vm.run_solution().unwrap();
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(20000)));
+    }
+
+    const COUNTDOWN: &str = "
+            PUSH 5
+            STORE_VAR \"n\"
+        loop:
+            LOAD_VAR \"n\"
+            JUMP_IF_FALSE done
+            LOAD_VAR \"n\"
+            CALL show
+            LOAD_VAR \"n\"
+            PUSH 1
+            SUB
+            STORE_VAR \"n\"
+            JUMP loop
+        done:
+            PRINT \"liftoff\"
+            HALT
+        show:
+            STORE_LOCAL \"x\"
+            LOAD_LOCAL \"x\"
+            LOAD_LOCAL \"x\"
+            MUL
+            PRINT_VAL
+            PRINT \" \"
+            RETURN
+    ";
+
+    #[test]
+    fn test_snapshot_resume_matches_uninterrupted_run() {
+        let bytecode = crate::assembler::assemble(COUNTDOWN).unwrap();
+
+        let full = OutputBuffer::new();
+        let mut vm = VM::with_output(full.clone());
+        vm.load_bytecode_solution(bytecode.clone());
+        vm.run_solution().unwrap();
+
+        // stop at every possible point, persist, resume in a fresh VM
+        for steps in 1..40 {
+            let before = OutputBuffer::new();
+            let mut vm = VM::with_output(before.clone());
+            vm.load_bytecode_solution(bytecode.clone());
+            for _ in 0..steps {
+                if !vm.step_solution().unwrap() {
+                    break;
+                }
+            }
+            let bytes = vm.snapshot().unwrap().to_bytes();
+
+            let after = OutputBuffer::new();
+            let mut resumed = VM::with_output(after.clone());
+            resumed.load_bytecode_solution(bytecode.clone());
+            resumed.restore_snapshot(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+            assert_eq!(resumed.snapshot(), vm.snapshot());
+            if resumed.running {
+                resumed.run_solution().unwrap();
+            }
+
+            assert_eq!(before.contents() + &after.contents(), full.contents(), "paused after {} steps", steps);
+            assert_eq!(resumed.get_variable("n"), Ok(Value::Integer(0)));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_rejects_other_bytecode() {
+        let mut vm = VM::with_output(OutputBuffer::new());
+        vm.load_bytecode_solution(crate::assembler::assemble(COUNTDOWN).unwrap());
+        vm.step_solution().unwrap();
+        let snapshot = vm.snapshot().unwrap();
+
+        let mut other = VM::new();
+        other.load_bytecode_solution(crate::assembler::assemble("HALT").unwrap());
+        assert_eq!(other.restore_snapshot(&snapshot), Err(VMError::SnapshotMismatch));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_dangling_handles() {
+        // the host can store a handle the heap has nothing for, that's an error and not a panic
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble("HALT").unwrap());
+        vm.set_variable("f", Value::Function(Handle(7, 0)));
+        assert_eq!(vm.snapshot(), Err(VMError::InvalidSnapshot("handle to an empty heap slot".to_string())));
+    }
+
+    #[test]
+    fn test_call_export_by_name() {
+        let module = crate::assembler::assemble_module(&format!(".export square 1\n.export sub 2\n{}", SQUARE)).unwrap();
+        let mut vm = VM::new();
+        vm.load_module(module);
+
+        assert_eq!(vm.call_export("square", &[Value::Integer(9)]), Ok(Value::Integer(81)));
+        assert_eq!(vm.call_export("sub", &[Value::Integer(1), Value::Integer(9)]), Ok(Value::Integer(-8)));
+        assert_eq!(vm.exports().count(), 2);
+
+        assert_eq!(vm.call_export("cube", &[]), Err(VMError::UndefinedExport("cube".to_string())));
+        assert_eq!(
+            vm.call_export("square", &[]),
+            Err(VMError::ArityMismatch { name: "square".to_string(), expected: 1, found: 0 })
+        );
+
+        // plain bytecode has no exports
+        vm.load_bytecode_solution(vec![OpCode::Halt.convert_to_u8()]);
+        assert!(vm.call_export("square", &[Value::Integer(1)]).is_err());
+    }
+
+    const COUNTERS: &str = "
+            CALL make_counter
+            STORE_VAR \"c\"
+            LOAD_VAR \"c\"
+            CALL_VALUE
+            PRINT_VAL
+            LOAD_VAR \"c\"
+            CALL_VALUE
+            PRINT_VAL
+            CALL make_counter   ; a second counter gets its own count
+            CALL_VALUE
+            PRINT_VAL
+            LOAD_VAR \"c\"
+            CALL_VALUE
+            PRINT_VAL
+            HALT
+        make_counter:
+            PUSH 0
+            STORE_LOCAL \"count\"
+            MAKE_CLOSURE next \"count\"
+            RETURN
+        next:
+            LOAD_LOCAL \"count\"
+            PUSH 1
+            ADD
+            DUP
+            STORE_LOCAL \"count\"
+            RETURN
+    ";
+
+    #[test]
+    fn test_closure_outlives_its_frame() {
+        let (result, output) = run_with_input(COUNTERS, "");
+        result.unwrap();
+        assert_eq!(output, "1213");
+    }
+
+    #[test]
+    fn test_captured_local_is_shared() {
+        let source = "
+            CALL f
+            HALT
+        f:
+            PUSH 1
+            STORE_LOCAL \"x\"
+            MAKE_CLOSURE get_and_bump \"x\"
+            STORE_LOCAL \"g\"
+            PUSH 5
+            STORE_LOCAL \"x\"       ; the closure sees the new value
+            LOAD_LOCAL \"g\"
+            CALL_VALUE
+            PRINT_VAL
+            LOAD_LOCAL \"x\"        ; and the frame sees what the closure stored
+            PRINT_VAL
+            RETURN
+        get_and_bump:
+            LOAD_LOCAL \"x\"
+            DUP
+            PUSH 10
+            ADD
+            STORE_LOCAL \"x\"
+            RETURN
+        ";
+        let (result, output) = run_with_input(source, "");
+        result.unwrap();
+        assert_eq!(output, "515");
+    }
+
+    #[test]
+    fn test_closure_as_callback() {
+        let source = "
+            PUSH 3
+            STORE_LOCAL \"n\"
+            MAKE_CLOSURE add_n \"n\"
+            PUSH 10
+            CALL apply_twice
+            HALT
+        apply_twice:            ; (f, x) -> f(f(x))
+            STORE_LOCAL \"x\"
+            STORE_LOCAL \"f\"
+            LOAD_LOCAL \"x\"
+            LOAD_LOCAL \"f\"
+            CALL_VALUE
+            LOAD_LOCAL \"f\"
+            CALL_VALUE
+            RETURN
+        add_n:
+            LOAD_LOCAL \"n\"
+            ADD
+            RETURN
+        ";
+        let vm = run_assembly(source);
+        assert_eq!(vm.peek_stack(), Some(Value::Integer(16)));
+
+        let mut vm = run_assembly("PUSH 2\nSTORE_LOCAL \"n\"\nMAKE_CLOSURE 25 \"n\"\nHALT\nLOAD_LOCAL \"n\"\nMUL\nRETURN");
+        let double = vm.peek_stack().unwrap();
+        assert_eq!(vm.display_value(&double), "<fn 25>");
+        assert_eq!(vm.call_value(&double, &[Value::Integer(21)]), Ok(Value::Integer(42)));
+        assert_eq!(vm.call_value(&Value::Integer(1), &[]), Err(VMError::InvalidOperand));
+    }
+
+    #[test]
+    fn test_closure_errors() {
+        let (result, _) = run_with_input("PUSH 1\nCALL_VALUE\nHALT", "");
+        assert_eq!(result, Err(VMError::InvalidOperand));
+
+        let (result, _) = run_with_input("MAKE_CLOSURE 0 \"missing\"\nHALT", "");
+        assert_eq!(result, Err(VMError::UndefinedVariable("missing".to_string())));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_closure_cells_shared() {
+        let bytecode = crate::assembler::assemble(COUNTERS).unwrap();
+
+        for steps in 1..30 {
+            let before = OutputBuffer::new();
+            let mut vm = VM::with_output(before.clone());
+            vm.load_bytecode_solution(bytecode.clone());
+            for _ in 0..steps {
+                vm.step_solution().unwrap();
+            }
+            let bytes = vm.snapshot().unwrap().to_bytes();
+
+            let after = OutputBuffer::new();
+            let mut resumed = VM::with_output(after.clone());
+            resumed.load_bytecode_solution(bytecode.clone());
+            resumed.restore_snapshot(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+            assert_eq!(resumed.snapshot().unwrap().to_bytes(), bytes);
+            if resumed.running {
+                resumed.run_solution().unwrap();
+            }
+
+            assert_eq!(before.contents() + &after.contents(), "1213", "paused after {} steps", steps);
+        }
+    }
+
+    fn load_assembly(source: &str, config: GcConfig) -> VM {
+        let mut vm = VM::new();
+        vm.set_gc_config(config);
+        vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        vm
+    }
+
+    #[test]
+    fn test_gc_frees_unreachable_closures() {
+        // makes 100 counters and drops each one right away
+        let source = "
+            PUSH 100
+        loop:
+            CALL make_counter
+            POP
+            PUSH 1
+            SUB
+            DUP
+            JUMP_IF_TRUE loop
+            HALT
+        make_counter:
+            PUSH 0
+            STORE_LOCAL \"count\"
+            MAKE_CLOSURE make_counter \"count\"
+            RETURN
+        ";
+        let mut vm = load_assembly(source, GcConfig { initial_threshold: 8, ..GcConfig::default() });
+        vm.run_solution().unwrap();
+
+        let stats = vm.gc_stats();
+        assert_eq!(stats.allocated, 100);
+        assert!(stats.collections >= 10, "{:?}", stats);
+        assert_eq!(stats.live + stats.freed, 100);
+        assert!(stats.live <= 8, "{:?}", stats);
+
+        assert_eq!(vm.collect_garbage(), stats.live);
+        assert_eq!(vm.gc_stats().live, 0);
+    }
+
+    #[test]
+    fn test_gc_frees_cycles() {
+        // a closure that captures the local it's stored in, so it can call itself
+        let source = "
+            CALL make
+            HALT
+        make:
+            PUSH 0
+            STORE_LOCAL \"self\"
+            MAKE_CLOSURE make \"self\"
+            DUP
+            STORE_LOCAL \"self\"
+            RETURN
+        ";
+        let mut vm = load_assembly(source, GcConfig::default());
+        vm.run_solution().unwrap();
+
+        assert_eq!(vm.collect_garbage(), 0);
+        vm.stack.clear();
+        assert_eq!(vm.collect_garbage(), 1);
+    }
+
+    #[test]
+    fn test_gc_stress_mode() {
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_gc_config(GcConfig { stress: true, ..GcConfig::default() });
+        vm.load_bytecode_solution(crate::assembler::assemble(COUNTERS).unwrap());
+        vm.run_solution().unwrap();
+
+        assert_eq!(buffer.contents(), "1213");
+        let stats = vm.gc_stats();
+        assert_eq!(stats.collections, stats.allocated);
+        // the first counter is still in "c", the second one was dropped after its call
+        assert_eq!(vm.collect_garbage(), 1);
+        assert_eq!(vm.gc_stats().live, 1);
+    }
+
+    #[test]
+    fn test_pinned_values_survive_collection() {
+        let mut vm = load_assembly("PUSH 2\nSTORE_LOCAL \"n\"\nMAKE_CLOSURE 25 \"n\"\nHALT\nLOAD_LOCAL \"n\"\nMUL\nRETURN", GcConfig::default());
+        vm.run_solution().unwrap();
+        let double = vm.stack.pop().unwrap();
+
+        vm.pin(&double);
+        assert_eq!(vm.collect_garbage(), 0);
+        assert_eq!(vm.call_value(&double, &[Value::Integer(4)]), Ok(Value::Integer(8)));
+
+        vm.unpin(&double);
+        assert_eq!(vm.collect_garbage(), 1);
+        assert_eq!(vm.call_value(&double, &[Value::Integer(4)]), Err(VMError::DanglingHandle(0)));
+
+        // running the program again allocates a new closure into the freed slot, the old value
+        // still doesn't reach it
+        assert_eq!(vm.call_function(0, &[]), Err(VMError::HaltedInCall));
+        assert_eq!(vm.gc_stats().live, 1);
+        assert_eq!(vm.call_value(&double, &[Value::Integer(4)]), Err(VMError::DanglingHandle(0)));
+    }
+
+    #[test]
+    fn test_pins_survive_restore() {
+        // pins are host state, restoring a snapshot keeps them like it keeps natives
+        let mut vm = load_assembly("PUSH 2\nSTORE_LOCAL \"n\"\nMAKE_CLOSURE 25 \"n\"\nHALT\nLOAD_LOCAL \"n\"\nMUL\nRETURN", GcConfig::default());
+        vm.run_solution().unwrap();
+        let double = vm.stack.pop().unwrap();
+        vm.pin(&double);
+
+        let snapshot = vm.snapshot().unwrap();
+        vm.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(vm.collect_garbage(), 0);
+        assert_eq!(vm.call_value(&double, &[Value::Integer(4)]), Ok(Value::Integer(8)));
+
+        vm.unpin(&double);
+        assert_eq!(vm.collect_garbage(), 1);
+    }
+
+    // prints what a generator counting 1 to 3 yields and then what it returns
+    const GENERATOR: &str = "
+            MAKE_CLOSURE range
+            NEW_COROUTINE
+            STORE_VAR \"g\"
+        loop:
+            LOAD_VAR \"g\"
+            PUSH 0
+            RESUME
+            LOAD_VAR \"g\"
+            COROUTINE_STATUS
+            PUSH 2
+            EQ
+            JUMP_IF_TRUE done
+            PRINT_VAL
+            JUMP loop
+        done:
+            PRINT_VAL
+            HALT
+        range:
+            POP                 ; the value the first RESUME sent
+            PUSH 1
+            STORE_LOCAL \"i\"
+        next:
+            LOAD_LOCAL \"i\"
+            YIELD
+            POP
+            LOAD_LOCAL \"i\"
+            PUSH 1
+            ADD
+            DUP
+            STORE_LOCAL \"i\"
+            PUSH 4
+            LT
+            JUMP_IF_TRUE next
+            PUSH 99
+            RETURN
+    ";
+
+    #[test]
+    fn test_generator() {
+        let (result, output) = run_with_input(GENERATOR, "");
+        result.unwrap();
+        assert_eq!(output, "12399");
+    }
+
+    #[test]
+    fn test_resume_and_yield_pass_values_both_ways() {
+        // a running total of whatever is sent in
+        let source = "
+            MAKE_CLOSURE sum
+            NEW_COROUTINE
+            DUP
+            PUSH 5
+            RESUME
+            PRINT_VAL
+            DUP
+            PUSH 10
+            RESUME
+            PRINT_VAL
+            COROUTINE_STATUS
+            PRINT_VAL
+            HALT
+        sum:
+            STORE_LOCAL \"total\"
+        loop:
+            LOAD_LOCAL \"total\"
+            YIELD
+            LOAD_LOCAL \"total\"
+            ADD
+            STORE_LOCAL \"total\"
+            JUMP loop
+        ";
+        let (result, output) = run_with_input(source, "");
+        result.unwrap();
+        assert_eq!(output, "5150");
+    }
+
+    #[test]
+    fn test_drive_coroutine_from_host() {
+        let source = GENERATOR.replace("MAKE_CLOSURE range\n", "MAKE_CLOSURE range\nHALT\n");
+        let mut vm = load_assembly(&source, GcConfig { stress: true, ..GcConfig::default() });
+        vm.run_solution().unwrap();
+        let range = vm.peek_stack().unwrap();
+
+        let generator = vm.new_coroutine(&range).unwrap();
+        vm.pin(&generator);
+        let mut values = Vec::new();
+        while vm.coroutine_status(&generator) == Ok(CoroutineStatus::Suspended) {
+            values.push(vm.resume(&generator, Value::Integer(0)).unwrap());
+        }
+
+        assert_eq!(values, [1, 2, 3, 99].map(Value::Integer));
+        assert_eq!(vm.coroutine_status(&generator), Ok(CoroutineStatus::Dead));
+        assert_eq!(
+            vm.resume(&generator, Value::Integer(0)),
+            Err(VMError::CannotResume(CoroutineStatus::Dead))
+        );
+        assert_eq!(vm.get_stack(), &[range]);
+        assert_eq!(vm.call_stack_depth(), 1);
+    }
+
+    #[test]
+    fn test_coroutine_errors() {
+        let (result, _) = run_with_input("PUSH 1\nYIELD\nHALT", "");
+        assert_eq!(result, Err(VMError::YieldOutsideCoroutine));
+
+        let (result, _) = run_with_input("PUSH 1\nPUSH 2\nRESUME\nHALT", "");
+        assert_eq!(result, Err(VMError::InvalidOperand));
+
+        // a coroutine can't resume itself, it's running
+        let source = "
+            MAKE_CLOSURE body
+            NEW_COROUTINE
+            DUP
+            RESUME
+            HALT
+        body:
+            PUSH 0
+            RESUME
+            RETURN
+        ";
+        let (result, _) = run_with_input(source, "");
+        assert_eq!(result, Err(VMError::CannotResume(CoroutineStatus::Running)));
+
+        // an error inside leaves the coroutine dead and the host's VM state untouched
+        let mut vm = load_assembly("MAKE_CLOSURE body\nHALT\nbody:\nPUSH 0\nDIV\nRETURN", GcConfig::default());
+        vm.run_solution().unwrap();
+        let body = vm.peek_stack().unwrap();
+        let coroutine = vm.new_coroutine(&body).unwrap();
+        assert_eq!(vm.resume(&coroutine, Value::Integer(1)), Err(VMError::DivisionByZero));
+        assert_eq!(vm.coroutine_status(&coroutine), Ok(CoroutineStatus::Dead));
+        assert_eq!(vm.get_stack(), &[body]);
+        assert_eq!(vm.current_ip(), 11);
+    }
+
+    #[test]
+    fn test_snapshot_inside_coroutine() {
+        let bytecode = crate::assembler::assemble(GENERATOR).unwrap();
+
+        for steps in 1..60 {
+            let before = OutputBuffer::new();
+            let mut vm = VM::with_output(before.clone());
+            vm.load_bytecode_solution(bytecode.clone());
+            for _ in 0..steps {
+                vm.step_solution().unwrap();
+            }
+            let bytes = vm.snapshot().unwrap().to_bytes();
+
+            let after = OutputBuffer::new();
+            let mut resumed = VM::with_output(after.clone());
+            resumed.load_bytecode_solution(bytecode.clone());
+            resumed.restore_snapshot(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+            if resumed.running {
+                resumed.run_solution().unwrap();
+            }
+
+            assert_eq!(before.contents() + &after.contents(), "12399", "paused after {} steps", steps);
+        }
+    }
+
+    // sum(n, acc) adds n, n - 1, ... 1 onto acc, recursing in tail position
+    const SUM: &str = "
+            PUSH 1000000
+            PUSH 0
+            CALL sum
+            PRINT_VAL
+            HALT
+        sum:
+            STORE_LOCAL \"acc\"
+            STORE_LOCAL \"n\"
+            LOAD_LOCAL \"n\"
+            JUMP_IF_FALSE done
+            LOAD_LOCAL \"n\"
+            PUSH 1
+            SUB
+            LOAD_LOCAL \"acc\"
+            LOAD_LOCAL \"n\"
+            ADD
+            CALL sum
+            RETURN
+        done:
+            LOAD_LOCAL \"acc\"
+            RETURN
+    ";
+
+    #[test]
+    fn test_tail_calls_run_in_constant_stack_space() {
+        let bytecode = crate::assembler::assemble(SUM).unwrap();
+        let optimized = crate::optimizer::optimize(&bytecode, &crate::optimizer::Passes::default()).unwrap();
+
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_max_instructions(None);
+        vm.load_bytecode_solution(optimized.clone());
+        vm.run_solution().unwrap();
+        assert_eq!(buffer.contents(), "500000500000");
+
+        // stepping through the first thousand iterations, the call stack never gets deeper than
+        // main plus sum
+        let mut vm = VM::with_output(OutputBuffer::new());
+        vm.load_bytecode_solution(optimized);
+        while vm.call_stack.last().unwrap().elided() < 1000 {
+            vm.step_solution().unwrap();
+            assert!(vm.call_stack_depth() <= 2);
+        }
+    }
+
+    #[test]
+    fn test_stack_trace_marks_elided_frames() {
+        let source = "
+            CALL f
+            HALT
+        f:
+            PUSH 3
+            TAIL_CALL g
+        g:
+            TAIL_CALL h
+        h:
+            PUSH 0
+            DIV
+            RETURN
+        ";
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        assert_eq!(vm.run_solution(), Err(VMError::DivisionByZero));
+
+        let trace = vm.stack_trace();
+        assert!(trace.contains("  #1 at IP 9 (2 tail call frame(s) elided)\n"), "{}", trace);
+        assert!(trace.contains("  #0 at IP 0\n"), "{}", trace);
+
+        // the elided count survives a snapshot
+        let restored = Snapshot::from_bytes(&vm.snapshot().unwrap().to_bytes()).unwrap();
+        assert_eq!(restored.call_stack[1].elided(), 2);
+    }
+
+    #[test]
+    fn test_switches() {
+        // prints a name for each of -1 to 5, through a table for 0 to 3 and a lookup for the rest
+        let source = "
+            PUSH -1
+        loop:
+            DUP
+            TABLE_SWITCH 0 sparse zero one two three
+        zero:
+            PRINT \"zero \"
+            JUMP next
+        one:
+            PRINT \"one \"
+            JUMP next
+        two:
+        three:
+            PRINT \"few \"
+            JUMP next
+        sparse:
+            DUP
+            LOOKUP_SWITCH other 5:five -1:minus
+        five:
+            PRINT \"five \"
+            JUMP next
+        minus:
+            PRINT \"minus \"
+            JUMP next
+        other:
+            PRINT \"other \"
+        next:
+            PUSH 1
+            ADD
+            DUP
+            PUSH 6
+            LT
+            JUMP_IF_TRUE loop
+            HALT
+        ";
+        let (result, output) = run_with_input(source, "");
+        result.unwrap();
+        assert_eq!(output, "minus zero one few few other five ");
+
+        let (result, _) = run_with_input("PUSH 1\nPUSH 2\nEQ\nTABLE_SWITCH 0 0\nHALT", "");
+        assert_eq!(result, Err(VMError::InvalidOperand));
+
+        // hand built, with the keys out of order: 3 -> 55 then 2 -> 55, the default being 54
+        let mut bytecode = vec![OpCode::Push.convert_to_u8()];
+        bytecode.extend_from_slice(&3i64.to_le_bytes());
+        bytecode.push(OpCode::LookupSwitch.convert_to_u8());
+        bytecode.extend_from_slice(&54usize.to_le_bytes());
+        bytecode.extend_from_slice(&2u32.to_le_bytes());
+        for key in [3i64, 2] {
+            bytecode.extend_from_slice(&key.to_le_bytes());
+            bytecode.extend_from_slice(&55usize.to_le_bytes());
+        }
+        bytecode.extend_from_slice(&[OpCode::Halt.convert_to_u8(), OpCode::Halt.convert_to_u8()]);
+        let mut vm = VM::new();
+        vm.load_bytecode_solution(bytecode);
+        assert_eq!(vm.run_solution(), Err(VMError::InvalidOperand));
+    }
+
+    #[test]
+    fn test_nil_and_types() {
+        // a function returning nil for non-positive inputs, and a caller branching on the type
+        let source = "
+            PUSH 0
+            CALL check
+            DUP
+            TYPE_OF
+            PRINT_VAL
+            PRINT \" \"
+            IS_TYPE \"nil\"
+            PRINT_VAL
+            PRINT \" \"
+            PUSH 3
+            CALL check
+            DUP
+            IS_TYPE \"int\"
+            JUMP_IF_FALSE done
+            PRINT_VAL
+            PRINT \" \"
+            PUSH_NIL
+            PUSH_NIL
+            EQ
+            PRINT_VAL
+        done:
+            HALT
+        check:
+            DUP
+            PUSH 0
+            GT
+            JUMP_IF_TRUE positive
+            POP
+            PUSH_NIL
+        positive:
+            RETURN
+        ";
+        let (result, output) = run_with_input(source, "");
+        result.unwrap();
+        assert_eq!(output, "nil true 3 true");
+
+        let (result, _) = run_with_input("PUSH_NIL\nPUSH 1\nADD\nHALT", "");
+        assert_eq!(result, Err(VMError::InvalidOperand));
+    }
+
+    fn run_module(source: &str, config: GcConfig) -> (VM, Result<(), VMError>, String) {
+        let buffer = OutputBuffer::new();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_gc_config(config);
+        vm.load_module(crate::assembler::assemble_module(source).unwrap());
+        let result = vm.run_solution();
+        (vm, result, buffer.contents())
+    }
+
+    #[test]
+    fn test_records() {
+        let source = "
+            .record Point x y
+            .record Line from to
+            PUSH 1
+            PUSH 2
+            NEW_RECORD \"Point\"
+            DUP
+            STORE_VAR \"p\"
+            PRINT_VAL
+            PRINT_LN
+            LOAD_VAR \"p\"
+            PUSH 10
+            SET_FIELD \"x\"
+            LOAD_VAR \"p\"
+            GET_FIELD_AT 0
+            LOAD_VAR \"p\"
+            GET_FIELD \"y\"
+            ADD
+            PRINT_VAL
+            PRINT_LN
+            LOAD_VAR \"p\"
+            LOAD_VAR \"p\"
+            NEW_RECORD \"Line\"
+            DUP
+            TYPE_OF
+            PRINT_VAL
+            PRINT \" \"
+            PRINT_VAL
+            PRINT_LN
+            LOAD_VAR \"p\"
+            LOAD_VAR \"p\"
+            SET_FIELD_AT 1
+            LOAD_VAR \"p\"
+            PRINT_VAL
+            HALT
+        ";
+        // stress mode collects on every allocation, the fields have to survive it
+        let (_, result, output) = run_module(source, GcConfig { stress: true, ..GcConfig::default() });
+        result.unwrap();
+        assert_eq!(
+            output,
+            "Point { x: 1, y: 2 }\n12\nrecord Line { from: Point { x: 10, y: 2 }, to: Point { x: 10, y: 2 } }\n\
+             Point { x: 10, y: Point { .. } }"
+        );
+    }
+
+    #[test]
+    fn test_record_errors() {
+        let run = |body: &str| run_module(&format!(".record Point x y\n{}\nHALT", body), GcConfig::default()).1;
+
+        assert_eq!(run("PUSH 1\nNEW_RECORD \"Point\""), Err(VMError::StackUnderflow));
+        assert_eq!(run("NEW_RECORD \"Size\""), Err(VMError::UndefinedRecord("Size".to_string())));
+        assert_eq!(run("PUSH 1\nGET_FIELD \"x\""), Err(VMError::NotARecord("int")));
+        assert_eq!(run("PUSH_NIL\nPUSH 1\nSET_FIELD_AT 0"), Err(VMError::NotARecord("nil")));
+
+        let error = run("PUSH 1\nPUSH 2\nNEW_RECORD \"Point\"\nGET_FIELD \"z\"").unwrap_err();
+        assert_eq!(error, VMError::NoSuchField { record: "Point".to_string(), field: "z".to_string() });
+        assert_eq!(error.to_string(), "Record Point has no field z");
+        assert_eq!(
+            run("PUSH 1\nPUSH 2\nNEW_RECORD \"Point\"\nPUSH 3\nSET_FIELD_AT 2"),
+            Err(VMError::NoSuchField { record: "Point".to_string(), field: "2".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_snapshot_keeps_records() {
+        let source = ".record Pair a b\n.enum Option None Some:1\nPUSH 1\nNEW_VARIANT \"Option.Some\"\nPUSH_NIL\n\
+                      NEW_RECORD \"Pair\"\nSTORE_VAR \"pair\"\nHALT";
+        let (vm, result, _) = run_module(source, GcConfig::default());
+        result.unwrap();
+
+        // the restoring VM never loaded the module, the record brings its type along
+        let mut restored = VM::new();
+        restored.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        restored.restore_snapshot(&Snapshot::from_bytes(&vm.snapshot().unwrap().to_bytes()).unwrap()).unwrap();
+        let pair = restored.get_variable("pair").unwrap();
+        assert_eq!(restored.display_value(&pair), "Pair { a: Some(1), b: nil }");
+        assert_eq!(restored.get_field(&pair, "b"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_enums() {
+        // safe division returning Option, matched by tag through a table switch
+        let source = "
+            .enum Option None Some:1
+            .enum Shape Rect:2
+            PUSH 7
+            PUSH 2
+            CALL divide
+            CALL show
+            PUSH 7
+            PUSH 0
+            CALL divide
+            CALL show
+            PUSH 3
+            PUSH 4
+            NEW_VARIANT \"Shape.Rect\"
+            DUP
+            PRINT_VAL
+            PRINT \" \"
+            UNPACK
+            MUL
+            PRINT_VAL
+            PRINT \" \"
+            PUSH 3
+            NEW_VARIANT \"Option.Some\"
+            PUSH 3
+            NEW_VARIANT \"Option.Some\"
+            EQ
+            PRINT_VAL
+            HALT
+        divide:
+            DUP
+            JUMP_IF_FALSE by_zero
+            DIV
+            NEW_VARIANT \"Option.Some\"
+            RETURN
+        by_zero:
+            POP
+            POP
+            NEW_VARIANT \"Option.None\"
+            RETURN
+        show:
+            DUP
+            VARIANT_TAG
+            TABLE_SWITCH 0 none none some
+        none:
+            IS_VARIANT \"Option.None\"
+            PRINT_VAL
+            PRINT \" \"
+            RETURN
+        some:
+            PAYLOAD_AT 0
+            PRINT_VAL
+            PRINT \" \"
+            RETURN
+        ";
+        let (_, result, output) = run_module(source, GcConfig::default());
+        result.unwrap();
+        assert_eq!(output, "3 true Rect(3, 4) 12 true");
+    }
+
+    #[test]
+    fn test_variant_payload_stays_reachable() {
+        // the only reference to the closure is inside a variant stored in a global
+        let source = "
+            .enum Option None Some:1
+            MAKE_CLOSURE seven
+            NEW_VARIANT \"Option.Some\"
+            STORE_VAR \"f\"
+            PUSH 1
+            PUSH 2
+            NEW_VARIANT \"Option.Some\"
+            NEW_VARIANT \"Option.Some\"
+            POP
+            LOAD_VAR \"f\"
+            PAYLOAD_AT 0
+            CALL_VALUE
+            HALT
+        seven:
+            PUSH 7
+            RETURN
+        ";
+        let (mut vm, result, _) = run_module(source, GcConfig { stress: true, ..GcConfig::default() });
+        result.unwrap();
+        assert_eq!(vm.get_stack(), &[Value::Integer(1), Value::Integer(7)]);
+
+        let f = vm.get_variable("f").unwrap();
+        assert_eq!(vm.display_value(&f), "Some(<fn 78>)");
+        assert_eq!(vm.new_variant("Option.Some", vec![]), Err(VMError::ArityMismatch {
+            name: "Option.Some".to_string(),
+            expected: 1,
+            found: 0,
+        }));
+        assert_eq!(vm.collect_garbage(), 0);
+    }
+
+    #[test]
+    fn test_variant_errors() {
+        let run = |body: &str| {
+            run_module(&format!(".enum Option None Some:1\n{}\nHALT", body), GcConfig::default()).1
+        };
+
+        assert_eq!(run("NEW_VARIANT \"Option.Other\""), Err(VMError::UndefinedVariant("Option.Other".to_string())));
+        assert_eq!(run("NEW_VARIANT \"Some\""), Err(VMError::UndefinedVariant("Some".to_string())));
+        assert_eq!(run("NEW_VARIANT \"Option.Some\""), Err(VMError::StackUnderflow));
+        assert_eq!(run("PUSH 1\nVARIANT_TAG"), Err(VMError::NotAVariant("int")));
+        assert_eq!(run("PUSH_NIL\nUNPACK"), Err(VMError::NotAVariant("nil")));
+        assert_eq!(
+            run("NEW_VARIANT \"Option.None\"\nPAYLOAD_AT 0"),
+            Err(VMError::NoSuchPayload { variant: "Option.None".to_string(), index: 0 })
+        );
+        // a mismatch is just false, not an error
+        assert_eq!(run("PUSH 1\nIS_VARIANT \"Option.None\"\nPOP"), Ok(()));
+    }
+
+    #[test]
+    fn test_format() {
+        let source = "
+            .record Point x y
+            PUSH 5
+            PUSH 120
+            PRINT_FORMAT \"{}! = {}\\n\"
+            PUSH 255
+            PUSH 5
+            PUSH 1
+            PUSH 2
+            NEW_RECORD \"Point\"
+            FORMAT \"{:#06x}|{:>4b}|{}\"
+            DUP
+            PRINT_VAL
+            TYPE_OF
+            PRINT_FORMAT \" {:.3}\"
+            HALT
+        ";
+        let (_, result, output) = run_module(source, GcConfig::default());
+        result.unwrap();
+        assert_eq!(output, "5! = 120\n0x00ff| 101|Point { x: 1, y: 2 } str");
+
+        let (result, _) = run_with_input("PUSH_NIL\nPRINT_FORMAT \"{:x}\"\nHALT", "");
+        assert_eq!(result, Err(VMError::InvalidFormat("hex formatting needs an int, found nil".to_string())));
+        let (result, _) = run_with_input("PUSH 1\nFORMAT \"{} {}\"\nHALT", "");
+        assert_eq!(result, Err(VMError::StackUnderflow));
+        // unverified bytecode can't make the host allocate an enormous padding
+        let (result, _) = run_with_input("PUSH 1\nFORMAT \"{:18446744073709551615}\"\nHALT", "");
+        assert_eq!(result, Err(VMError::InvalidFormat("18446744073709551615 is larger than 65535".to_string())));
+    }
+
+    #[test]
+    fn test_arrays() {
+        // the closure and the record are only reachable through the array once it's stored
+        let source = "
+            .record Box v
+            MAKE_CLOSURE seven
+            PUSH 1
+            NEW_RECORD \"Box\"
+            NEW_ARRAY 2
+            STORE_VAR \"a\"
+            MAKE_CLOSURE seven
+            POP
+            LOAD_VAR \"a\"
+            DUP
+            PRINT_VAL
+            IS_TYPE \"array\"
+            PRINT_VAL
+            PUSH 1
+            PUSH 2
+            NEW_ARRAY 2
+            PUSH 1
+            PUSH 2
+            NEW_ARRAY 2
+            EQ
+            PRINT_VAL
+            NEW_ARRAY 0
+            JUMP_IF_FALSE done
+            PRINT \"unreachable\"
+        done:
+            HALT
+        seven:
+            PUSH 7
+            RETURN
+        ";
+        let (vm, result, output) = run_module(source, GcConfig { stress: true, ..GcConfig::default() });
+        result.unwrap();
+        assert_eq!(output, "[<fn 120>, Box { v: 1 }]truetrue");
+
+        let mut restored = VM::new();
+        restored.load_bytecode_solution(crate::assembler::assemble(source).unwrap());
+        restored.restore_snapshot(&Snapshot::from_bytes(&vm.snapshot().unwrap().to_bytes()).unwrap()).unwrap();
+        let a = restored.get_variable("a").unwrap();
+        assert_eq!(restored.display_value(&a), vm.display_value(&vm.get_variable("a").unwrap()));
+
+        let (result, _) = run_with_input("PUSH 1\nNEW_ARRAY 2\nHALT", "");
+        assert_eq!(result, Err(VMError::StackUnderflow));
+    }
+}